@@ -1,6 +1,6 @@
 use crate::trace_capnp::trace;
 use codetracer_trace_types::{TraceLowLevelEvent, VariableId};
-use capnp::serialize_packed;
+use capnp::{serialize, serialize_packed};
 use std::str::FromStr;
 
 /// The first 5 bytes identify the file as a CodeTracer file (hex l33tsp33k - C0DE72ACE2 for "CodeTracer").
@@ -9,6 +9,14 @@ use std::str::FromStr;
 /// The header is 8 bytes in size, ensuring 64-bit alignment for the rest of the file.
 pub const HEADER: &[u8] = &[0xC0, 0xDE, 0x72, 0xAC, 0xE2, 0x00, 0x00, 0x00];
 
+/// Header of segmented traces: the 7th byte is the revision of the format. Instead of a single packed
+/// message, the header is followed by a sequence of unpacked messages, each holding a batch of events,
+/// so traces can be written and read incrementally.
+pub const SEGMENTED_HEADER: &[u8] = &[0xC0, 0xDE, 0x72, 0xAC, 0xE2, 0x00, 0x01, 0x00];
+
+/// Number of events in every message (but the last one) of a segmented trace
+pub const DEFAULT_BATCH_SIZE: usize = 4096;
+
 impl From<codetracer_trace_types::TypeKind> for trace::TypeKind {
     fn from(item: codetracer_trace_types::TypeKind) -> Self {
         match item {
@@ -46,6 +54,7 @@ impl From<codetracer_trace_types::TypeKind> for trace::TypeKind {
             codetracer_trace_types::TypeKind::NonExpanded => trace::TypeKind::NonExpanded,
             codetracer_trace_types::TypeKind::Any => trace::TypeKind::Any,
             codetracer_trace_types::TypeKind::Slice => trace::TypeKind::Slice,
+            codetracer_trace_types::TypeKind::Map => trace::TypeKind::Map,
         }
     }
 }
@@ -87,6 +96,7 @@ impl From<trace::TypeKind> for codetracer_trace_types::TypeKind {
             trace::TypeKind::NonExpanded => codetracer_trace_types::TypeKind::NonExpanded,
             trace::TypeKind::Any => codetracer_trace_types::TypeKind::Any,
             trace::TypeKind::Slice => codetracer_trace_types::TypeKind::Slice,
+            trace::TypeKind::Map => codetracer_trace_types::TypeKind::Map,
         }
     }
 }
@@ -150,6 +160,32 @@ fn conv_valuerecord(bldr: crate::trace_capnp::trace::value_record::Builder, vr:
             let mut q_typ_id = qi.init_type_id();
             q_typ_id.set_i(type_id.0.try_into().unwrap());
         }
+        codetracer_trace_types::ValueRecord::UInt { u, type_id } => {
+            let mut qu = bldr.init_uint();
+            qu.set_u(*u);
+            let mut q_typ_id = qu.init_type_id();
+            q_typ_id.set_i(type_id.0.try_into().unwrap());
+        }
+        codetracer_trace_types::ValueRecord::Int128 { i, type_id } => {
+            let mut qi = bldr.init_int128();
+            qi.set_hi((*i >> 64) as i64);
+            qi.set_lo(*i as u64);
+            let mut q_typ_id = qi.init_type_id();
+            q_typ_id.set_i(type_id.0.try_into().unwrap());
+        }
+        codetracer_trace_types::ValueRecord::UInt128 { u, type_id } => {
+            let mut qu = bldr.init_uint128();
+            qu.set_hi((*u >> 64) as u64);
+            qu.set_lo(*u as u64);
+            let mut q_typ_id = qu.init_type_id();
+            q_typ_id.set_i(type_id.0.try_into().unwrap());
+        }
+        codetracer_trace_types::ValueRecord::Char { c, type_id } => {
+            let mut qc = bldr.init_char();
+            qc.set_c(u32::from(*c));
+            let mut q_typ_id = qc.init_type_id();
+            q_typ_id.set_i(type_id.0.try_into().unwrap());
+        }
         codetracer_trace_types::ValueRecord::Float { f, type_id } => {
             let mut qf = bldr.init_float();
             qf.set_f(*f);
@@ -171,8 +207,7 @@ fn conv_valuerecord(bldr: crate::trace_capnp::trace::value_record::Builder, vr:
         codetracer_trace_types::ValueRecord::Sequence { elements, is_slice, type_id } => {
             let mut qseq = bldr.init_sequence();
             let mut elems = qseq.reborrow().init_elements(elements.len().try_into().unwrap());
-            for i in 0..elements.len() {
-                let ele = &elements[i];
+            for (i, ele) in elements.iter().enumerate() {
                 let bele = elems.reborrow().get(i.try_into().unwrap());
                 conv_valuerecord(bele, ele);
             }
@@ -183,8 +218,7 @@ fn conv_valuerecord(bldr: crate::trace_capnp::trace::value_record::Builder, vr:
         codetracer_trace_types::ValueRecord::Tuple { elements, type_id } => {
             let mut qtup = bldr.init_tuple();
             let mut elems = qtup.reborrow().init_elements(elements.len().try_into().unwrap());
-            for i in 0..elements.len() {
-                let ele = &elements[i];
+            for (i, ele) in elements.iter().enumerate() {
                 let bele = elems.reborrow().get(i.try_into().unwrap());
                 conv_valuerecord(bele, ele);
             }
@@ -194,8 +228,7 @@ fn conv_valuerecord(bldr: crate::trace_capnp::trace::value_record::Builder, vr:
         codetracer_trace_types::ValueRecord::Struct { field_values, type_id } => {
             let mut qstruc = bldr.init_struct();
             let mut elems = qstruc.reborrow().init_field_values(field_values.len().try_into().unwrap());
-            for i in 0..field_values.len() {
-                let ele = &field_values[i];
+            for (i, ele) in field_values.iter().enumerate() {
                 let bele = elems.reborrow().get(i.try_into().unwrap());
                 conv_valuerecord(bele, ele);
             }
@@ -253,17 +286,68 @@ fn conv_valuerecord(bldr: crate::trace_capnp::trace::value_record::Builder, vr:
         codetracer_trace_types::ValueRecord::BigInt { b, negative, type_id } => {
             let mut qbigint = bldr.init_bigint();
             let mut bigint_b = qbigint.reborrow().init_b(b.len().try_into().unwrap());
-            for i in 0..=b.len() {
-                bigint_b.set(i.try_into().unwrap(), b[i]);
+            for (i, byte) in b.iter().enumerate() {
+                bigint_b.set(i.try_into().unwrap(), *byte);
             }
             qbigint.set_negative(*negative);
             let mut q_typ_id = qbigint.init_type_id();
             q_typ_id.set_i(type_id.0.try_into().unwrap());
         }
+        codetracer_trace_types::ValueRecord::Bytes {
+            data,
+            original_length,
+            type_id,
+        } => {
+            let mut qbytes = bldr.init_bytes();
+            qbytes.set_data(data);
+            qbytes.set_has_original_length(original_length.is_some());
+            qbytes.set_original_length(original_length.unwrap_or(0));
+            let mut q_typ_id = qbytes.init_type_id();
+            q_typ_id.set_i(type_id.0.try_into().unwrap());
+        }
+        codetracer_trace_types::ValueRecord::Elided {
+            original_length,
+            preview,
+            type_id,
+        } => {
+            let mut qelided = bldr.init_elided();
+            qelided.set_has_original_length(original_length.is_some());
+            qelided.set_original_length(original_length.unwrap_or(0));
+            if let Some(preview) = preview {
+                conv_valuerecord(qelided.reborrow().init_preview(), preview);
+            }
+            let mut q_typ_id = qelided.init_type_id();
+            q_typ_id.set_i(type_id.0.try_into().unwrap());
+        }
+        codetracer_trace_types::ValueRecord::Map { entries, type_id } => {
+            let mut qmap = bldr.init_map();
+            let mut q_entries = qmap.reborrow().init_entries(entries.len().try_into().unwrap());
+            for (i, (key, value)) in entries.iter().enumerate() {
+                let mut q_entry = q_entries.reborrow().get(i.try_into().unwrap());
+                conv_valuerecord(q_entry.reborrow().init_key(), key);
+                conv_valuerecord(q_entry.init_value(), value);
+            }
+            let mut q_typ_id = qmap.init_type_id();
+            q_typ_id.set_i(type_id.0.try_into().unwrap());
+        }
     }
 }
 
+/// Writes all events as a single packed message after `HEADER`
 pub fn write_trace(q: &[codetracer_trace_types::TraceLowLevelEvent], output: &mut impl std::io::Write) -> ::capnp::Result<()> {
+    let message = build_message(q);
+
+    output.write_all(HEADER)?;
+
+    serialize_packed::write_message(output, &message)
+}
+
+/// Writes a batch of events as one message of a segmented trace; `SEGMENTED_HEADER` must be written first
+pub fn write_event_batch(q: &[codetracer_trace_types::TraceLowLevelEvent], output: &mut impl std::io::Write) -> ::capnp::Result<()> {
+    serialize::write_message(output, &build_message(q))
+}
+
+fn build_message(q: &[codetracer_trace_types::TraceLowLevelEvent]) -> ::capnp::message::Builder<::capnp::message::HeapAllocator> {
     let mut message = ::capnp::message::Builder::new_default();
 
     let trace = message.init_root::<trace::Builder>();
@@ -285,20 +369,48 @@ pub fn write_trace(q: &[codetracer_trace_types::TraceLowLevelEvent], output: &mu
                     }
                     codetracer_trace_types::TypeSpecificInfo::Struct { fields } => {
                         let strct = specific_info.init_struct();
-                        let mut flds = strct.init_fields(fields.len().try_into().unwrap());
-                        for i in 0..fields.len() {
-                            let ftr = &fields[i];
-                            let mut fld = flds.reborrow().get(i.try_into().unwrap());
-                            fld.set_name(ftr.name.clone());
-                            let mut typ_id = fld.init_type_id();
-                            typ_id.set_i(ftr.type_id.0.try_into().unwrap());
-                        }
+                        conv_field_type_records(strct.init_fields(fields.len().try_into().unwrap()), fields);
                     }
                     codetracer_trace_types::TypeSpecificInfo::Pointer { dereference_type_id } => {
                         let ptr = specific_info.init_pointer();
                         let mut deref_typ_id = ptr.init_dereference_type_id();
                         deref_typ_id.set_i(dereference_type_id.0.try_into().unwrap());
                     }
+                    codetracer_trace_types::TypeSpecificInfo::Collection { element_type_id } => {
+                        let collection = specific_info.init_collection();
+                        let mut elem_typ_id = collection.init_element_type_id();
+                        elem_typ_id.set_i(element_type_id.0.try_into().unwrap());
+                    }
+                    codetracer_trace_types::TypeSpecificInfo::Map { key_type_id, value_type_id } => {
+                        let mut map = specific_info.init_map();
+                        let mut key_typ_id = map.reborrow().init_key_type_id();
+                        key_typ_id.set_i(key_type_id.0.try_into().unwrap());
+                        let mut value_typ_id = map.init_value_type_id();
+                        value_typ_id.set_i(value_type_id.0.try_into().unwrap());
+                    }
+                    codetracer_trace_types::TypeSpecificInfo::Enum { variants } => {
+                        let enm = specific_info.init_enum();
+                        let mut vrnts = enm.init_variants(variants.len().try_into().unwrap());
+                        for (i, vtr) in variants.iter().enumerate() {
+                            let mut vrnt = vrnts.reborrow().get(i.try_into().unwrap());
+                            vrnt.set_name(vtr.name.clone());
+                            conv_field_type_records(vrnt.init_fields(vtr.fields.len().try_into().unwrap()), &vtr.fields);
+                        }
+                    }
+                    codetracer_trace_types::TypeSpecificInfo::Tuple { element_type_ids } => {
+                        let tuple = specific_info.init_tuple();
+                        let mut elem_typ_ids = tuple.init_element_type_ids(element_type_ids.len().try_into().unwrap());
+                        for (i, element_type_id) in element_type_ids.iter().enumerate() {
+                            let mut elem_typ_id = elem_typ_ids.reborrow().get(i.try_into().unwrap());
+                            elem_typ_id.set_i(element_type_id.0.try_into().unwrap());
+                        }
+                    }
+                    codetracer_trace_types::TypeSpecificInfo::Function { parameters, return_type_id } => {
+                        let mut function = specific_info.init_function();
+                        conv_field_type_records(function.reborrow().init_parameters(parameters.len().try_into().unwrap()), parameters);
+                        let mut ret_typ_id = function.init_return_type_id();
+                        ret_typ_id.set_i(return_type_id.0.try_into().unwrap());
+                    }
                 }
             }
             TraceLowLevelEvent::Path(pathbuf) => {
@@ -360,8 +472,8 @@ pub fn write_trace(q: &[codetracer_trace_types::TraceLowLevelEvent], output: &mu
             }
             TraceLowLevelEvent::Asm(strs) => {
                 let mut ret = event.init_asm(strs.len().try_into().unwrap());
-                for i in 0..strs.len() {
-                    ret.set(i.try_into().unwrap(), &strs[i]);
+                for (i, s) in strs.iter().enumerate() {
+                    ret.set(i.try_into().unwrap(), s);
                 }
             }
             TraceLowLevelEvent::BindVariable(bindvarrec) => {
@@ -369,7 +481,7 @@ pub fn write_trace(q: &[codetracer_trace_types::TraceLowLevelEvent], output: &mu
                 let mut ret_var_id = ret.reborrow().init_variable_id();
                 ret_var_id.set_i(bindvarrec.variable_id.0.try_into().unwrap());
                 let mut ret_place = ret.init_place();
-                ret_place.set_p(bindvarrec.place.0.try_into().unwrap());
+                ret_place.set_p(bindvarrec.place.0);
             }
             TraceLowLevelEvent::Assignment(assrec) => {
                 let mut ret = event.init_assignment();
@@ -384,9 +496,9 @@ pub fn write_trace(q: &[codetracer_trace_types::TraceLowLevelEvent], output: &mu
                     }
                     codetracer_trace_types::RValue::Compound(variable_ids) => {
                         let mut ret_from_compound = ret_from.init_compound(variable_ids.len().try_into().unwrap());
-                        for i in 0..variable_ids.len() {
+                        for (i, variable_id) in variable_ids.iter().enumerate() {
                             let mut r = ret_from_compound.reborrow().get(i.try_into().unwrap());
-                            r.set_i(variable_ids[i].0.try_into().unwrap());
+                            r.set_i(variable_id.0.try_into().unwrap());
                         }
                     }
                 }
@@ -396,9 +508,9 @@ pub fn write_trace(q: &[codetracer_trace_types::TraceLowLevelEvent], output: &mu
             }
             TraceLowLevelEvent::DropVariables(vars) => {
                 let mut ret_vars = event.init_drop_variables(vars.len().try_into().unwrap());
-                for i in 0..vars.len() {
+                for (i, var) in vars.iter().enumerate() {
                     let mut q = ret_vars.reborrow().get(i.try_into().unwrap());
-                    q.set_i(vars[i].0.try_into().unwrap());
+                    q.set_i(var.0.try_into().unwrap());
                 }
             }
             TraceLowLevelEvent::DropVariable(varid) => {
@@ -408,38 +520,38 @@ pub fn write_trace(q: &[codetracer_trace_types::TraceLowLevelEvent], output: &mu
             TraceLowLevelEvent::CompoundValue(cvr) => {
                 let mut ret = event.init_compound_value();
                 let mut ret_place = ret.reborrow().init_place();
-                ret_place.set_p(cvr.place.0.try_into().unwrap());
+                ret_place.set_p(cvr.place.0);
                 let ret_value = ret.init_value();
                 conv_valuerecord(ret_value, &cvr.value);
             }
             TraceLowLevelEvent::CellValue(cvr) => {
                 let mut ret = event.init_cell_value();
                 let mut ret_place = ret.reborrow().init_place();
-                ret_place.set_p(cvr.place.0.try_into().unwrap());
+                ret_place.set_p(cvr.place.0);
                 let ret_value = ret.init_value();
                 conv_valuerecord(ret_value, &cvr.value);
             }
             TraceLowLevelEvent::AssignCell(acr) => {
                 let mut ret = event.init_assign_cell();
                 let mut ret_place = ret.reborrow().init_place();
-                ret_place.set_p(acr.place.0.try_into().unwrap());
+                ret_place.set_p(acr.place.0);
                 let ret_new_value = ret.init_new_value();
                 conv_valuerecord(ret_new_value, &acr.new_value);
             }
             TraceLowLevelEvent::AssignCompoundItem(aci) => {
                 let mut ret = event.init_assign_compound_item();
                 let mut ret_place = ret.reborrow().init_place();
-                ret_place.set_p(aci.place.0.try_into().unwrap());
+                ret_place.set_p(aci.place.0);
                 ret.set_index(aci.index.try_into().unwrap());
                 let mut ret_item_place = ret.init_item_place();
-                ret_item_place.set_p(aci.item_place.0.try_into().unwrap());
+                ret_item_place.set_p(aci.item_place.0);
             }
             TraceLowLevelEvent::VariableCell(vcr) => {
                 let mut ret = event.init_variable_cell();
                 let mut ret_variable_id = ret.reborrow().init_variable_id();
                 ret_variable_id.set_i(vcr.variable_id.0.try_into().unwrap());
                 let mut ret_place = ret.init_place();
-                ret_place.set_p(vcr.place.0.try_into().unwrap());
+                ret_place.set_p(vcr.place.0);
             }
             TraceLowLevelEvent::ThreadStart(tid) => {
                 let mut ret = event.init_thread_start();
@@ -453,12 +565,41 @@ pub fn write_trace(q: &[codetracer_trace_types::TraceLowLevelEvent], output: &mu
                 let mut ret = event.init_thread_switch();
                 ret.set_i(tid.0);
             }
+            TraceLowLevelEvent::Timestamp(nanoseconds) => {
+                event.set_timestamp(*nanoseconds);
+            }
+            TraceLowLevelEvent::DroppedEvents(count) => {
+                event.set_dropped_events(*count);
+            }
         }
     }
 
-    output.write_all(HEADER)?;
+    message
+}
 
-    serialize_packed::write_message(output, &message)
+fn conv_field_type_records(
+    mut ret: capnp::struct_list::Builder<trace::field_type_record::Owned>,
+    fields: &[codetracer_trace_types::FieldTypeRecord],
+) {
+    for (i, ftr) in fields.iter().enumerate() {
+        let mut fld = ret.reborrow().get(i.try_into().unwrap());
+        fld.set_name(ftr.name.clone());
+        let mut typ_id = fld.init_type_id();
+        typ_id.set_i(ftr.type_id.0.try_into().unwrap());
+    }
+}
+
+fn get_field_type_records(
+    r: capnp::struct_list::Reader<trace::field_type_record::Owned>,
+) -> Result<Vec<codetracer_trace_types::FieldTypeRecord>, capnp::Error> {
+    let mut res = Vec::with_capacity(r.len().try_into().unwrap());
+    for field in r {
+        res.push(codetracer_trace_types::FieldTypeRecord {
+            name: field.get_name()?.to_string()?,
+            type_id: codetracer_trace_types::TypeId(field.get_type_id()?.get_i().try_into().unwrap()),
+        });
+    }
+    Ok(res)
 }
 
 fn get_value_records(r: capnp::struct_list::Reader<trace::value_record::Owned>) -> Result<Vec<codetracer_trace_types::ValueRecord>, capnp::Error> {
@@ -469,12 +610,28 @@ fn get_value_records(r: capnp::struct_list::Reader<trace::value_record::Owned>)
     Ok(res)
 }
 
-fn get_value_record(r: trace::value_record::Reader) -> Result<codetracer_trace_types::ValueRecord, capnp::Error> {
+pub(crate) fn get_value_record(r: trace::value_record::Reader) -> Result<codetracer_trace_types::ValueRecord, capnp::Error> {
     match r.which() {
         Ok(trace::value_record::Which::Int(q)) => Ok(codetracer_trace_types::ValueRecord::Int {
             i: q.get_i(),
             type_id: codetracer_trace_types::TypeId(q.get_type_id()?.get_i().try_into().unwrap()),
         }),
+        Ok(trace::value_record::Which::Uint(q)) => Ok(codetracer_trace_types::ValueRecord::UInt {
+            u: q.get_u(),
+            type_id: codetracer_trace_types::TypeId(q.get_type_id()?.get_i().try_into().unwrap()),
+        }),
+        Ok(trace::value_record::Which::Int128(q)) => Ok(codetracer_trace_types::ValueRecord::Int128 {
+            i: (i128::from(q.get_hi()) << 64) | i128::from(q.get_lo()),
+            type_id: codetracer_trace_types::TypeId(q.get_type_id()?.get_i().try_into().unwrap()),
+        }),
+        Ok(trace::value_record::Which::Uint128(q)) => Ok(codetracer_trace_types::ValueRecord::UInt128 {
+            u: (u128::from(q.get_hi()) << 64) | u128::from(q.get_lo()),
+            type_id: codetracer_trace_types::TypeId(q.get_type_id()?.get_i().try_into().unwrap()),
+        }),
+        Ok(trace::value_record::Which::Char(q)) => Ok(codetracer_trace_types::ValueRecord::Char {
+            c: char::from_u32(q.get_c()).ok_or_else(|| capnp::Error::failed(format!("invalid char value {:#x}", q.get_c())))?,
+            type_id: codetracer_trace_types::TypeId(q.get_type_id()?.get_i().try_into().unwrap()),
+        }),
         Ok(trace::value_record::Which::Float(q)) => Ok(codetracer_trace_types::ValueRecord::Float {
             f: q.get_f(),
             type_id: codetracer_trace_types::TypeId(q.get_type_id()?.get_i().try_into().unwrap()),
@@ -530,6 +687,31 @@ fn get_value_record(r: trace::value_record::Reader) -> Result<codetracer_trace_t
             negative: q.get_negative(),
             type_id: codetracer_trace_types::TypeId(q.get_type_id()?.get_i().try_into().unwrap()),
         }),
+        Ok(trace::value_record::Which::Bytes(q)) => Ok(codetracer_trace_types::ValueRecord::Bytes {
+            data: q.get_data()?.to_vec(),
+            original_length: q.get_has_original_length().then(|| q.get_original_length()),
+            type_id: codetracer_trace_types::TypeId(q.get_type_id()?.get_i().try_into().unwrap()),
+        }),
+        Ok(trace::value_record::Which::Elided(q)) => Ok(codetracer_trace_types::ValueRecord::Elided {
+            original_length: q.get_has_original_length().then(|| q.get_original_length()),
+            preview: if q.has_preview() {
+                Some(Box::new(get_value_record(q.get_preview()?)?))
+            } else {
+                None
+            },
+            type_id: codetracer_trace_types::TypeId(q.get_type_id()?.get_i().try_into().unwrap()),
+        }),
+        Ok(trace::value_record::Which::Map(q)) => {
+            let q_entries = q.get_entries()?;
+            let mut entries = Vec::with_capacity(q_entries.len().try_into().unwrap());
+            for q_entry in q_entries {
+                entries.push((get_value_record(q_entry.get_key()?)?, get_value_record(q_entry.get_value()?)?));
+            }
+            Ok(codetracer_trace_types::ValueRecord::Map {
+                entries,
+                type_id: codetracer_trace_types::TypeId(q.get_type_id()?.get_i().try_into().unwrap()),
+            })
+        }
         Err(_) => panic!(),
     }
 }
@@ -541,195 +723,336 @@ fn get_full_value_record(r: trace::full_value_record::Reader) -> Result<codetrac
     })
 }
 
+/// Reads a trace written either with `write_trace` or as a segmented trace
 pub fn read_trace(input: &mut impl std::io::BufRead) -> ::capnp::Result<Vec<codetracer_trace_types::TraceLowLevelEvent>> {
     let mut header_buf = [0; 8];
     input.read_exact(&mut header_buf)?;
-    if header_buf != HEADER {
+    if header_buf == HEADER {
+        let message_reader = serialize_packed::read_message(input, ::capnp::message::ReaderOptions::new())?;
+        let mut res = vec![];
+        read_events(message_reader.get_root::<trace::Reader>()?, &mut res)?;
+        Ok(res)
+    } else if header_buf == SEGMENTED_HEADER {
+        let mut res = vec![];
+        for batch in EventBatches::new(input) {
+            res.append(&mut batch?);
+        }
+        Ok(res)
+    } else {
         panic!("Invalid file header (wrong file format or incompatible version)");
     }
-    let message_reader = serialize_packed::read_message(input, ::capnp::message::ReaderOptions::new())?;
+}
 
-    let trace = message_reader.get_root::<trace::Reader>()?;
+/// Iterates over the event batches of a segmented trace, reading one message at a time;
+/// `input` must be positioned after `SEGMENTED_HEADER`
+pub struct EventBatches<R> {
+    input: R,
+}
 
-    let mut res: Vec<codetracer_trace_types::TraceLowLevelEvent> = Vec::with_capacity(trace.get_events()?.len().try_into().unwrap());
+impl<R: std::io::BufRead> EventBatches<R> {
+    pub fn new(input: R) -> Self {
+        EventBatches { input }
+    }
+}
+
+impl<R: std::io::BufRead> Iterator for EventBatches<R> {
+    type Item = ::capnp::Result<Vec<codetracer_trace_types::TraceLowLevelEvent>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let message_reader = match serialize::try_read_message(&mut self.input, ::capnp::message::ReaderOptions::new()) {
+            Ok(Some(message_reader)) => message_reader,
+            Ok(None) => return None,
+            Err(err) => return Some(Err(err)),
+        };
+        let mut res = vec![];
+        let result = message_reader.get_root::<trace::Reader>().and_then(|trace| read_events(trace, &mut res));
+        Some(result.map(|()| res))
+    }
+}
+
+fn read_events(trace: trace::Reader, res: &mut Vec<codetracer_trace_types::TraceLowLevelEvent>) -> ::capnp::Result<()> {
+    res.reserve(trace.get_events()?.len().try_into().unwrap());
 
     for event in trace.get_events()? {
-        let q = match event.which() {
-            Ok(trace::trace_low_level_event::Which::Step(step_record)) => {
-                let step_record = step_record?;
-                TraceLowLevelEvent::Step(codetracer_trace_types::StepRecord {
-                    path_id: codetracer_trace_types::PathId(step_record.get_path_id()?.get_i().try_into().unwrap()),
-                    line: codetracer_trace_types::Line(step_record.get_line()?.get_l()),
-                })
-            }
-            Ok(trace::trace_low_level_event::Which::Path(path_buf)) => {
-                TraceLowLevelEvent::Path(std::path::PathBuf::from_str(path_buf?.get_p()?.to_str()?).unwrap())
-            }
-            Ok(trace::trace_low_level_event::Which::VariableName(variable_name)) => TraceLowLevelEvent::VariableName(variable_name?.to_string()?),
-            Ok(trace::trace_low_level_event::Which::Variable(variable)) => TraceLowLevelEvent::Variable(variable?.to_string()?),
-            Ok(trace::trace_low_level_event::Which::Type(type_record)) => {
-                let type_record = type_record?;
-                TraceLowLevelEvent::Type(codetracer_trace_types::TypeRecord {
-                    kind: type_record.get_kind()?.into(),
-                    lang_type: type_record.get_lang_type()?.to_string()?,
-                    specific_info: match type_record.get_specific_info()?.which() {
-                        Ok(trace::type_specific_info::Which::None(())) => codetracer_trace_types::TypeSpecificInfo::None,
-                        Ok(trace::type_specific_info::Which::Struct(s)) => {
-                            let s_fields = s.get_fields()?;
-                            let mut fields: Vec<codetracer_trace_types::FieldTypeRecord> = Vec::with_capacity(s_fields.len().try_into().unwrap());
-                            for s_field in s_fields {
-                                fields.push(codetracer_trace_types::FieldTypeRecord {
-                                    name: s_field.get_name()?.to_string()?,
-                                    type_id: codetracer_trace_types::TypeId(s_field.get_type_id()?.get_i().try_into().unwrap()),
-                                });
-                            }
-                            codetracer_trace_types::TypeSpecificInfo::Struct { fields }
-                        }
-                        Ok(trace::type_specific_info::Which::Pointer(p)) => codetracer_trace_types::TypeSpecificInfo::Pointer {
-                            dereference_type_id: codetracer_trace_types::TypeId(p.get_dereference_type_id()?.get_i().try_into().unwrap()),
-                        },
-                        Err(_) => {
-                            panic!()
-                        }
+        res.push(get_event(event)?);
+    }
+
+    Ok(())
+}
+
+pub(crate) fn get_event(event: trace::trace_low_level_event::Reader) -> ::capnp::Result<codetracer_trace_types::TraceLowLevelEvent> {
+    let q = match event.which() {
+        Ok(trace::trace_low_level_event::Which::Step(step_record)) => {
+            let step_record = step_record?;
+            TraceLowLevelEvent::Step(codetracer_trace_types::StepRecord {
+                path_id: codetracer_trace_types::PathId(step_record.get_path_id()?.get_i().try_into().unwrap()),
+                line: codetracer_trace_types::Line(step_record.get_line()?.get_l()),
+            })
+        }
+        Ok(trace::trace_low_level_event::Which::Path(path_buf)) => {
+            TraceLowLevelEvent::Path(std::path::PathBuf::from_str(path_buf?.get_p()?.to_str()?).unwrap())
+        }
+        Ok(trace::trace_low_level_event::Which::VariableName(variable_name)) => TraceLowLevelEvent::VariableName(variable_name?.to_string()?),
+        Ok(trace::trace_low_level_event::Which::Variable(variable)) => TraceLowLevelEvent::Variable(variable?.to_string()?),
+        Ok(trace::trace_low_level_event::Which::Type(type_record)) => {
+            let type_record = type_record?;
+            TraceLowLevelEvent::Type(codetracer_trace_types::TypeRecord {
+                kind: type_record.get_kind()?.into(),
+                lang_type: type_record.get_lang_type()?.to_string()?,
+                specific_info: match type_record.get_specific_info()?.which() {
+                    Ok(trace::type_specific_info::Which::None(())) => codetracer_trace_types::TypeSpecificInfo::None,
+                    Ok(trace::type_specific_info::Which::Struct(s)) => codetracer_trace_types::TypeSpecificInfo::Struct {
+                        fields: get_field_type_records(s.get_fields()?)?,
                     },
-                })
-            }
-            Ok(trace::trace_low_level_event::Which::Value(fvr)) => TraceLowLevelEvent::Value(get_full_value_record(fvr?)?),
-            Ok(trace::trace_low_level_event::Which::Function(function_record)) => {
-                let function_record = function_record?;
-                TraceLowLevelEvent::Function(codetracer_trace_types::FunctionRecord {
-                    path_id: codetracer_trace_types::PathId(function_record.get_path_id()?.get_i().try_into().unwrap()),
-                    line: codetracer_trace_types::Line(function_record.get_line()?.get_l()),
-                    name: function_record.get_name()?.to_string()?,
-                })
-            }
-            Ok(trace::trace_low_level_event::Which::Call(call_record)) => {
-                let call_record = call_record?;
-                let sargs = call_record.get_args()?;
-                let mut args: Vec<codetracer_trace_types::FullValueRecord> = Vec::with_capacity(sargs.len().try_into().unwrap());
-                for sarg in sargs {
-                    args.push(codetracer_trace_types::FullValueRecord {
-                        variable_id: codetracer_trace_types::VariableId(sarg.get_variable_id()?.get_i().try_into().unwrap()),
-                        value: get_value_record(sarg.get_value()?)?,
-                    });
-                }
-                TraceLowLevelEvent::Call(codetracer_trace_types::CallRecord {
-                    function_id: codetracer_trace_types::FunctionId(call_record.get_function_id()?.get_i().try_into().unwrap()),
-                    args,
-                })
-            }
-            Ok(trace::trace_low_level_event::Which::Return(return_record)) => TraceLowLevelEvent::Return(codetracer_trace_types::ReturnRecord {
-                return_value: get_value_record(return_record?.get_return_value()?)?,
-            }),
-            Ok(trace::trace_low_level_event::Which::Event(record_event)) => {
-                let record_event = record_event?;
-                TraceLowLevelEvent::Event(codetracer_trace_types::RecordEvent {
-                    kind: record_event.get_kind()?.into(),
-                    metadata: record_event.get_metadata()?.to_string()?,
-                    content: record_event.get_content()?.to_string()?,
-                })
-            }
-            Ok(trace::trace_low_level_event::Which::Asm(asm_strings)) => {
-                let asm_strings = asm_strings?;
-                let mut strs: Vec<String> = Vec::with_capacity(asm_strings.len().try_into().unwrap());
-                for s in asm_strings {
-                    strs.push(s?.to_string()?);
-                }
-                TraceLowLevelEvent::Asm(strs)
-            }
-            Ok(trace::trace_low_level_event::Which::BindVariable(bind_variable_record)) => {
-                let bind_variable_record = bind_variable_record?;
-                TraceLowLevelEvent::BindVariable(codetracer_trace_types::BindVariableRecord {
-                    variable_id: codetracer_trace_types::VariableId(bind_variable_record.get_variable_id()?.get_i().try_into().unwrap()),
-                    place: codetracer_trace_types::Place(bind_variable_record.get_place()?.get_p()),
-                })
-            }
-            Ok(trace::trace_low_level_event::Which::Assignment(assignment_record)) => {
-                let assignment_record = assignment_record?;
-                TraceLowLevelEvent::Assignment(codetracer_trace_types::AssignmentRecord {
-                    to: codetracer_trace_types::VariableId(assignment_record.get_to()?.get_i().try_into().unwrap()),
-                    pass_by: match assignment_record.get_pass_by()? {
-                        trace::PassBy::Value => codetracer_trace_types::PassBy::Value,
-                        trace::PassBy::Reference => codetracer_trace_types::PassBy::Reference,
+                    Ok(trace::type_specific_info::Which::Pointer(p)) => codetracer_trace_types::TypeSpecificInfo::Pointer {
+                        dereference_type_id: codetracer_trace_types::TypeId(p.get_dereference_type_id()?.get_i().try_into().unwrap()),
                     },
-                    from: match assignment_record.get_from()?.which()? {
-                        trace::r_value::Which::Simple(variable_id) => {
-                            codetracer_trace_types::RValue::Simple(codetracer_trace_types::VariableId(variable_id?.get_i().try_into().unwrap()))
+                    Ok(trace::type_specific_info::Which::Collection(c)) => codetracer_trace_types::TypeSpecificInfo::Collection {
+                        element_type_id: codetracer_trace_types::TypeId(c.get_element_type_id()?.get_i().try_into().unwrap()),
+                    },
+                    Ok(trace::type_specific_info::Which::Map(m)) => codetracer_trace_types::TypeSpecificInfo::Map {
+                        key_type_id: codetracer_trace_types::TypeId(m.get_key_type_id()?.get_i().try_into().unwrap()),
+                        value_type_id: codetracer_trace_types::TypeId(m.get_value_type_id()?.get_i().try_into().unwrap()),
+                    },
+                    Ok(trace::type_specific_info::Which::Enum(e)) => {
+                        let e_variants = e.get_variants()?;
+                        let mut variants: Vec<codetracer_trace_types::VariantTypeRecord> =
+                            Vec::with_capacity(e_variants.len().try_into().unwrap());
+                        for e_variant in e_variants {
+                            variants.push(codetracer_trace_types::VariantTypeRecord {
+                                name: e_variant.get_name()?.to_string()?,
+                                fields: get_field_type_records(e_variant.get_fields()?)?,
+                            });
                         }
-                        trace::r_value::Which::Compound(variables) => {
-                            let variables = variables?;
-                            let mut v: Vec<VariableId> = Vec::with_capacity(variables.len().try_into().unwrap());
-                            for vv in variables {
-                                v.push(codetracer_trace_types::VariableId(vv.get_i().try_into().unwrap()));
-                            }
-                            codetracer_trace_types::RValue::Compound(v)
+                        codetracer_trace_types::TypeSpecificInfo::Enum { variants }
+                    }
+                    Ok(trace::type_specific_info::Which::Tuple(t)) => {
+                        let mut element_type_ids = vec![];
+                        for element_type_id in t.get_element_type_ids()? {
+                            element_type_ids.push(codetracer_trace_types::TypeId(element_type_id.get_i().try_into().unwrap()));
                         }
+                        codetracer_trace_types::TypeSpecificInfo::Tuple { element_type_ids }
+                    }
+                    Ok(trace::type_specific_info::Which::Function(f)) => codetracer_trace_types::TypeSpecificInfo::Function {
+                        parameters: get_field_type_records(f.get_parameters()?)?,
+                        return_type_id: codetracer_trace_types::TypeId(f.get_return_type_id()?.get_i().try_into().unwrap()),
                     },
-                })
-            }
-            Ok(trace::trace_low_level_event::Which::DropVariables(variables)) => {
-                let variables = variables?;
-                let mut v: Vec<codetracer_trace_types::VariableId> = Vec::with_capacity(variables.len().try_into().unwrap());
-                for vv in variables {
-                    v.push(codetracer_trace_types::VariableId(vv.get_i().try_into().unwrap()))
-                }
-                TraceLowLevelEvent::DropVariables(v)
-            }
-            Ok(trace::trace_low_level_event::Which::CompoundValue(compound_value_record)) => {
-                let compound_value_record = compound_value_record?;
-                TraceLowLevelEvent::CompoundValue(codetracer_trace_types::CompoundValueRecord {
-                    place: codetracer_trace_types::Place(compound_value_record.get_place()?.get_p()),
-                    value: get_value_record(compound_value_record.get_value()?)?,
-                })
+                    Err(_) => {
+                        panic!()
+                    }
+                },
+            })
+        }
+        Ok(trace::trace_low_level_event::Which::Value(fvr)) => TraceLowLevelEvent::Value(get_full_value_record(fvr?)?),
+        Ok(trace::trace_low_level_event::Which::Function(function_record)) => {
+            let function_record = function_record?;
+            TraceLowLevelEvent::Function(codetracer_trace_types::FunctionRecord {
+                path_id: codetracer_trace_types::PathId(function_record.get_path_id()?.get_i().try_into().unwrap()),
+                line: codetracer_trace_types::Line(function_record.get_line()?.get_l()),
+                name: function_record.get_name()?.to_string()?,
+            })
+        }
+        Ok(trace::trace_low_level_event::Which::Call(call_record)) => {
+            let call_record = call_record?;
+            let sargs = call_record.get_args()?;
+            let mut args: Vec<codetracer_trace_types::FullValueRecord> = Vec::with_capacity(sargs.len().try_into().unwrap());
+            for sarg in sargs {
+                args.push(codetracer_trace_types::FullValueRecord {
+                    variable_id: codetracer_trace_types::VariableId(sarg.get_variable_id()?.get_i().try_into().unwrap()),
+                    value: get_value_record(sarg.get_value()?)?,
+                });
             }
-            Ok(trace::trace_low_level_event::Which::CellValue(cell_value_record)) => {
-                let cell_value_record = cell_value_record?;
-                TraceLowLevelEvent::CellValue(codetracer_trace_types::CellValueRecord {
-                    place: codetracer_trace_types::Place(cell_value_record.get_place()?.get_p()),
-                    value: get_value_record(cell_value_record.get_value()?)?,
-                })
+            TraceLowLevelEvent::Call(codetracer_trace_types::CallRecord {
+                function_id: codetracer_trace_types::FunctionId(call_record.get_function_id()?.get_i().try_into().unwrap()),
+                args,
+            })
+        }
+        Ok(trace::trace_low_level_event::Which::Return(return_record)) => TraceLowLevelEvent::Return(codetracer_trace_types::ReturnRecord {
+            return_value: get_value_record(return_record?.get_return_value()?)?,
+        }),
+        Ok(trace::trace_low_level_event::Which::Event(record_event)) => {
+            let record_event = record_event?;
+            TraceLowLevelEvent::Event(codetracer_trace_types::RecordEvent {
+                kind: record_event.get_kind()?.into(),
+                metadata: record_event.get_metadata()?.to_string()?,
+                content: record_event.get_content()?.to_string()?,
+            })
+        }
+        Ok(trace::trace_low_level_event::Which::Asm(asm_strings)) => {
+            let asm_strings = asm_strings?;
+            let mut strs: Vec<String> = Vec::with_capacity(asm_strings.len().try_into().unwrap());
+            for s in asm_strings {
+                strs.push(s?.to_string()?);
             }
-            Ok(trace::trace_low_level_event::Which::AssignCompoundItem(assign_compound_item_record)) => {
-                let assign_compound_item_record = assign_compound_item_record?;
-                TraceLowLevelEvent::AssignCompoundItem(codetracer_trace_types::AssignCompoundItemRecord {
-                    place: codetracer_trace_types::Place(assign_compound_item_record.get_place()?.get_p()),
-                    index: assign_compound_item_record.get_index().try_into().unwrap(),
-                    item_place: codetracer_trace_types::Place(assign_compound_item_record.get_item_place()?.get_p()),
-                })
+            TraceLowLevelEvent::Asm(strs)
+        }
+        Ok(trace::trace_low_level_event::Which::BindVariable(bind_variable_record)) => {
+            let bind_variable_record = bind_variable_record?;
+            TraceLowLevelEvent::BindVariable(codetracer_trace_types::BindVariableRecord {
+                variable_id: codetracer_trace_types::VariableId(bind_variable_record.get_variable_id()?.get_i().try_into().unwrap()),
+                place: codetracer_trace_types::Place(bind_variable_record.get_place()?.get_p()),
+            })
+        }
+        Ok(trace::trace_low_level_event::Which::Assignment(assignment_record)) => {
+            let assignment_record = assignment_record?;
+            TraceLowLevelEvent::Assignment(codetracer_trace_types::AssignmentRecord {
+                to: codetracer_trace_types::VariableId(assignment_record.get_to()?.get_i().try_into().unwrap()),
+                pass_by: match assignment_record.get_pass_by()? {
+                    trace::PassBy::Value => codetracer_trace_types::PassBy::Value,
+                    trace::PassBy::Reference => codetracer_trace_types::PassBy::Reference,
+                },
+                from: match assignment_record.get_from()?.which()? {
+                    trace::r_value::Which::Simple(variable_id) => {
+                        codetracer_trace_types::RValue::Simple(codetracer_trace_types::VariableId(variable_id?.get_i().try_into().unwrap()))
+                    }
+                    trace::r_value::Which::Compound(variables) => {
+                        let variables = variables?;
+                        let mut v: Vec<VariableId> = Vec::with_capacity(variables.len().try_into().unwrap());
+                        for vv in variables {
+                            v.push(codetracer_trace_types::VariableId(vv.get_i().try_into().unwrap()));
+                        }
+                        codetracer_trace_types::RValue::Compound(v)
+                    }
+                },
+            })
+        }
+        Ok(trace::trace_low_level_event::Which::DropVariables(variables)) => {
+            let variables = variables?;
+            let mut v: Vec<codetracer_trace_types::VariableId> = Vec::with_capacity(variables.len().try_into().unwrap());
+            for vv in variables {
+                v.push(codetracer_trace_types::VariableId(vv.get_i().try_into().unwrap()))
             }
-            Ok(trace::trace_low_level_event::Which::AssignCell(assign_cell_record)) => {
-                let assign_cell_record = assign_cell_record?;
-                TraceLowLevelEvent::AssignCell(codetracer_trace_types::AssignCellRecord {
-                    place: codetracer_trace_types::Place(assign_cell_record.get_place()?.get_p()),
-                    new_value: get_value_record(assign_cell_record.get_new_value()?)?,
+            TraceLowLevelEvent::DropVariables(v)
+        }
+        Ok(trace::trace_low_level_event::Which::CompoundValue(compound_value_record)) => {
+            let compound_value_record = compound_value_record?;
+            TraceLowLevelEvent::CompoundValue(codetracer_trace_types::CompoundValueRecord {
+                place: codetracer_trace_types::Place(compound_value_record.get_place()?.get_p()),
+                value: get_value_record(compound_value_record.get_value()?)?,
+            })
+        }
+        Ok(trace::trace_low_level_event::Which::CellValue(cell_value_record)) => {
+            let cell_value_record = cell_value_record?;
+            TraceLowLevelEvent::CellValue(codetracer_trace_types::CellValueRecord {
+                place: codetracer_trace_types::Place(cell_value_record.get_place()?.get_p()),
+                value: get_value_record(cell_value_record.get_value()?)?,
+            })
+        }
+        Ok(trace::trace_low_level_event::Which::AssignCompoundItem(assign_compound_item_record)) => {
+            let assign_compound_item_record = assign_compound_item_record?;
+            TraceLowLevelEvent::AssignCompoundItem(codetracer_trace_types::AssignCompoundItemRecord {
+                place: codetracer_trace_types::Place(assign_compound_item_record.get_place()?.get_p()),
+                index: assign_compound_item_record.get_index().try_into().unwrap(),
+                item_place: codetracer_trace_types::Place(assign_compound_item_record.get_item_place()?.get_p()),
+            })
+        }
+        Ok(trace::trace_low_level_event::Which::AssignCell(assign_cell_record)) => {
+            let assign_cell_record = assign_cell_record?;
+            TraceLowLevelEvent::AssignCell(codetracer_trace_types::AssignCellRecord {
+                place: codetracer_trace_types::Place(assign_cell_record.get_place()?.get_p()),
+                new_value: get_value_record(assign_cell_record.get_new_value()?)?,
+            })
+        }
+        Ok(trace::trace_low_level_event::Which::VariableCell(variable_cell_record)) => {
+            let variable_cell_record = variable_cell_record?;
+            TraceLowLevelEvent::VariableCell(codetracer_trace_types::VariableCellRecord {
+                variable_id: codetracer_trace_types::VariableId(variable_cell_record.get_variable_id()?.get_i().try_into().unwrap()),
+                place: codetracer_trace_types::Place(variable_cell_record.get_place()?.get_p()),
+            })
+        }
+        Ok(trace::trace_low_level_event::Which::DropVariable(variable_id)) => {
+            TraceLowLevelEvent::DropVariable(codetracer_trace_types::VariableId(variable_id?.get_i().try_into().unwrap()))
+        }
+        Ok(trace::trace_low_level_event::Which::ThreadStart(thread_id)) => {
+            TraceLowLevelEvent::ThreadStart(codetracer_trace_types::ThreadId(thread_id?.get_i()))
+        }
+        Ok(trace::trace_low_level_event::Which::ThreadExit(thread_id)) => {
+            TraceLowLevelEvent::ThreadExit(codetracer_trace_types::ThreadId(thread_id?.get_i()))
+        }
+        Ok(trace::trace_low_level_event::Which::ThreadSwitch(thread_id)) => {
+            TraceLowLevelEvent::ThreadSwitch(codetracer_trace_types::ThreadId(thread_id?.get_i()))
+        }
+        Ok(trace::trace_low_level_event::Which::Timestamp(nanoseconds)) => TraceLowLevelEvent::Timestamp(nanoseconds),
+        Ok(trace::trace_low_level_event::Which::DroppedEvents(count)) => TraceLowLevelEvent::DroppedEvents(count),
+        Ok(trace::trace_low_level_event::Which::DropLastStep(())) => TraceLowLevelEvent::DropLastStep,
+        Err(_) => {
+            panic!()
+        }
+    };
+    Ok(q)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codetracer_trace_types::{FullValueRecord, Line, PathId, StepRecord, TypeId, ValueRecord};
+
+    fn steps(count: i64) -> Vec<TraceLowLevelEvent> {
+        (0..count)
+            .map(|i| {
+                TraceLowLevelEvent::Step(StepRecord {
+                    path_id: PathId(0),
+                    line: Line(i),
                 })
-            }
-            Ok(trace::trace_low_level_event::Which::VariableCell(variable_cell_record)) => {
-                let variable_cell_record = variable_cell_record?;
-                TraceLowLevelEvent::VariableCell(codetracer_trace_types::VariableCellRecord {
-                    variable_id: codetracer_trace_types::VariableId(variable_cell_record.get_variable_id()?.get_i().try_into().unwrap()),
-                    place: codetracer_trace_types::Place(variable_cell_record.get_place()?.get_p()),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_single_message_and_segmented_traces() {
+        let events = steps(10);
+
+        let mut single_message = vec![];
+        write_trace(&events, &mut single_message).unwrap();
+
+        let mut segmented = SEGMENTED_HEADER.to_vec();
+        write_event_batch(&events[..4], &mut segmented).unwrap();
+        write_event_batch(&events[4..], &mut segmented).unwrap();
+
+        for buf in [single_message, segmented] {
+            let read = read_trace(&mut &buf[..]).unwrap();
+            assert_eq!(format!("{read:?}"), format!("{events:?}"));
+        }
+    }
+
+    #[test]
+    fn test_optional_original_lengths() {
+        let type_id = TypeId(0);
+        let events: Vec<TraceLowLevelEvent> = [None, Some(0), Some(10)]
+            .into_iter()
+            .flat_map(|original_length| {
+                let bytes = ValueRecord::Bytes {
+                    data: vec![],
+                    original_length,
+                    type_id,
+                };
+                let elided = ValueRecord::Elided {
+                    original_length,
+                    preview: None,
+                    type_id,
+                };
+                [bytes, elided].map(|value| {
+                    TraceLowLevelEvent::Value(FullValueRecord {
+                        variable_id: VariableId(0),
+                        value,
+                    })
                 })
-            }
-            Ok(trace::trace_low_level_event::Which::DropVariable(variable_id)) => {
-                TraceLowLevelEvent::DropVariable(codetracer_trace_types::VariableId(variable_id?.get_i().try_into().unwrap()))
-            }
-            Ok(trace::trace_low_level_event::Which::ThreadStart(thread_id)) => {
-                TraceLowLevelEvent::ThreadStart(codetracer_trace_types::ThreadId(thread_id?.get_i()))
-            }
-            Ok(trace::trace_low_level_event::Which::ThreadExit(thread_id)) => {
-                TraceLowLevelEvent::ThreadExit(codetracer_trace_types::ThreadId(thread_id?.get_i()))
-            }
-            Ok(trace::trace_low_level_event::Which::ThreadSwitch(thread_id)) => {
-                TraceLowLevelEvent::ThreadSwitch(codetracer_trace_types::ThreadId(thread_id?.get_i()))
-            }
-            Ok(trace::trace_low_level_event::Which::DropLastStep(())) => TraceLowLevelEvent::DropLastStep,
-            Err(_) => {
-                panic!()
-            }
-        };
-        res.push(q);
+            })
+            .collect();
+
+        let mut segmented = SEGMENTED_HEADER.to_vec();
+        write_event_batch(&events, &mut segmented).unwrap();
+        let read = read_trace(&mut &segmented[..]).unwrap();
+        assert_eq!(format!("{read:?}"), format!("{events:?}"));
     }
 
-    Ok(res)
+    #[test]
+    fn test_event_batches() {
+        let mut segmented = vec![];
+        write_event_batch(&steps(3), &mut segmented).unwrap();
+        write_event_batch(&steps(5), &mut segmented).unwrap();
+
+        let batch_sizes: Vec<usize> = EventBatches::new(&segmented[..]).map(|batch| batch.unwrap().len()).collect();
+        assert_eq!(batch_sizes, vec![3, 5]);
+    }
 }
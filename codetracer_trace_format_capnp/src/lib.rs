@@ -1,4 +1,5 @@
 pub mod capnptrace;
+pub mod view;
 
 pub mod trace_capnp {
     include!(concat!(env!("OUT_DIR"), "/src/trace_capnp.rs"));
@@ -0,0 +1,425 @@
+//! Borrowed views of segmented traces, which read the events in place instead of converting
+//! them into owned `TraceLowLevelEvent`s.
+//!
+//! Scanning the steps and calls of a memory-mapped trace this way doesn't allocate; single
+//! events and values can still be converted into the owned types with `to_event` and
+//! `to_value_record` when needed.
+
+use capnp::{
+    message::{self, ReaderOptions},
+    serialize::{self, BufferSegments},
+};
+use codetracer_trace_types::{EventLogKind, FunctionId, Line, PathId, StepRecord, ThreadId, TraceLowLevelEvent, TypeId, ValueRecord, VariableId};
+
+use crate::capnptrace::{SEGMENTED_HEADER, get_event, get_value_record};
+use crate::trace_capnp::trace;
+
+/// Cap'n Proto messages can only be read in place from 8-byte aligned memory
+const ALIGNMENT: usize = 8;
+
+/// The traversal limit of each message of a [`TraceView`], in multiples of the size of the whole
+/// trace: plenty for repeated scans, but messages whose pointers make them expand far beyond the
+/// size of the file are still rejected
+pub const TRAVERSAL_LIMIT_FACTOR: usize = 64;
+
+/// A segmented trace (see `SEGMENTED_HEADER`) read in place from a byte slice
+pub struct TraceView<'a> {
+    messages: Vec<message::Reader<BufferSegments<&'a [u8]>>>,
+}
+
+impl<'a> TraceView<'a> {
+    /// `bytes` holds the whole trace, header included, and must be 8-byte aligned, like the
+    /// contents of a memory-mapped file are
+    pub fn new(bytes: &'a [u8]) -> capnp::Result<Self> {
+        let traversal_limit = (bytes.len() / ALIGNMENT).saturating_mul(TRAVERSAL_LIMIT_FACTOR);
+        Self::with_options(bytes, *ReaderOptions::new().traversal_limit_in_words(Some(traversal_limit)))
+    }
+
+    /// Like `new`, with the given reader options for every message. The traversal limit adds up
+    /// over repeated scans of a message; only disable it for traces from a trusted source
+    pub fn with_options(bytes: &'a [u8], options: ReaderOptions) -> capnp::Result<Self> {
+        if bytes.as_ptr().align_offset(ALIGNMENT) != 0 {
+            return Err(capnp::Error::failed("The trace must be 8-byte aligned to be viewed in place".to_string()));
+        }
+        let Some(mut rest) = bytes.strip_prefix(SEGMENTED_HEADER) else {
+            return Err(capnp::Error::failed("Only segmented binary traces can be viewed in place".to_string()));
+        };
+
+        let mut messages = vec![];
+        while !rest.is_empty() {
+            messages.push(serialize::read_message_from_flat_slice(&mut rest, options)?);
+        }
+        Ok(TraceView { messages })
+    }
+
+    pub fn event_count(&self) -> capnp::Result<usize> {
+        let mut count = 0;
+        for message in &self.messages {
+            count += message.get_root::<trace::Reader>()?.get_events()?.len() as usize;
+        }
+        Ok(count)
+    }
+
+    pub fn events(&self) -> impl Iterator<Item = capnp::Result<EventRef<'_>>> {
+        self.messages.iter().flat_map(|message| {
+            let (events, error) = match message.get_root::<trace::Reader>().and_then(|trace| trace.get_events()) {
+                Ok(events) => (Some(events), None),
+                Err(err) => (None, Some(err)),
+            };
+            error
+                .map(Err)
+                .into_iter()
+                .chain(events.into_iter().flatten().map(|event| Ok(EventRef(event))))
+        })
+    }
+}
+
+/// An event of a [`TraceView`]
+#[derive(Clone, Copy)]
+pub struct EventRef<'a>(trace::trace_low_level_event::Reader<'a>);
+
+/// The contents of an [`EventRef`], borrowed from the trace
+pub enum EventView<'a> {
+    Step(StepRecord),
+    Path(&'a str),
+    VariableName(&'a str),
+    Function {
+        name: &'a str,
+        path_id: PathId,
+        line: Line,
+    },
+    Call {
+        function_id: FunctionId,
+        args: ArgsRef<'a>,
+    },
+    Return(ValueRef<'a>),
+    Value {
+        variable_id: VariableId,
+        value: ValueRef<'a>,
+    },
+    Event {
+        kind: EventLogKind,
+        metadata: &'a str,
+        content: &'a str,
+    },
+    ThreadStart(ThreadId),
+    ThreadExit(ThreadId),
+    ThreadSwitch(ThreadId),
+    Timestamp(u64),
+    DroppedEvents(u64),
+    DropLastStep,
+    /// Any other event; `EventRef::to_event` converts it
+    Other,
+}
+
+impl<'a> EventRef<'a> {
+    pub fn get(&self) -> capnp::Result<EventView<'a>> {
+        use trace::trace_low_level_event::Which;
+
+        Ok(match self.0.which()? {
+            Which::Step(step_record) => {
+                let step_record = step_record?;
+                EventView::Step(StepRecord {
+                    path_id: PathId(step_record.get_path_id()?.get_i().try_into().unwrap()),
+                    line: Line(step_record.get_line()?.get_l()),
+                })
+            }
+            Which::Path(path_buf) => EventView::Path(path_buf?.get_p()?.to_str()?),
+            Which::VariableName(variable_name) => EventView::VariableName(variable_name?.to_str()?),
+            Which::Function(function_record) => {
+                let function_record = function_record?;
+                EventView::Function {
+                    name: function_record.get_name()?.to_str()?,
+                    path_id: PathId(function_record.get_path_id()?.get_i().try_into().unwrap()),
+                    line: Line(function_record.get_line()?.get_l()),
+                }
+            }
+            Which::Call(call_record) => {
+                let call_record = call_record?;
+                EventView::Call {
+                    function_id: FunctionId(call_record.get_function_id()?.get_i().try_into().unwrap()),
+                    args: ArgsRef(call_record.get_args()?),
+                }
+            }
+            Which::Return(return_record) => EventView::Return(ValueRef(return_record?.get_return_value()?)),
+            Which::Value(full_value_record) => {
+                let full_value_record = full_value_record?;
+                EventView::Value {
+                    variable_id: VariableId(full_value_record.get_variable_id()?.get_i().try_into().unwrap()),
+                    value: ValueRef(full_value_record.get_value()?),
+                }
+            }
+            Which::Event(record_event) => {
+                let record_event = record_event?;
+                EventView::Event {
+                    kind: record_event.get_kind()?.into(),
+                    metadata: record_event.get_metadata()?.to_str()?,
+                    content: record_event.get_content()?.to_str()?,
+                }
+            }
+            Which::ThreadStart(thread_id) => EventView::ThreadStart(ThreadId(thread_id?.get_i())),
+            Which::ThreadExit(thread_id) => EventView::ThreadExit(ThreadId(thread_id?.get_i())),
+            Which::ThreadSwitch(thread_id) => EventView::ThreadSwitch(ThreadId(thread_id?.get_i())),
+            Which::Timestamp(nanoseconds) => EventView::Timestamp(nanoseconds),
+            Which::DroppedEvents(count) => EventView::DroppedEvents(count),
+            Which::DropLastStep(()) => EventView::DropLastStep,
+            _ => EventView::Other,
+        })
+    }
+
+    pub fn to_event(&self) -> capnp::Result<TraceLowLevelEvent> {
+        get_event(self.0)
+    }
+}
+
+/// The arguments of a `Call` event
+#[derive(Clone, Copy)]
+pub struct ArgsRef<'a>(capnp::struct_list::Reader<'a, trace::full_value_record::Owned>);
+
+impl<'a> ArgsRef<'a> {
+    pub fn len(&self) -> usize {
+        self.0.len() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = capnp::Result<(VariableId, ValueRef<'a>)>> + use<'a> {
+        self.0.iter().map(|arg| {
+            let variable_id = VariableId(arg.get_variable_id()?.get_i().try_into().unwrap());
+            Ok((variable_id, ValueRef(arg.get_value()?)))
+        })
+    }
+}
+
+/// A value of a [`TraceView`]
+#[derive(Clone, Copy)]
+pub struct ValueRef<'a>(trace::value_record::Reader<'a>);
+
+/// The contents of a [`ValueRef`], borrowed from the trace
+pub enum ValueView<'a> {
+    Int {
+        i: i64,
+        type_id: TypeId,
+    },
+    UInt {
+        u: u64,
+        type_id: TypeId,
+    },
+    Float {
+        f: f64,
+        type_id: TypeId,
+    },
+    Bool {
+        b: bool,
+        type_id: TypeId,
+    },
+    String {
+        text: &'a str,
+        type_id: TypeId,
+    },
+    Bytes {
+        data: &'a [u8],
+        type_id: TypeId,
+    },
+    Sequence {
+        elements: ValuesRef<'a>,
+        is_slice: bool,
+        type_id: TypeId,
+    },
+    Tuple {
+        elements: ValuesRef<'a>,
+        type_id: TypeId,
+    },
+    Struct {
+        field_values: ValuesRef<'a>,
+        type_id: TypeId,
+    },
+    None {
+        type_id: TypeId,
+    },
+    /// Any other value; `ValueRef::to_value_record` converts it
+    Other,
+}
+
+impl<'a> ValueRef<'a> {
+    pub fn get(&self) -> capnp::Result<ValueView<'a>> {
+        use trace::value_record::Which;
+
+        fn type_id(type_id: capnp::Result<trace::type_id::Reader>) -> capnp::Result<TypeId> {
+            Ok(TypeId(type_id?.get_i().try_into().unwrap()))
+        }
+
+        Ok(match self.0.which()? {
+            Which::Int(q) => ValueView::Int {
+                i: q.get_i(),
+                type_id: type_id(q.get_type_id())?,
+            },
+            Which::Uint(q) => ValueView::UInt {
+                u: q.get_u(),
+                type_id: type_id(q.get_type_id())?,
+            },
+            Which::Float(q) => ValueView::Float {
+                f: q.get_f(),
+                type_id: type_id(q.get_type_id())?,
+            },
+            Which::Bool(q) => ValueView::Bool {
+                b: q.get_b(),
+                type_id: type_id(q.get_type_id())?,
+            },
+            Which::String(q) => ValueView::String {
+                text: q.get_text()?.to_str()?,
+                type_id: type_id(q.get_type_id())?,
+            },
+            Which::Bytes(q) => ValueView::Bytes {
+                data: q.get_data()?,
+                type_id: type_id(q.get_type_id())?,
+            },
+            Which::Sequence(q) => ValueView::Sequence {
+                elements: ValuesRef(q.get_elements()?),
+                is_slice: q.get_is_slice(),
+                type_id: type_id(q.get_type_id())?,
+            },
+            Which::Tuple(q) => ValueView::Tuple {
+                elements: ValuesRef(q.get_elements()?),
+                type_id: type_id(q.get_type_id())?,
+            },
+            Which::Struct(q) => ValueView::Struct {
+                field_values: ValuesRef(q.get_field_values()?),
+                type_id: type_id(q.get_type_id())?,
+            },
+            Which::None(q) => ValueView::None {
+                type_id: type_id(q.get_type_id())?,
+            },
+            _ => ValueView::Other,
+        })
+    }
+
+    pub fn to_value_record(&self) -> capnp::Result<ValueRecord> {
+        get_value_record(self.0)
+    }
+}
+
+/// The elements of a compound value
+#[derive(Clone, Copy)]
+pub struct ValuesRef<'a>(capnp::struct_list::Reader<'a, trace::value_record::Owned>);
+
+impl<'a> ValuesRef<'a> {
+    pub fn len(&self) -> usize {
+        self.0.len() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<ValueRef<'a>> {
+        self.0.try_get(index.try_into().ok()?).map(ValueRef)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = ValueRef<'a>> + use<'a> {
+        self.0.iter().map(ValueRef)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capnptrace::write_event_batch;
+    use codetracer_trace_types::{CallRecord, FullValueRecord, FunctionRecord};
+
+    // copies the trace into 8-byte aligned memory, like a memory-mapped file
+    fn aligned(bytes: &[u8]) -> Vec<capnp::Word> {
+        let mut words = capnp::Word::allocate_zeroed_vec(bytes.len().div_ceil(8));
+        capnp::Word::words_to_bytes_mut(&mut words)[..bytes.len()].copy_from_slice(bytes);
+        words
+    }
+
+    #[test]
+    fn test_trace_view() {
+        let int = |i| ValueRecord::Int { i, type_id: TypeId(0) };
+        let sequence = ValueRecord::Sequence {
+            elements: vec![int(1), int(2)],
+            is_slice: false,
+            type_id: TypeId(1),
+        };
+        let events = vec![
+            TraceLowLevelEvent::Path("/test/main.rs".into()),
+            TraceLowLevelEvent::Function(FunctionRecord {
+                path_id: PathId(0),
+                line: Line(1),
+                name: "main".to_string(),
+            }),
+            TraceLowLevelEvent::Call(CallRecord {
+                function_id: FunctionId(0),
+                args: vec![FullValueRecord {
+                    variable_id: VariableId(0),
+                    value: sequence.clone(),
+                }],
+            }),
+            TraceLowLevelEvent::Step(StepRecord {
+                path_id: PathId(0),
+                line: Line(2),
+            }),
+        ];
+
+        let mut trace = SEGMENTED_HEADER.to_vec();
+        write_event_batch(&events[..2], &mut trace).unwrap();
+        write_event_batch(&events[2..], &mut trace).unwrap();
+        let words = aligned(&trace);
+        let view = TraceView::new(&capnp::Word::words_to_bytes(&words)[..trace.len()]).unwrap();
+
+        assert_eq!(view.event_count().unwrap(), 4);
+        let event_refs: Vec<EventRef> = view.events().map(|event| event.unwrap()).collect();
+        assert!(matches!(event_refs[0].get().unwrap(), EventView::Path("/test/main.rs")));
+        assert!(matches!(event_refs[1].get().unwrap(), EventView::Function { name: "main", .. }));
+
+        let EventView::Call { function_id, args } = event_refs[2].get().unwrap() else {
+            panic!("expected a call");
+        };
+        assert_eq!(function_id, FunctionId(0));
+        assert_eq!(args.len(), 1);
+        let (variable_id, value) = args.iter().next().unwrap().unwrap();
+        assert_eq!(variable_id, VariableId(0));
+        let ValueView::Sequence { elements, .. } = value.get().unwrap() else {
+            panic!("expected a sequence");
+        };
+        let ints: Vec<i64> = elements
+            .iter()
+            .map(|element| match element.get().unwrap() {
+                ValueView::Int { i, .. } => i,
+                _ => panic!("expected an int"),
+            })
+            .collect();
+        assert_eq!(ints, vec![1, 2]);
+        assert_eq!(format!("{:?}", value.to_value_record().unwrap()), format!("{sequence:?}"));
+
+        let owned: Vec<TraceLowLevelEvent> = event_refs.iter().map(|event| event.to_event().unwrap()).collect();
+        assert_eq!(format!("{owned:?}"), format!("{events:?}"));
+    }
+
+    #[test]
+    fn test_trace_view_traversal_limit() {
+        let events = vec![TraceLowLevelEvent::Path("/test/main.rs".into()); 100];
+        let mut trace = SEGMENTED_HEADER.to_vec();
+        write_event_batch(&events, &mut trace).unwrap();
+        let words = aligned(&trace);
+        let bytes = &capnp::Word::words_to_bytes(&words)[..trace.len()];
+
+        let view = TraceView::new(bytes).unwrap();
+        for _ in 0..10 {
+            assert!(view.events().all(|event| event.and_then(|event| event.to_event()).is_ok()));
+        }
+
+        assert!(TraceView::with_options(bytes, *ReaderOptions::new().traversal_limit_in_words(Some(16))).is_err());
+    }
+
+    #[test]
+    fn test_trace_view_rejects_single_message_traces() {
+        let mut trace = vec![];
+        crate::capnptrace::write_trace(&[], &mut trace).unwrap();
+        let words = aligned(&trace);
+        assert!(TraceView::new(&capnp::Word::words_to_bytes(&words)[..trace.len()]).is_err());
+    }
+}
@@ -1,7 +1,33 @@
+pub mod live;
+pub mod wire;
+
+/// Revision of the version 1 format, stored in the byte after the version. Revisions only add
+/// new kinds of items or guarantees about the stream, so a reader supporting a revision can read
+/// all earlier ones too.
+///
+/// * 0 – every item is a CBOR encoded `TraceLowLevelEvent`
+/// * 1 – adds value back-references, see [`wire`]
+/// * 2 – adds step line deltas
+/// * 3 – zstd frames end at item boundaries, so they can be decoded independently
+pub const FORMAT_REVISION: u8 = 3;
+
+/// The first revision whose frames end at item boundaries
+pub const ALIGNED_FRAMES_REVISION: u8 = 3;
+
 /// The next 3 bytes are reserved/version info.
 /// The header is 8 bytes in size, ensuring 64-bit alignment for the rest of the file.
 pub const HEADERV1: &[u8] = &[
     0xC0, 0xDE, 0x72, 0xAC, 0xE2, // The first 5 bytes identify the file as a CodeTracer file (hex l33tsp33k - C0DE72ACE2 for "CodeTracer").
     0x01, // Indicates version 1 of the file format
-    0x00, 0x00,
+    FORMAT_REVISION, 0x00,
 ]; // Reserved, must be zero in this version.
+
+/// Returns the revision of a version 1 header, or `None` if `header` is not
+/// a version 1 header or its revision is newer than [`FORMAT_REVISION`].
+pub fn headerv1_revision(header: &[u8]) -> Option<u8> {
+    if header.len() == HEADERV1.len() && header[..6] == HEADERV1[..6] && header[6] <= FORMAT_REVISION && header[7] == 0 {
+        Some(header[6])
+    } else {
+        None
+    }
+}
@@ -0,0 +1,159 @@
+//! Protocol for streaming a trace while it's being recorded, e.g. over TCP or a Unix domain socket.
+//!
+//! A stream starts with an 8 byte header, like a trace file, whose version byte is `S` and whose
+//! revision byte is the protocol version. Messages follow, each a kind byte, the length of the
+//! payload as a little-endian `u32` and the payload:
+//!
+//! * `METADATA` – the `TraceMetadata` of the trace, CBOR encoded. It's the first message, and
+//!   together with the header forms the handshake. Senders repeat it before `END` with the final
+//!   values, e.g. the exit code; a later one replaces the earlier.
+//! * `EVENTS` – a batch of items of the event stream, as described in [`wire`](crate::wire), but
+//!   without value back-references, so the receiver doesn't need to keep the events it got.
+//! * `END` – the trace is complete; the payload is empty.
+
+use std::{
+    error::Error,
+    io::{self, Read, Write},
+};
+
+use codetracer_trace_types::TraceMetadata;
+
+/// Version of the protocol. Receivers accept every version up to the one they implement.
+pub const PROTOCOL_VERSION: u8 = 1;
+
+pub const STREAM_HEADER: &[u8] = &[
+    0xC0,
+    0xDE,
+    0x72,
+    0xAC,
+    0xE2,
+    b'S', // a stream, not a trace file
+    PROTOCOL_VERSION,
+    0x00,
+]; // Reserved, must be zero in this version.
+
+/// Senders send a batch when it reaches this many bytes
+pub const DEFAULT_BATCH_SIZE: usize = 64 * 1024;
+
+// limits the memory a malformed or hostile stream can make a receiver allocate
+const MAX_MESSAGE_SIZE: usize = 256 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    Metadata = 0,
+    Events = 1,
+    End = 2,
+}
+
+impl TryFrom<u8> for MessageKind {
+    type Error = Box<dyn Error>;
+
+    fn try_from(kind: u8) -> Result<Self, Self::Error> {
+        match kind {
+            0 => Ok(MessageKind::Metadata),
+            1 => Ok(MessageKind::Events),
+            2 => Ok(MessageKind::End),
+            _ => Err(format!("unknown trace stream message kind {kind}").into()),
+        }
+    }
+}
+
+/// Returns the protocol version of a stream header, or `None` if `header` is not a stream header
+/// or its version is newer than [`PROTOCOL_VERSION`].
+pub fn stream_header_version(header: &[u8]) -> Option<u8> {
+    if header.len() == STREAM_HEADER.len() && header[..6] == STREAM_HEADER[..6] && header[6] <= PROTOCOL_VERSION && header[7] == 0 {
+        Some(header[6])
+    } else {
+        None
+    }
+}
+
+pub fn write_message(output: &mut impl Write, kind: MessageKind, payload: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(payload.len()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "trace stream message too large"))?;
+    output.write_all(&[kind as u8])?;
+    output.write_all(&len.to_le_bytes())?;
+    output.write_all(payload)
+}
+
+pub fn write_metadata_message(output: &mut impl Write, metadata: &TraceMetadata) -> io::Result<()> {
+    let payload = cbor4ii::serde::to_vec(Vec::new(), metadata).map_err(io::Error::other)?;
+    write_message(output, MessageKind::Metadata, &payload)
+}
+
+/// The kind and the payload of a message
+pub type Message = (MessageKind, Vec<u8>);
+
+/// Reads the next message, or returns `None` if the stream ended before it
+pub fn read_message(input: &mut impl Read) -> Result<Option<Message>, Box<dyn Error>> {
+    let mut kind = [0; 1];
+    match input.read_exact(&mut kind) {
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        result => result?,
+    }
+    let kind = MessageKind::try_from(kind[0])?;
+
+    let mut len = [0; 4];
+    input.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_MESSAGE_SIZE {
+        return Err(format!("trace stream message of {len} bytes is too large").into());
+    }
+    let mut payload = vec![0; len];
+    input.read_exact(&mut payload)?;
+    Ok(Some((kind, payload)))
+}
+
+pub fn read_metadata_payload(payload: &[u8]) -> Result<TraceMetadata, Box<dyn Error>> {
+    Ok(cbor4ii::serde::from_slice(payload)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_messages() {
+        let mut metadata = TraceMetadata {
+            workdir: "/work".into(),
+            program: "program.small".to_string(),
+            args: vec!["arg".to_string()],
+            format_version: 1,
+            start_time: Some(1),
+            end_time: None,
+            hostname: None,
+            environment: BTreeMap::new(),
+            language: None,
+            recorder: None,
+            git: None,
+            exit_code: Some(3),
+            extensions: BTreeMap::new(),
+        };
+        metadata
+            .extensions
+            .insert("small".to_string(), serde_json::json!({"optimized": false, "level": -1}));
+
+        let mut stream = STREAM_HEADER.to_vec();
+        write_metadata_message(&mut stream, &metadata).unwrap();
+        write_message(&mut stream, MessageKind::Events, &[1, 2, 3]).unwrap();
+        write_message(&mut stream, MessageKind::End, &[]).unwrap();
+
+        assert_eq!(stream_header_version(&stream[..8]), Some(PROTOCOL_VERSION));
+        let mut input = &stream[8..];
+        let (kind, payload) = read_message(&mut input).unwrap().unwrap();
+        assert_eq!(kind, MessageKind::Metadata);
+        let received = read_metadata_payload(&payload).unwrap();
+        assert_eq!(received.program, metadata.program);
+        assert_eq!(received.exit_code, Some(3));
+        assert_eq!(received.extensions, metadata.extensions);
+
+        assert_eq!(read_message(&mut input).unwrap().unwrap(), (MessageKind::Events, vec![1, 2, 3]));
+        assert_eq!(read_message(&mut input).unwrap().unwrap(), (MessageKind::End, vec![]));
+        assert!(read_message(&mut input).unwrap().is_none());
+
+        // a message cut off in the middle is an error, not the end of the stream
+        assert!(read_message(&mut &[1, 3, 0, 0, 0, 1][..]).is_err());
+        assert!(read_message(&mut &[7, 0, 0, 0, 0][..]).is_err());
+        assert_eq!(stream_header_version(&[0xC0, 0xDE, 0x72, 0xAC, 0xE2, 0x01, 0x00, 0x00]), None);
+    }
+}
@@ -0,0 +1,449 @@
+//! Items of the CBOR event stream.
+//!
+//! The uncompressed stream is a sequence of CBOR items. Most of them are `TraceLowLevelEvent`s,
+//! which serde encodes as CBOR maps (or text strings, for variants without data). A CBOR array
+//! is never a valid event, so since revision 1 arrays are used for compact items instead:
+//!
+//! * `[variable_id, source_event]` – a value back-reference: a `Value` event for `variable_id`
+//!   with the same value as the `Value` event at index `source_event` of the stream. Writers emit
+//!   it for large values which were already recorded, e.g. arrays captured unchanged on every step.
+//!   It only refers to the values a [`SharedValueWindow`] keeps, so readers don't have to keep
+//!   all of them.
+//!
+//! Integers are never valid events either, so since revision 2:
+//!
+//! * `line_delta` – a `Step` in the same file as the previous `Step` of the stream, on the line
+//!   `line_delta` lines after it. Small deltas, like the usual `1` of the next line, take one byte.
+//!
+//! Every item expands to exactly one event, so event indices are the same before and after expansion.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    error::Error,
+    io::BufRead,
+    sync::Arc,
+};
+
+use codetracer_trace_types::{FullValueRecord, Line, StepRecord, TraceLowLevelEvent, ValueRecord, VariableId};
+
+/// Values with a shorter encoding are always written inline: a back-reference wouldn't be much smaller
+pub const MIN_SHARED_VALUE_SIZE: usize = 32;
+
+/// Total encoded size of the values back-references can refer to; the oldest are forgotten first
+pub const MAX_SHARED_VALUES_SIZE: usize = 64 * 1024 * 1024;
+
+// CBOR major types, in the top 3 bits of the initial byte of an item
+const CBOR_UNSIGNED_MAJOR_TYPE: u8 = 0;
+const CBOR_NEGATIVE_MAJOR_TYPE: u8 = 1;
+const CBOR_ARRAY_MAJOR_TYPE: u8 = 4;
+const CBOR_MAP_MAJOR_TYPE: u8 = 5;
+
+#[derive(Debug)]
+pub enum WireItem {
+    Event(TraceLowLevelEvent),
+    ValueRef { variable_id: VariableId, source_event: u64 },
+    StepDelta { line_delta: i64 },
+}
+
+impl WireItem {
+    /// Reads the next item; `reader` must not be at the end of the stream
+    pub fn read(reader: &mut impl BufRead) -> Result<WireItem, Box<dyn Error>> {
+        let initial_byte = *reader.fill_buf()?.first().ok_or("unexpected end of the event stream")?;
+        match initial_byte >> 5 {
+            CBOR_ARRAY_MAJOR_TYPE => {
+                let (variable_id, source_event) = cbor4ii::serde::from_reader::<(usize, u64), _>(reader)?;
+                Ok(WireItem::ValueRef {
+                    variable_id: VariableId(variable_id),
+                    source_event,
+                })
+            }
+            CBOR_UNSIGNED_MAJOR_TYPE | CBOR_NEGATIVE_MAJOR_TYPE => Ok(WireItem::StepDelta {
+                line_delta: cbor4ii::serde::from_reader(reader)?,
+            }),
+            _ => Ok(WireItem::Event(cbor4ii::serde::from_reader(reader)?)),
+        }
+    }
+}
+
+/// Expands items into events, keeping the state compact items are relative to
+#[derive(Debug, Default)]
+pub struct WireDecoder {
+    last_step: Option<StepRecord>,
+}
+
+impl WireDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Turns the item into the event it stands for; `previous` are all events before it
+    pub fn expand(&mut self, item: WireItem, previous: &[TraceLowLevelEvent]) -> Result<TraceLowLevelEvent, Box<dyn Error>> {
+        self.expand_with(item, |index| match usize::try_from(index).ok().and_then(|index| previous.get(index)) {
+            Some(TraceLowLevelEvent::Value(FullValueRecord { value, .. })) => Some(value.clone()),
+            _ => None,
+        })
+    }
+
+    /// Like `expand`, for readers which don't keep all events: `value_of` returns the value of the
+    /// event at the given index of the stream, if it is a `Value` event
+    pub fn expand_with(&mut self, item: WireItem, value_of: impl FnOnce(u64) -> Option<ValueRecord>) -> Result<TraceLowLevelEvent, Box<dyn Error>> {
+        let event = match item {
+            WireItem::Event(event) => event,
+            WireItem::StepDelta { line_delta } => {
+                let last_step = self.last_step.ok_or("step line delta without a preceding Step event")?;
+                let line = last_step.line.0.checked_add(line_delta).ok_or("step line delta out of range")?;
+                TraceLowLevelEvent::Step(StepRecord {
+                    path_id: last_step.path_id,
+                    line: Line(line),
+                })
+            }
+            WireItem::ValueRef { variable_id, source_event } => match value_of(source_event) {
+                Some(value) => TraceLowLevelEvent::Value(FullValueRecord { variable_id, value }),
+                None => return Err(format!("value back-reference to event {source_event}, which is not a preceding Value event").into()),
+            },
+        };
+        if let TraceLowLevelEvent::Step(step) = &event {
+            self.last_step = Some(*step);
+        }
+        Ok(event)
+    }
+}
+
+/// The values of the preceding `Value` events which back-references can refer to, for readers
+/// which don't keep all events: the values of at least `MIN_SHARED_VALUE_SIZE` bytes which
+/// weren't back-references themselves, up to `MAX_SHARED_VALUES_SIZE` bytes of the most recent
+/// ones. A `WireEncoder` remembers the same values, or fewer of them.
+#[derive(Debug, Default)]
+pub struct SharedValueWindow {
+    values: HashMap<u64, ValueRecord>,
+    // the keys of `values` with the encoded sizes of the values, oldest first
+    order: VecDeque<(u64, usize)>,
+    size: usize,
+}
+
+impl SharedValueWindow {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remembers the value of the event at `event_index`, if it's a `Value` event read from an
+    /// item of `item_size` bytes (and so not a back-reference) which can be referred to
+    pub fn insert(&mut self, event_index: u64, event: &TraceLowLevelEvent, item_size: usize) {
+        let TraceLowLevelEvent::Value(FullValueRecord { variable_id, value }) = event else {
+            return;
+        };
+        let mut header = vec![];
+        write_value_event(&mut header, *variable_id, &[]);
+        let value_size = item_size.saturating_sub(header.len());
+        if value_size < MIN_SHARED_VALUE_SIZE {
+            return;
+        }
+        self.values.insert(event_index, value.clone());
+        self.order.push_back((event_index, value_size));
+        self.size += value_size;
+        while self.size > MAX_SHARED_VALUES_SIZE {
+            let Some((oldest, oldest_size)) = self.order.pop_front() else {
+                break;
+            };
+            self.size -= oldest_size;
+            self.values.remove(&oldest);
+        }
+    }
+
+    /// The value of the `Value` event at `event_index`, if it's still kept
+    pub fn get(&self, event_index: u64) -> Option<ValueRecord> {
+        self.values.get(&event_index).cloned()
+    }
+}
+
+/// Encodes events into items, replacing repeated large values with back-references
+pub struct WireEncoder {
+    /// Whether repeated values are encoded as back-references; receivers which don't keep the
+    /// events can't expand them
+    pub share_values: bool,
+    /// Total encoded size of the values remembered for back-references, at most (and by default)
+    /// `MAX_SHARED_VALUES_SIZE`: readers don't keep more
+    pub max_shared_values_size: usize,
+
+    // encoded value -> index of the first event with it; keyed by the bytes themselves, so a
+    // back-reference is only written for exactly the same value
+    shared_values: HashMap<Arc<[u8]>, u64>,
+    // the keys of `shared_values`, oldest first
+    shared_values_order: VecDeque<Arc<[u8]>>,
+    shared_values_size: usize,
+    events_written: u64,
+    last_step: Option<StepRecord>,
+}
+
+impl Default for WireEncoder {
+    fn default() -> Self {
+        WireEncoder {
+            share_values: true,
+            max_shared_values_size: MAX_SHARED_VALUES_SIZE,
+            shared_values: HashMap::new(),
+            shared_values_order: VecDeque::new(),
+            shared_values_size: 0,
+            events_written: 0,
+            last_step: None,
+        }
+    }
+}
+
+impl WireEncoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends the encoding of the next event of the stream to `output`
+    pub fn encode(&mut self, event: &TraceLowLevelEvent, output: &mut Vec<u8>) {
+        let event_index = self.events_written;
+        self.events_written += 1;
+
+        if let TraceLowLevelEvent::Step(step) = event {
+            let last_step = self.last_step.replace(*step);
+            if let Some(last_step) = last_step
+                && last_step.path_id == step.path_id
+                && let Some(line_delta) = step.line.0.checked_sub(last_step.line.0)
+            {
+                cbor4ii::serde::to_writer(output, &line_delta).expect("CBOR encode failed");
+                return;
+            }
+        }
+
+        if let TraceLowLevelEvent::Value(FullValueRecord { variable_id, value }) = event {
+            let value_bytes = cbor4ii::serde::to_vec(Vec::new(), value).expect("CBOR encode failed");
+            if self.share_values && value_bytes.len() >= MIN_SHARED_VALUE_SIZE {
+                if let Some(source_event) = self.shared_values.get(&value_bytes[..]) {
+                    let item = (variable_id.0, *source_event);
+                    cbor4ii::serde::to_writer(output, &item).expect("CBOR encode failed");
+                    return;
+                }
+                write_value_event(output, *variable_id, &value_bytes);
+                self.share_value(value_bytes.into(), event_index);
+            } else {
+                write_value_event(output, *variable_id, &value_bytes);
+            }
+            return;
+        }
+
+        cbor4ii::serde::to_writer(output, event).expect("CBOR encode failed");
+    }
+
+    fn share_value(&mut self, value_bytes: Arc<[u8]>, event_index: u64) {
+        self.shared_values_size += value_bytes.len();
+        self.shared_values.insert(value_bytes.clone(), event_index);
+        self.shared_values_order.push_back(value_bytes);
+        while self.shared_values_size > self.max_shared_values_size.min(MAX_SHARED_VALUES_SIZE) {
+            let Some(oldest) = self.shared_values_order.pop_front() else {
+                break;
+            };
+            self.shared_values_size -= oldest.len();
+            self.shared_values.remove(&oldest);
+        }
+    }
+}
+
+/// Writes the same item as serde for `TraceLowLevelEvent::Value`, with the value already encoded
+fn write_value_event(output: &mut Vec<u8>, variable_id: VariableId, value_bytes: &[u8]) {
+    output.push(CBOR_MAP_MAJOR_TYPE << 5 | 1);
+    cbor4ii::serde::to_writer(&mut *output, &"Value").expect("CBOR encode failed");
+    output.push(CBOR_MAP_MAJOR_TYPE << 5 | 2);
+    cbor4ii::serde::to_writer(&mut *output, &"variable_id").expect("CBOR encode failed");
+    cbor4ii::serde::to_writer(&mut *output, &variable_id).expect("CBOR encode failed");
+    cbor4ii::serde::to_writer(&mut *output, &"value").expect("CBOR encode failed");
+    output.extend_from_slice(value_bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codetracer_trace_types::{PathId, TypeId, ValueRecord};
+
+    fn large_value(first: i64) -> ValueRecord {
+        ValueRecord::Sequence {
+            elements: (first..first + 100).map(|i| ValueRecord::Int { i, type_id: TypeId(1) }).collect(),
+            is_slice: false,
+            type_id: TypeId(2),
+        }
+    }
+
+    fn value_event(variable_id: usize, value: ValueRecord) -> TraceLowLevelEvent {
+        TraceLowLevelEvent::Value(FullValueRecord {
+            variable_id: VariableId(variable_id),
+            value,
+        })
+    }
+
+    #[test]
+    fn test_value_back_references() {
+        let events = vec![
+            value_event(0, large_value(0)),
+            TraceLowLevelEvent::DropLastStep,
+            value_event(1, large_value(0)),
+            value_event(0, large_value(1)),
+            value_event(0, large_value(0)),
+        ];
+
+        let mut encoder = WireEncoder::new();
+        let mut stream = vec![];
+        let mut first_size = 0;
+        for (i, event) in events.iter().enumerate() {
+            encoder.encode(event, &mut stream);
+            if i == 0 {
+                first_size = stream.len();
+            }
+        }
+        // two of the three large values are back-references
+        assert!(stream.len() < first_size * 3);
+
+        assert_eq!(to_json(&decode(&stream)), to_json(&events));
+    }
+
+    #[test]
+    fn test_value_event_encoding() {
+        for value in [ValueRecord::Int { i: 5, type_id: TypeId(1) }, large_value(0)] {
+            let event = value_event(7, value);
+            let mut stream = vec![];
+            WireEncoder::new().encode(&event, &mut stream);
+            assert_eq!(stream, cbor4ii::serde::to_vec(Vec::new(), &event).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_shared_values_limit() {
+        let mut encoder = WireEncoder::new();
+        let mut value_size = vec![];
+        cbor4ii::serde::to_writer(&mut value_size, &large_value(0)).unwrap();
+        // room for a single large value
+        encoder.max_shared_values_size = value_size.len();
+        let mut stream = vec![];
+        let events = vec![
+            value_event(0, large_value(0)),
+            value_event(0, large_value(1)),
+            value_event(0, large_value(0)),
+            value_event(0, large_value(0)),
+        ];
+        let mut item_sizes = vec![];
+        for event in &events {
+            let start = stream.len();
+            encoder.encode(event, &mut stream);
+            item_sizes.push(stream.len() - start);
+        }
+        // the first value is forgotten when the second is shared, so it is written again, once
+        assert_eq!(item_sizes[2], item_sizes[0]);
+        assert!(item_sizes[3] < 8);
+        assert_eq!(encoder.shared_values.len(), 1);
+
+        assert_eq!(to_json(&decode(&stream)), to_json(&events));
+    }
+
+    #[test]
+    fn test_shared_value_window() {
+        let events = vec![
+            value_event(0, large_value(0)),
+            value_event(1, ValueRecord::Int { i: 5, type_id: TypeId(1) }),
+            value_event(1, large_value(0)),
+            value_event(0, large_value(1)),
+        ];
+        let mut encoder = WireEncoder::new();
+        let mut stream = vec![];
+        for event in &events {
+            encoder.encode(event, &mut stream);
+        }
+
+        let mut reader = &stream[..];
+        let mut decoder = WireDecoder::new();
+        let mut window = SharedValueWindow::new();
+        for (event_index, expected) in (0..).zip(&events) {
+            let size = reader.len();
+            let item = WireItem::read(&mut reader).unwrap();
+            let event = decoder.expand_with(item, |index| window.get(index)).unwrap();
+            window.insert(event_index, &event, size - reader.len());
+            assert_eq!(to_json(&[event]), to_json(std::slice::from_ref(expected)));
+        }
+        // only the large values which weren't back-references are kept
+        assert_eq!(window.values.len(), 2);
+        assert!(window.get(0).is_some() && window.get(3).is_some());
+    }
+
+    #[test]
+    fn test_step_line_deltas() {
+        let step = |path_id: usize, line: i64| {
+            TraceLowLevelEvent::Step(StepRecord {
+                path_id: PathId(path_id),
+                line: Line(line),
+            })
+        };
+        let events = vec![
+            step(0, 10),
+            step(0, 11),
+            TraceLowLevelEvent::DropLastStep,
+            step(0, 11),
+            step(0, 5),
+            step(1, 6),
+            step(1, 7),
+        ];
+
+        let mut encoder = WireEncoder::new();
+        let mut stream = vec![];
+        for event in &events {
+            encoder.encode(event, &mut stream);
+        }
+        let mut full_step = vec![];
+        cbor4ii::serde::to_writer(&mut full_step, &step(0, 10)).unwrap();
+        // two full steps, four one-byte deltas and DropLastStep
+        let mut drop_last_step = vec![];
+        cbor4ii::serde::to_writer(&mut drop_last_step, &TraceLowLevelEvent::DropLastStep).unwrap();
+        assert_eq!(stream.len(), full_step.len() * 2 + 4 + drop_last_step.len());
+
+        assert_eq!(to_json(&decode(&stream)), to_json(&events));
+    }
+
+    #[test]
+    fn test_int128_values() {
+        let events = vec![
+            value_event(
+                0,
+                ValueRecord::Int128 {
+                    i: i128::MIN,
+                    type_id: TypeId(1),
+                },
+            ),
+            value_event(
+                1,
+                ValueRecord::UInt128 {
+                    u: u128::MAX,
+                    type_id: TypeId(2),
+                },
+            ),
+        ];
+
+        let mut encoder = WireEncoder::new();
+        let mut stream = vec![];
+        for event in &events {
+            encoder.encode(event, &mut stream);
+        }
+        // a 16 byte CBOR byte string, not the decimal digits
+        let mut min = vec![0x50];
+        min.extend(i128::MIN.to_be_bytes());
+        assert!(stream.windows(min.len()).any(|window| window == min));
+
+        assert_eq!(to_json(&decode(&stream)), to_json(&events));
+        assert!(to_json(&events).contains(&format!("\"{}\"", i128::MIN)));
+    }
+
+    fn decode(stream: &[u8]) -> Vec<TraceLowLevelEvent> {
+        let mut reader = stream;
+        let mut decoder = WireDecoder::new();
+        let mut decoded = vec![];
+        while !reader.is_empty() {
+            let event = decoder.expand(WireItem::read(&mut reader).unwrap(), &decoded).unwrap();
+            decoded.push(event);
+        }
+        decoded
+    }
+
+    fn to_json(events: &[TraceLowLevelEvent]) -> String {
+        serde_json::to_string(events).unwrap()
+    }
+}
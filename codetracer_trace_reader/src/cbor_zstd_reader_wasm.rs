@@ -2,9 +2,12 @@ use std::io::{self, BufRead, BufReader, Read, Seek, Write};
 
 use fscommon::StreamSlice;
 
-use ruzstd::decoding::StreamingDecoder;
+use ruzstd::decoding::{Dictionary, FrameDecoder, StreamingDecoder};
 
-use codetracer_trace_format_cbor_zstd::HEADERV1;
+use codetracer_trace_format_cbor_zstd::{
+    headerv1_revision,
+    wire::{WireDecoder, WireItem},
+};
 use codetracer_trace_types::TraceLowLevelEvent;
 
 fn is_at_eof<R: BufRead>(reader: &mut R) -> io::Result<bool> {
@@ -12,27 +15,44 @@ fn is_at_eof<R: BufRead>(reader: &mut R) -> io::Result<bool> {
     Ok(buffer.is_empty())
 }
 
-pub fn read_trace(input: &mut (impl Read + Write + Seek)) -> Result<Vec<TraceLowLevelEvent>, Box<dyn std::error::Error>> {
+pub fn read_trace(input: &mut (impl Read + Write + Seek), dictionary: Option<&[u8]>) -> Result<Vec<TraceLowLevelEvent>, Box<dyn std::error::Error>> {
     let end_pos = input.seek(io::SeekFrom::End(0))?;
     input.seek(io::SeekFrom::Start(0))?;
 
     let mut header_buf = [0; 8];
     let mut buf_reader = BufReader::new(&mut *input);
     buf_reader.read_exact(&mut header_buf)?;
-    if header_buf != HEADERV1 {
-        panic!("Invalid file header (wrong file format or incompatible version)");
+    if headerv1_revision(&header_buf).is_none() {
+        return Err("Invalid file header (wrong file format or incompatible version)".into());
     }
 
     input.seek(io::SeekFrom::Start(0))?;
     let input2 = StreamSlice::new(&mut *input, 8, end_pos)?;
+    decode_events(input2, dictionary)
+}
 
-    let decoder = StreamingDecoder::new(input2)?;
+/// Like `read_trace`, for a trace which is already in memory
+pub fn read_trace_from_slice(bytes: &[u8], dictionary: Option<&[u8]>) -> Result<Vec<TraceLowLevelEvent>, Box<dyn std::error::Error>> {
+    if bytes.get(..8).and_then(headerv1_revision).is_none() {
+        return Err("Invalid file header (wrong file format or incompatible version)".into());
+    }
+    decode_events(&bytes[8..], dictionary)
+}
+
+/// Decodes the zstd frames following the header
+fn decode_events(input: impl Read, dictionary: Option<&[u8]>) -> Result<Vec<TraceLowLevelEvent>, Box<dyn std::error::Error>> {
+    let mut frame_decoder = FrameDecoder::new();
+    if let Some(dictionary) = dictionary {
+        frame_decoder.add_dict(Dictionary::decode_dict(dictionary)?)?;
+    }
+    let decoder = StreamingDecoder::new_with_decoder(input, frame_decoder)?;
     let mut buf_reader = BufReader::new(decoder);
 
     let mut result: Vec<TraceLowLevelEvent> = vec![];
+    let mut wire_decoder = WireDecoder::new();
 
     while !is_at_eof(&mut buf_reader)? {
-        let obj = cbor4ii::serde::from_reader::<TraceLowLevelEvent, _>(&mut buf_reader)?;
+        let obj = wire_decoder.expand(WireItem::read(&mut buf_reader)?, &result)?;
         result.push(obj);
     }
 
@@ -1,3 +1,5 @@
+mod migration;
+mod stream_reader;
 mod trace_readers;
 
 #[cfg(target_arch = "wasm32")]
@@ -7,6 +9,20 @@ mod cbor_zstd_reader;
 #[cfg(not(target_arch = "wasm32"))]
 mod cbor_zstd_reader;
 
+#[cfg(not(target_arch = "wasm32"))]
+mod parallel_reader;
+
+#[cfg(all(feature = "mmap", not(target_arch = "wasm32")))]
+mod mapped_trace;
+
+#[cfg(all(feature = "mmap", not(target_arch = "wasm32")))]
+pub use mapped_trace::{MappedBinaryTraceReader, MappedTrace};
+pub use migration::{upgrade_event, upgrade_events};
+#[cfg(not(target_arch = "wasm32"))]
+pub use parallel_reader::{ParallelBinaryTraceReader, for_each_event_parallel, read_trace_parallel};
+pub use stream_reader::TraceStreamReader;
+pub use trace_readers::detect_trace_format;
+
 #[derive(Debug, Clone, Copy)]
 pub enum TraceEventsFileFormat {
     Json,
@@ -15,8 +31,31 @@ pub enum TraceEventsFileFormat {
 }
 
 pub fn create_trace_reader(format: TraceEventsFileFormat) -> Box<dyn trace_readers::TraceReader> {
+    create_trace_reader_with_dictionary(format, None)
+}
+
+/// Like `create_trace_reader`, for binary traces written with a pretrained zstd dictionary
+pub fn create_trace_reader_with_dictionary(format: TraceEventsFileFormat, dictionary: Option<Vec<u8>>) -> Box<dyn trace_readers::TraceReader> {
+    match format {
+        TraceEventsFileFormat::Json => Box::new(trace_readers::JsonTraceReader {}),
+        TraceEventsFileFormat::BinaryV0 | TraceEventsFileFormat::Binary => Box::new(trace_readers::BinaryTraceReader { dictionary }),
+    }
+}
+
+/// Like `create_trace_reader_with_dictionary`, but binary traces are read through a memory mapping
+#[cfg(all(feature = "mmap", not(target_arch = "wasm32")))]
+pub fn create_mapped_trace_reader(format: TraceEventsFileFormat, dictionary: Option<Vec<u8>>) -> Box<dyn trace_readers::TraceReader> {
+    match format {
+        TraceEventsFileFormat::Json => Box::new(trace_readers::JsonTraceReader {}),
+        TraceEventsFileFormat::BinaryV0 | TraceEventsFileFormat::Binary => Box::new(MappedBinaryTraceReader { dictionary }),
+    }
+}
+
+/// Like `create_trace_reader_with_dictionary`, but `Binary` traces are decoded on all available cores
+#[cfg(not(target_arch = "wasm32"))]
+pub fn create_parallel_trace_reader(format: TraceEventsFileFormat, dictionary: Option<Vec<u8>>) -> Box<dyn trace_readers::TraceReader> {
     match format {
         TraceEventsFileFormat::Json => Box::new(trace_readers::JsonTraceReader {}),
-        TraceEventsFileFormat::BinaryV0 | TraceEventsFileFormat::Binary => Box::new(trace_readers::BinaryTraceReader {}),
+        TraceEventsFileFormat::BinaryV0 | TraceEventsFileFormat::Binary => Box::new(ParallelBinaryTraceReader { dictionary }),
     }
 }
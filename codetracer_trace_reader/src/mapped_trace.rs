@@ -0,0 +1,67 @@
+use std::{error::Error, fs::File, path::Path};
+
+use codetracer_trace_format_capnp::view::TraceView;
+use codetracer_trace_types::TraceLowLevelEvent;
+use memmap2::Mmap;
+
+use crate::{
+    TraceEventsFileFormat,
+    trace_readers::{TraceReader, detect_bin_slice_version, read_bin_slice},
+};
+
+/// A binary trace mapped into memory.
+///
+/// The pages of the file are shared with every other process mapping the same trace, and
+/// reading any part of it needs no buffering.
+pub struct MappedTrace {
+    mmap: Mmap,
+    format: TraceEventsFileFormat,
+}
+
+impl MappedTrace {
+    pub fn open(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let file = File::open(path)?;
+        // SAFETY: the file must not be changed while it's mapped. Writers never touch a trace
+        // after finishing it, and the ones being written are read with `create_trace_reader`.
+        let mmap = unsafe { Mmap::map(&file)? };
+        let format = detect_bin_slice_version(&mmap)?;
+        Ok(MappedTrace { mmap, format })
+    }
+
+    /// `BinaryV0` or `Binary`, as given by the header
+    pub fn format(&self) -> TraceEventsFileFormat {
+        self.format
+    }
+
+    /// The whole file, header included
+    pub fn bytes(&self) -> &[u8] {
+        &self.mmap
+    }
+
+    /// Reads the events in place, without converting them; only segmented `BinaryV0` traces
+    /// support this
+    pub fn view(&self) -> Result<TraceView<'_>, Box<dyn Error>> {
+        // the mapping starts at a page boundary, so it's aligned as Cap'n Proto requires
+        Ok(TraceView::new(self.bytes())?)
+    }
+
+    pub fn load_trace_events(&self, dictionary: Option<&[u8]>) -> Result<Vec<TraceLowLevelEvent>, Box<dyn Error>> {
+        read_bin_slice(self.bytes(), dictionary)
+    }
+}
+
+/// Like `BinaryTraceReader`, but reads the trace through a memory mapping
+pub struct MappedBinaryTraceReader {
+    /// zstd dictionary the trace was compressed with, if any
+    pub dictionary: Option<Vec<u8>>,
+}
+
+impl TraceReader for MappedBinaryTraceReader {
+    fn load_trace_events(&mut self, path: &Path) -> Result<Vec<TraceLowLevelEvent>, Box<dyn Error>> {
+        MappedTrace::open(path)?.load_trace_events(self.dictionary.as_deref())
+    }
+
+    fn load_trace_events_from_slice(&mut self, bytes: &[u8]) -> Result<Vec<TraceLowLevelEvent>, Box<dyn Error>> {
+        read_bin_slice(bytes, self.dictionary.as_deref())
+    }
+}
@@ -0,0 +1,46 @@
+//! Upgrading of events recorded by older tracers to their current equivalents.
+
+use codetracer_trace_types::TraceLowLevelEvent;
+
+/// Rewrites a deprecated event into the event current writers produce for it.
+///
+/// * `Variable(name)` becomes `VariableName(name)`
+/// * `DropVariable(id)` becomes `DropVariables(vec![id])`
+///
+/// All other events are returned unchanged.
+pub fn upgrade_event(event: TraceLowLevelEvent) -> TraceLowLevelEvent {
+    match event {
+        TraceLowLevelEvent::Variable(name) => TraceLowLevelEvent::VariableName(name),
+        TraceLowLevelEvent::DropVariable(variable_id) => TraceLowLevelEvent::DropVariables(vec![variable_id]),
+        event => event,
+    }
+}
+
+/// Applies [`upgrade_event`] to every event of a trace.
+///
+/// Both rewrites keep the number and order of events, so ids derived from positions
+/// in the stream (e.g. `VariableId`s) stay valid.
+pub fn upgrade_events(events: Vec<TraceLowLevelEvent>) -> Vec<TraceLowLevelEvent> {
+    events.into_iter().map(upgrade_event).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codetracer_trace_types::VariableId;
+
+    #[test]
+    fn test_upgrade_deprecated_events() {
+        let events = vec![
+            TraceLowLevelEvent::Variable("a".to_string()),
+            TraceLowLevelEvent::DropVariable(VariableId(0)),
+            TraceLowLevelEvent::DropLastStep,
+        ];
+
+        let upgraded = upgrade_events(events);
+
+        assert!(matches!(&upgraded[0], TraceLowLevelEvent::VariableName(name) if name == "a"));
+        assert!(matches!(&upgraded[1], TraceLowLevelEvent::DropVariables(ids) if ids == &[VariableId(0)]));
+        assert!(matches!(upgraded[2], TraceLowLevelEvent::DropLastStep));
+    }
+}
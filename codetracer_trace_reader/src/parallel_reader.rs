@@ -0,0 +1,259 @@
+//! Decoding of `Binary` traces on several threads.
+//!
+//! Since revision 3 every zstd frame of a trace ends at an item boundary, so the frames are
+//! decompressed and parsed into items independently. Expanding the items into events depends on
+//! the events before them, which is cheap and happens in order on the calling thread.
+
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fs,
+    io::Cursor,
+    path::Path,
+    sync::{
+        Condvar, Mutex,
+        atomic::{AtomicU32, Ordering},
+        mpsc::{self, SyncSender},
+    },
+    thread,
+};
+
+use codetracer_trace_format_cbor_zstd::{
+    ALIGNED_FRAMES_REVISION, headerv1_revision,
+    wire::{SharedValueWindow, WireDecoder, WireItem},
+};
+use codetracer_trace_types::TraceLowLevelEvent;
+use zeekstd::SeekTable;
+use zstd_safe::DCtx;
+
+use crate::{
+    TraceEventsFileFormat,
+    cbor_zstd_reader::{create_dctx, read_trace_from_slice},
+    trace_readers::{TraceReader, detect_bin_slice_version, read_bin_slice},
+};
+
+// the items of a frame, with their encoded sizes
+type FrameItems = Result<Vec<(WireItem, usize)>, String>;
+
+fn thread_count(frame_count: u32) -> usize {
+    let available = thread::available_parallelism().map_or(1, usize::from);
+    available.min(frame_count as usize).max(1)
+}
+
+fn decode_frame(dctx: &mut DCtx, stream: &[u8], seek_table: &SeekTable, index: u32) -> Result<Vec<(WireItem, usize)>, Box<dyn Error>> {
+    let start = seek_table.frame_start_comp(index)? as usize;
+    let end = seek_table.frame_end_comp(index)? as usize;
+    let compressed = stream.get(start..end).ok_or("zstd frame outside of the trace")?;
+
+    let mut frame = Vec::with_capacity(seek_table.frame_size_decomp(index)? as usize);
+    dctx.decompress(&mut frame, compressed)
+        .map_err(|code| zstd_safe::get_error_name(code).to_string())?;
+
+    let mut items = vec![];
+    let mut rest = &frame[..];
+    while !rest.is_empty() {
+        let size = rest.len();
+        let item = WireItem::read(&mut rest)?;
+        items.push((item, size - rest.len()));
+    }
+    Ok(items)
+}
+
+/// How far the frames handed out to the workers may run ahead of the one expanded next
+struct FrameWindow {
+    // the index of the frame expanded next, or `u32::MAX` once the workers should stop
+    next_expanded: Mutex<u32>,
+    expanded: Condvar,
+    size: u32,
+}
+
+impl FrameWindow {
+    /// Waits until frame `index` is close enough to the expanded ones; false if the workers should stop
+    fn wait_for(&self, index: u32) -> bool {
+        let mut next_expanded = self.next_expanded.lock().unwrap();
+        while index >= next_expanded.saturating_add(self.size) {
+            next_expanded = self.expanded.wait(next_expanded).unwrap();
+        }
+        *next_expanded != u32::MAX
+    }
+
+    fn set_next_expanded(&self, index: u32) {
+        *self.next_expanded.lock().unwrap() = index;
+        self.expanded.notify_all();
+    }
+}
+
+/// Takes frames from `next_frame` until there are none left or the receiver is gone
+fn decode_frames(
+    stream: &[u8],
+    seek_table: &SeekTable,
+    dictionary: Option<&[u8]>,
+    next_frame: &AtomicU32,
+    window: &FrameWindow,
+    sender: SyncSender<(u32, FrameItems)>,
+) {
+    let mut dctx = create_dctx(dictionary);
+    loop {
+        let index = next_frame.fetch_add(1, Ordering::Relaxed);
+        if index >= seek_table.num_frames() || !window.wait_for(index) {
+            return;
+        }
+        let items = match &mut dctx {
+            Ok(dctx) => decode_frame(dctx, stream, seek_table, index).map_err(|err| err.to_string()),
+            Err(err) => Err(err.clone()),
+        };
+        if sender.send((index, items)).is_err() {
+            return;
+        }
+    }
+}
+
+/// Decodes the frames of `stream` on all available cores and passes the items of every frame,
+/// with their encoded sizes, in order, to `on_frame` on the calling thread
+fn for_each_frame_parallel(
+    stream: &[u8],
+    seek_table: &SeekTable,
+    dictionary: Option<&[u8]>,
+    mut on_frame: impl FnMut(Vec<(WireItem, usize)>) -> Result<(), Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    let threads = thread_count(seek_table.num_frames());
+    let next_frame = AtomicU32::new(0);
+    // bounds the decoded frames waiting for the calling thread, together with the channel
+    let window = FrameWindow {
+        next_expanded: Mutex::new(0),
+        expanded: Condvar::new(),
+        size: threads as u32 * 2,
+    };
+    let (sender, receiver) = mpsc::sync_channel(threads * 2);
+
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            let sender = sender.clone();
+            let (next_frame, window) = (&next_frame, &window);
+            scope.spawn(move || decode_frames(stream, seek_table, dictionary, next_frame, window, sender));
+        }
+        drop(sender);
+
+        let result = (|| {
+            // frames decoded ahead of the one which is expanded next
+            let mut pending = BTreeMap::new();
+            let mut next_index = 0;
+            for (index, items) in &receiver {
+                pending.insert(index, items);
+                while let Some(items) = pending.remove(&next_index) {
+                    on_frame(items?)?;
+                    next_index += 1;
+                    window.set_next_expanded(next_index);
+                }
+            }
+            Ok(())
+        })();
+        // stops the workers, which may be waiting for the window or the receiver
+        window.set_next_expanded(u32::MAX);
+        drop(receiver);
+        result
+    })
+}
+
+/// The seek table of a trace whose frames can be decoded independently
+fn aligned_seek_table(bytes: &[u8]) -> Result<Option<SeekTable>, Box<dyn Error>> {
+    let revision = bytes
+        .get(..8)
+        .and_then(headerv1_revision)
+        .ok_or("Invalid file header (wrong file format or incompatible version)")?;
+    match SeekTable::from_seekable(&mut Cursor::new(&bytes[8..])) {
+        Ok(seek_table) if revision >= ALIGNED_FRAMES_REVISION => Ok(Some(seek_table)),
+        _ => Ok(None),
+    }
+}
+
+/// Like `cbor_zstd_reader::read_trace_from_slice`, decoding the frames of `bytes` on all available
+/// cores. Traces written before revision 3, or without a seek table, are decoded sequentially.
+pub fn read_trace_parallel(bytes: &[u8], dictionary: Option<&[u8]>) -> Result<Vec<TraceLowLevelEvent>, Box<dyn Error>> {
+    let Some(seek_table) = aligned_seek_table(bytes)? else {
+        return read_trace_from_slice(bytes, dictionary);
+    };
+
+    let mut result: Vec<TraceLowLevelEvent> = vec![];
+    let mut wire_decoder = WireDecoder::new();
+    for_each_frame_parallel(&bytes[8..], &seek_table, dictionary, |items| {
+        for (item, _) in items {
+            let event = wire_decoder.expand(item, &result)?;
+            result.push(event);
+        }
+        Ok(())
+    })?;
+    Ok(result)
+}
+
+/// Calls `f` for every event of the trace, from several threads and in no particular order, e.g.
+/// for counting or coverage.
+///
+/// The events of every frame are passed to `f` as soon as the frame is expanded, so the trace
+/// isn't kept in memory, except for the values value back-references can refer to: at most
+/// `MAX_SHARED_VALUES_SIZE` bytes of them, see `SharedValueWindow`. Traces which can't be decoded
+/// in parallel (see `read_trace_parallel`) are read as a whole first.
+pub fn for_each_event_parallel(bytes: &[u8], dictionary: Option<&[u8]>, f: impl Fn(&TraceLowLevelEvent) + Sync) -> Result<(), Box<dyn Error>> {
+    let threads = thread_count(u32::MAX);
+    let Some(seek_table) = aligned_seek_table(bytes)? else {
+        let events = read_trace_from_slice(bytes, dictionary)?;
+        let chunk_size = events.len().div_ceil(threads).max(1);
+        thread::scope(|scope| {
+            for chunk in events.chunks(chunk_size) {
+                let f = &f;
+                scope.spawn(move || chunk.iter().for_each(f));
+            }
+        });
+        return Ok(());
+    };
+
+    let (sender, receiver) = mpsc::sync_channel::<Vec<TraceLowLevelEvent>>(threads * 2);
+    let receiver = Mutex::new(receiver);
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            let (f, receiver) = (&f, &receiver);
+            scope.spawn(move || {
+                // the lock is only held while waiting for the next frame
+                while let Ok(events) = receiver.lock().unwrap().recv() {
+                    events.iter().for_each(f);
+                }
+            });
+        }
+
+        let mut wire_decoder = WireDecoder::new();
+        let mut shared_values = SharedValueWindow::new();
+        let mut event_index = 0;
+        let result = for_each_frame_parallel(&bytes[8..], &seek_table, dictionary, |items| {
+            let mut events = Vec::with_capacity(items.len());
+            for (item, size) in items {
+                let event = wire_decoder.expand_with(item, |index| shared_values.get(index))?;
+                shared_values.insert(event_index, &event, size);
+                event_index += 1;
+                events.push(event);
+            }
+            // the workers only stop when the sender is dropped
+            sender.send(events).map_err(|err| err.to_string().into())
+        });
+        drop(sender);
+        result
+    })
+}
+
+/// Like `BinaryTraceReader`, but decodes `Binary` traces with `read_trace_parallel`
+pub struct ParallelBinaryTraceReader {
+    /// zstd dictionary the trace was compressed with, if any
+    pub dictionary: Option<Vec<u8>>,
+}
+
+impl TraceReader for ParallelBinaryTraceReader {
+    fn load_trace_events(&mut self, path: &Path) -> Result<Vec<TraceLowLevelEvent>, Box<dyn Error>> {
+        self.load_trace_events_from_slice(&fs::read(path)?)
+    }
+
+    fn load_trace_events_from_slice(&mut self, bytes: &[u8]) -> Result<Vec<TraceLowLevelEvent>, Box<dyn Error>> {
+        match detect_bin_slice_version(bytes)? {
+            TraceEventsFileFormat::Binary => read_trace_parallel(bytes, self.dictionary.as_deref()),
+            _ => read_bin_slice(bytes, self.dictionary.as_deref()),
+        }
+    }
+}
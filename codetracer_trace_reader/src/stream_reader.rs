@@ -0,0 +1,69 @@
+use std::{error::Error, io::Read};
+
+use codetracer_trace_format_cbor_zstd::{
+    live::{self, MessageKind},
+    wire::{WireDecoder, WireItem},
+};
+use codetracer_trace_types::{TraceLowLevelEvent, TraceMetadata};
+
+/// Receives a trace streamed by a `SocketTraceWriter`, one batch of events at a time, using the
+/// protocol of `codetracer_trace_format_cbor_zstd::live`
+pub struct TraceStreamReader<R: Read> {
+    input: R,
+    metadata: TraceMetadata,
+    wire_decoder: WireDecoder,
+    finished: bool,
+}
+
+impl<R: Read> TraceStreamReader<R> {
+    /// Reads the handshake: the stream header and the initial metadata
+    pub fn new(mut input: R) -> Result<Self, Box<dyn Error>> {
+        let mut header = [0; 8];
+        input.read_exact(&mut header)?;
+        live::stream_header_version(&header).ok_or("Invalid trace stream header (not a trace stream or incompatible version)")?;
+
+        let metadata = match live::read_message(&mut input)? {
+            Some((MessageKind::Metadata, payload)) => live::read_metadata_payload(&payload)?,
+            _ => return Err("trace stream doesn't start with the metadata".into()),
+        };
+        Ok(TraceStreamReader {
+            input,
+            metadata,
+            wire_decoder: WireDecoder::new(),
+            finished: false,
+        })
+    }
+
+    /// The latest metadata sent by the writer; it's final once the stream is finished
+    pub fn metadata(&self) -> &TraceMetadata {
+        &self.metadata
+    }
+
+    /// Whether the writer finished the trace
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Waits for the next batch of events. Returns `None` once the trace is finished, and an error
+    /// if the stream ends before that, e.g. because the traced program crashed.
+    pub fn next_batch(&mut self) -> Result<Option<Vec<TraceLowLevelEvent>>, Box<dyn Error>> {
+        while !self.finished {
+            match live::read_message(&mut self.input)? {
+                Some((MessageKind::Events, payload)) => {
+                    let mut events = vec![];
+                    let mut rest = &payload[..];
+                    while !rest.is_empty() {
+                        let item = WireItem::read(&mut rest)?;
+                        // there are no back-references in a stream, so no previous events are needed
+                        events.push(self.wire_decoder.expand(item, &[])?);
+                    }
+                    return Ok(Some(events));
+                }
+                Some((MessageKind::Metadata, payload)) => self.metadata = live::read_metadata_payload(&payload)?,
+                Some((MessageKind::End, _)) => self.finished = true,
+                None => return Err("trace stream ended before the end of the trace".into()),
+            }
+        }
+        Ok(None)
+    }
+}
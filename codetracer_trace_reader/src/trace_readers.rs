@@ -6,12 +6,30 @@ use std::{
 };
 
 use crate::TraceEventsFileFormat;
-use codetracer_trace_format_cbor_zstd::HEADERV1;
+use codetracer_trace_format_capnp::capnptrace::{HEADER, SEGMENTED_HEADER};
+use codetracer_trace_format_cbor_zstd::headerv1_revision;
 use codetracer_trace_types::TraceLowLevelEvent;
-use codetracer_trace_format_capnp::capnptrace::HEADER;
 
 pub trait TraceReader {
     fn load_trace_events(&mut self, path: &Path) -> Result<Vec<TraceLowLevelEvent>, Box<dyn Error>>;
+
+    /// Like `load_trace_events`, for a trace which is already in memory. Readers which only
+    /// implement `load_trace_events` don't support it
+    fn load_trace_events_from_slice(&mut self, _bytes: &[u8]) -> Result<Vec<TraceLowLevelEvent>, Box<dyn Error>> {
+        Err("this trace reader can only load traces from files".into())
+    }
+
+    /// Like `load_trace_events`, reading the trace from `input` (e.g. stdin or a socket) until it ends
+    fn load_trace_events_from(&mut self, input: &mut dyn Read) -> Result<Vec<TraceLowLevelEvent>, Box<dyn Error>> {
+        let mut bytes = vec![];
+        input.read_to_end(&mut bytes)?;
+        self.load_trace_events_from_slice(&bytes)
+    }
+
+    /// Like `load_trace_events`, but with deprecated events rewritten to their current equivalents
+    fn load_upgraded_trace_events(&mut self, path: &Path) -> Result<Vec<TraceLowLevelEvent>, Box<dyn Error>> {
+        Ok(crate::migration::upgrade_events(self.load_trace_events(path)?))
+    }
 }
 
 pub struct JsonTraceReader {}
@@ -21,41 +39,99 @@ impl TraceReader for JsonTraceReader {
         let json = std::fs::read_to_string(path)?;
         Ok(serde_json::from_str(&json)?)
     }
+
+    fn load_trace_events_from_slice(&mut self, bytes: &[u8]) -> Result<Vec<TraceLowLevelEvent>, Box<dyn Error>> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+
+    fn load_trace_events_from(&mut self, input: &mut dyn Read) -> Result<Vec<TraceLowLevelEvent>, Box<dyn Error>> {
+        Ok(serde_json::from_reader(BufReader::new(input))?)
+    }
+}
+
+pub struct BinaryTraceReader {
+    /// zstd dictionary the trace was compressed with, if any
+    pub dictionary: Option<Vec<u8>>,
 }
 
-pub struct BinaryTraceReader {}
+/// Length of the magic prefix shared by all binary trace headers
+const MAGIC_LEN: usize = 5;
 
-fn detect_bin_file_version(input: &mut File) -> Result<Option<TraceEventsFileFormat>, Box<dyn Error>> {
+/// The byte after the magic prefix holds the binary format version
+const VERSION_OFFSET: usize = MAGIC_LEN;
+
+/// Guesses the format of a trace from its first bytes, e.g. for a trace read from stdin: binary
+/// traces of every version start with the same magic bytes and JSON traces are arrays
+pub fn detect_trace_format(bytes: &[u8]) -> Option<TraceEventsFileFormat> {
+    if bytes.starts_with(&HEADER[..MAGIC_LEN]) {
+        Some(TraceEventsFileFormat::Binary)
+    } else if bytes.trim_ascii_start().starts_with(b"[") {
+        Some(TraceEventsFileFormat::Json)
+    } else {
+        None
+    }
+}
+
+fn detect_bin_file_version(input: &mut File) -> Result<TraceEventsFileFormat, Box<dyn Error>> {
     input.seek(SeekFrom::Start(0))?;
     let mut header_buf = [0; 8];
     input.read_exact(&mut header_buf)?;
     input.seek(SeekFrom::Start(0))?;
+    detect_bin_header_version(header_buf)
+}
+
+pub(crate) fn detect_bin_slice_version(bytes: &[u8]) -> Result<TraceEventsFileFormat, Box<dyn Error>> {
+    let Some(header_buf) = bytes.first_chunk::<8>() else {
+        return Err("Invalid file header: not a CodeTracer binary trace".into());
+    };
+    detect_bin_header_version(*header_buf)
+}
+
+pub(crate) fn detect_bin_header_version(header_buf: [u8; 8]) -> Result<TraceEventsFileFormat, Box<dyn Error>> {
+    if header_buf[..MAGIC_LEN] != HEADER[..MAGIC_LEN] {
+        return Err("Invalid file header: not a CodeTracer binary trace".into());
+    }
 
-    if header_buf == HEADER {
-        Ok(Some(TraceEventsFileFormat::BinaryV0))
-    } else if header_buf == HEADERV1 {
-        Ok(Some(TraceEventsFileFormat::Binary))
+    if header_buf == HEADER || header_buf == SEGMENTED_HEADER {
+        Ok(TraceEventsFileFormat::BinaryV0)
+    } else if headerv1_revision(&header_buf).is_some() {
+        Ok(TraceEventsFileFormat::Binary)
     } else {
-        Ok(None)
+        Err(format!(
+            "Unsupported binary trace format version {} (header {:02X?})",
+            header_buf[VERSION_OFFSET], header_buf
+        )
+        .into())
     }
 }
 
 impl TraceReader for BinaryTraceReader {
     fn load_trace_events(&mut self, path: &Path) -> Result<Vec<TraceLowLevelEvent>, Box<dyn Error>> {
         let mut file = fs::File::open(path)?;
-        let ver = detect_bin_file_version(&mut file)?;
-        match ver {
-            Some(TraceEventsFileFormat::BinaryV0) => {
+        match detect_bin_file_version(&mut file)? {
+            TraceEventsFileFormat::BinaryV0 => {
                 let mut buf_reader = BufReader::new(file);
                 Ok(codetracer_trace_format_capnp::capnptrace::read_trace(&mut buf_reader)?)
             }
-            Some(TraceEventsFileFormat::Binary) => Ok(crate::cbor_zstd_reader::read_trace(&mut file)?),
-            Some(TraceEventsFileFormat::Json) => {
+            TraceEventsFileFormat::Binary => Ok(crate::cbor_zstd_reader::read_trace(&mut file, self.dictionary.as_deref())?),
+            TraceEventsFileFormat::Json => {
                 unreachable!()
             }
-            None => {
-                panic!("Invalid file header (wrong file format or incompatible version)");
-            }
+        }
+    }
+
+    fn load_trace_events_from_slice(&mut self, bytes: &[u8]) -> Result<Vec<TraceLowLevelEvent>, Box<dyn Error>> {
+        read_bin_slice(bytes, self.dictionary.as_deref())
+    }
+}
+
+/// Reads a binary trace of any version which is already in memory
+pub(crate) fn read_bin_slice(bytes: &[u8], dictionary: Option<&[u8]>) -> Result<Vec<TraceLowLevelEvent>, Box<dyn Error>> {
+    match detect_bin_slice_version(bytes)? {
+        TraceEventsFileFormat::BinaryV0 => Ok(codetracer_trace_format_capnp::capnptrace::read_trace(&mut &bytes[..])?),
+        TraceEventsFileFormat::Binary => crate::cbor_zstd_reader::read_trace_from_slice(bytes, dictionary),
+        TraceEventsFileFormat::Json => {
+            unreachable!()
         }
     }
 }
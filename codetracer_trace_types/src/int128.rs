@@ -0,0 +1,97 @@
+//! 128-bit integers: a decimal string in human-readable formats (JSON), 16 big-endian bytes
+//! otherwise (CBOR).
+//!
+//! Like in `bytes`, both representations are accepted when deserializing, because values nested in
+//! internally tagged enums are buffered by serde and lose the `is_human_readable` flag of the format.
+
+use std::{fmt, marker::PhantomData, str::FromStr};
+
+use serde::de::{self, SeqAccess, Visitor};
+use serde::{Deserializer, Serializer};
+
+trait Int128: FromStr + fmt::Display + Sized {
+    const NAME: &'static str;
+
+    fn from_be_bytes(bytes: [u8; 16]) -> Self;
+}
+
+impl Int128 for i128 {
+    const NAME: &'static str = "an i128";
+
+    fn from_be_bytes(bytes: [u8; 16]) -> Self {
+        i128::from_be_bytes(bytes)
+    }
+}
+
+impl Int128 for u128 {
+    const NAME: &'static str = "a u128";
+
+    fn from_be_bytes(bytes: [u8; 16]) -> Self {
+        u128::from_be_bytes(bytes)
+    }
+}
+
+fn serialize<S: Serializer>(v: impl fmt::Display, be_bytes: [u8; 16], s: S) -> Result<S::Ok, S::Error> {
+    if s.is_human_readable() {
+        s.collect_str(&v)
+    } else {
+        s.serialize_bytes(&be_bytes)
+    }
+}
+
+struct Int128Visitor<T>(PhantomData<T>);
+
+impl<T: Int128> Int128Visitor<T> {
+    fn from_bytes<E: de::Error>(v: &[u8]) -> Result<T, E> {
+        let bytes: [u8; 16] = v.try_into().map_err(|_| E::invalid_length(v.len(), &"16 bytes"))?;
+        Ok(T::from_be_bytes(bytes))
+    }
+}
+
+impl<'de, T: Int128> Visitor<'de> for Int128Visitor<T> {
+    type Value = T;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "{} as a decimal string or 16 big-endian bytes", T::NAME)
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<T, E> {
+        v.parse().map_err(|_| E::invalid_value(de::Unexpected::Str(v), &self))
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<T, E> {
+        Self::from_bytes(v)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<T, A::Error> {
+        let mut bytes = Vec::with_capacity(16);
+        while let Some(byte) = seq.next_element::<u8>()? {
+            bytes.push(byte);
+        }
+        Self::from_bytes(&bytes)
+    }
+}
+
+pub mod signed {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(v: &i128, s: S) -> Result<S::Ok, S::Error> {
+        super::serialize(v, v.to_be_bytes(), s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<i128, D::Error> {
+        d.deserialize_any(Int128Visitor(PhantomData))
+    }
+}
+
+pub mod unsigned {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(v: &u128, s: S) -> Result<S::Ok, S::Error> {
+        super::serialize(v, v.to_be_bytes(), s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<u128, D::Error> {
+        d.deserialize_any(Int128Visitor(PhantomData))
+    }
+}
@@ -1,5 +1,9 @@
 mod base64;
+mod bytes;
+mod int128;
+mod schema;
 mod types;
+pub use schema::*;
 pub use types::*;
 
 #[test]
@@ -11,3 +15,22 @@ fn test_equality_of_value_records() {
     assert_eq!(a, b);
     assert_ne!(a, different);
 }
+
+#[test]
+fn test_trace_events_schema() {
+    let schema = serde_json::to_value(trace_events_schema()).unwrap();
+    let definitions = &schema["definitions"];
+
+    for name in ["TraceLowLevelEvent", "ValueRecord", "TypeRecord", "TypeKind", "EventLogKind"] {
+        assert!(definitions.get(name).is_some(), "missing definition for {name}");
+    }
+    // repr enums are numbers and floats are strings, as in their serde representation
+    assert_eq!(definitions["TypeKind"]["type"], "integer");
+    let float = definitions["ValueRecord"]["oneOf"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|variant| variant["properties"]["kind"]["enum"][0] == "Float")
+        .unwrap();
+    assert_eq!(float["properties"]["f"]["type"], "string");
+}
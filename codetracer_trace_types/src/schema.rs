@@ -0,0 +1,24 @@
+//! [JSON Schema](https://json-schema.org/) descriptions of the JSON files that make up a trace.
+//!
+//! The schemas are derived from the record types, so they always follow their serde representation.
+
+use std::path::PathBuf;
+
+use schemars::{schema::RootSchema, schema_for};
+
+use crate::{TraceLowLevelEvent, TraceMetadata};
+
+/// Schema of `trace.json`: an array of [`TraceLowLevelEvent`] values.
+pub fn trace_events_schema() -> RootSchema {
+    schema_for!(Vec<TraceLowLevelEvent>)
+}
+
+/// Schema of `trace_metadata.json`.
+pub fn trace_metadata_schema() -> RootSchema {
+    schema_for!(TraceMetadata)
+}
+
+/// Schema of `trace_paths.json`: the recorded paths, indexed by `PathId`.
+pub fn trace_paths_schema() -> RootSchema {
+    schema_for!(Vec<PathBuf>)
+}
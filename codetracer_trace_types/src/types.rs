@@ -3,15 +3,18 @@
 //! All structures derive [`serde::Serialize`] and [`serde::Deserialize`].
 
 use std::cmp::Ord;
+use std::collections::BTreeMap;
 use std::ops;
 use std::path::PathBuf;
 
 use crate::base64;
+use crate::bytes;
+use crate::int128;
 use num_derive::FromPrimitive;
+use schemars::{JsonSchema, JsonSchema_repr};
 use serde::{Deserialize, Serialize};
 use serde_repr::*;
-use schemars::JsonSchema;
-use serde_with::{serde_as, DisplayFromStr};
+use serde_with::{DisplayFromStr, serde_as};
 
 // currently, we do assume that we record the whole program
 // so, we try to include minimal amount of data,
@@ -24,13 +27,20 @@ pub const NONE_VALUE: ValueRecord = ValueRecord::None { type_id: NONE_TYPE_ID };
 
 pub const TOP_LEVEL_FUNCTION_ID: FunctionId = FunctionId(0);
 
+/// Version of the event schema described by the types in this module.
+///
+/// It is recorded in [`TraceMetadata::format_version`]. Traces written before the field existed
+/// read back as version 0 and may still contain deprecated events such as
+/// [`TraceLowLevelEvent::Variable`] and [`TraceLowLevelEvent::DropVariable`].
+pub const TRACE_FORMAT_VERSION: u32 = 1;
+
 /// Low level building blocks that make up a recorded trace.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub enum TraceLowLevelEvent {
     Step(StepRecord),
     Path(PathBuf),            // should be always generated before usage, so we can stop stream at random n
     VariableName(String),     // interning new name for variables
-    Variable(String),         // interning new name for variables: backward compat
+    Variable(String),         // deprecated: old name of VariableName, upgraded to it when reading
     Type(TypeRecord),         // should be always generated before Value referencing it
     Value(FullValueRecord),   // full values: simpler case working even without modification support
     Function(FunctionRecord), // should be always generated before CallRecord referencing it
@@ -53,24 +63,32 @@ pub enum TraceLowLevelEvent {
     AssignCompoundItem(AssignCompoundItemRecord),
     AssignCell(AssignCellRecord),
     VariableCell(VariableCellRecord),
-    DropVariable(VariableId),
+    DropVariable(VariableId), // deprecated: same as a single-element DropVariables, upgraded to it when reading
 
     ThreadStart(ThreadId),
     ThreadExit(ThreadId),
     ThreadSwitch(ThreadId),
 
+    // monotonic time in nanoseconds since the start of the trace (`TraceMetadata::start_time`),
+    // for the events following it; optional, emitted only if the recorder enables timestamps
+    Timestamp(u64),
+
+    // the writer discarded this many events here because its queue was full;
+    // only events which later ones don't depend on are ever dropped
+    DroppedEvents(u64),
+
     // normal event, workaround for cases when we need to drop
     // a step event, but the trace needs to be append-only
     DropLastStep,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct BindVariableRecord {
     pub variable_id: VariableId,
     pub place: Place,
 }
 
-#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[derive(Default, Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub enum PassBy {
     #[default]
     Value,
@@ -81,14 +99,14 @@ pub enum PassBy {
 // used for all kinds of by value/by ref assignment/passing
 //   * assignments
 //   * arg(parameter) passing
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct AssignmentRecord {
     pub to: VariableId,
     pub pass_by: PassBy,
     pub from: RValue,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 #[serde(tag = "kind")]
 pub enum RValue {
     Simple(VariableId),
@@ -97,32 +115,32 @@ pub enum RValue {
     Compound(Vec<VariableId>),
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct CompoundValueRecord {
     pub place: Place,
     pub value: ValueRecord,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct CellValueRecord {
     pub place: Place,
     pub value: ValueRecord,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
 pub struct AssignCompoundItemRecord {
     pub place: Place,
     pub index: usize,
     pub item_place: Place,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct AssignCellRecord {
     pub place: Place,
     pub new_value: ValueRecord,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct VariableCellRecord {
     pub variable_id: VariableId,
     pub place: Place,
@@ -139,31 +157,80 @@ pub struct VariableCellRecord {
 //  it's useful to let us track things on the more direct value
 //    level/things like aliasing/mutable variables in different frames
 //    history of mutations to a value etc
-#[derive(Hash, Debug, Default, Copy, Clone, Serialize, Deserialize, Ord, PartialOrd, Eq, PartialEq)]
+#[derive(Hash, Debug, Default, Copy, Clone, Serialize, Deserialize, Ord, PartialOrd, Eq, PartialEq, JsonSchema)]
 pub struct Place(pub i64);
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct FullValueRecord {
     pub variable_id: VariableId,
     pub value: ValueRecord,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct TraceMetadata {
     pub workdir: PathBuf,
     pub program: String,
     pub args: Vec<String>,
+    // missing in traces from before versioning: those are version 0
+    #[serde(default)]
+    pub format_version: u32,
+
+    // the fields below are optional: older traces and some recorders don't provide them
+
+    // wall-clock time, in milliseconds since the unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start_time: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end_time: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    // only the variables selected by the recorder, not the whole environment
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub environment: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<LanguageInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recorder: Option<RecorderInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git: Option<GitInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    // free-form data, specific to a recorder or a tool
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub extensions: BTreeMap<String, serde_json::Value>,
+}
+
+/// The language of the traced program
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct LanguageInfo {
+    pub name: String,
+    pub version: String,
+}
+
+/// The tool which produced the trace, e.g. a recorder crate
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct RecorderInfo {
+    pub name: String,
+    pub version: String,
+}
+
+/// The state of the git repository containing `workdir`, at the time the trace was finished
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct GitInfo {
+    pub commit: String,
+    // true if there were uncommitted changes
+    pub dirty: bool,
 }
 
 // call keys:
 
-#[derive(Debug, Default, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
 #[serde(transparent)]
 pub struct CallKey(pub i64);
 
-impl Into<usize> for CallKey {
-    fn into(self) -> usize {
-        self.0 as usize
+impl From<CallKey> for usize {
+    fn from(value: CallKey) -> Self {
+        value.0 as usize
     }
 }
 
@@ -185,39 +252,39 @@ pub const NO_KEY: CallKey = CallKey(-1);
 
 // end of call keys code
 
-#[derive(Debug, Default, Copy, Clone, Serialize, Deserialize, PartialEq)]
+#[derive(Hash, Debug, Default, Copy, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
 #[serde(transparent)]
 pub struct Line(pub i64);
 
-impl Into<usize> for Line {
-    fn into(self) -> usize {
-        self.0 as usize
+impl From<Line> for usize {
+    fn from(value: Line) -> Self {
+        value.0 as usize
     }
 }
 
-impl Into<i64> for Line {
-    fn into(self) -> i64 {
-        self.0
+impl From<Line> for i64 {
+    fn from(value: Line) -> Self {
+        value.0
     }
 }
 
-#[derive(Hash, Debug, Default, Copy, Clone, Serialize, Deserialize, Ord, PartialOrd, Eq, PartialEq)]
+#[derive(Hash, Debug, Default, Copy, Clone, Serialize, Deserialize, Ord, PartialOrd, Eq, PartialEq, JsonSchema)]
 #[serde(transparent)]
 pub struct PathId(pub usize);
 
-impl Into<usize> for PathId {
-    fn into(self) -> usize {
-        self.0
+impl From<PathId> for usize {
+    fn from(value: PathId) -> Self {
+        value.0
     }
 }
 
-#[derive(Debug, Default, Copy, Clone, Serialize, Deserialize, Ord, PartialOrd, Eq, PartialEq)]
+#[derive(Debug, Default, Copy, Clone, Serialize, Deserialize, Ord, PartialOrd, Eq, PartialEq, JsonSchema)]
 #[serde(transparent)]
 pub struct StepId(pub i64);
 
-impl Into<usize> for StepId {
-    fn into(self) -> usize {
-        self.0 as usize
+impl From<StepId> for usize {
+    fn from(value: StepId) -> Self {
+        value.0 as usize
     }
 }
 
@@ -237,47 +304,47 @@ impl ops::Sub<usize> for StepId {
     }
 }
 
-#[derive(Hash, Debug, Default, Copy, Clone, Serialize, Deserialize, Ord, PartialOrd, Eq, PartialEq)]
+#[derive(Hash, Debug, Default, Copy, Clone, Serialize, Deserialize, Ord, PartialOrd, Eq, PartialEq, JsonSchema)]
 pub struct VariableId(pub usize);
 
-impl Into<usize> for VariableId {
-    fn into(self) -> usize {
-        self.0
+impl From<VariableId> for usize {
+    fn from(value: VariableId) -> Self {
+        value.0
     }
 }
 
-#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq, JsonSchema)]
 pub struct FunctionId(pub usize);
 
-impl Into<usize> for FunctionId {
-    fn into(self) -> usize {
-        self.0
+impl From<FunctionId> for usize {
+    fn from(value: FunctionId) -> Self {
+        value.0
     }
 }
 
-#[derive(Hash, Debug, Default, Copy, Clone, Serialize, Deserialize, Ord, PartialOrd, Eq, PartialEq)]
+#[derive(Hash, Debug, Default, Copy, Clone, Serialize, Deserialize, Ord, PartialOrd, Eq, PartialEq, JsonSchema)]
 pub struct ThreadId(pub u64);
 
-impl Into<u64> for ThreadId {
-    fn into(self) -> u64 {
-        self.0
+impl From<ThreadId> for u64 {
+    fn from(value: ThreadId) -> Self {
+        value.0
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct CallRecord {
     // pub key: CallKey,
     pub function_id: FunctionId,
     pub args: Vec<FullValueRecord>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct ReturnRecord {
     // implicit by order or explicit in some cases? pub call_key: CallKey
     pub return_value: ValueRecord,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct FunctionRecord {
     pub path_id: PathId,
     pub line: Line,
@@ -290,56 +357,84 @@ pub struct FunctionRecord {
 //     pub value: ValueRecord,
 // }
 
-#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, JsonSchema)]
 pub struct StepRecord {
     pub path_id: PathId,
     pub line: Line,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct VariableRecord {
     pub name: String,
     pub value: ValueRecord,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash, JsonSchema)]
 pub struct TypeRecord {
     pub kind: TypeKind,
     pub lang_type: String,
-    // for now only for Struct and Pointer: TODO eventually
-    // replace with an enum for TypeRecord, or with more cases
-    // in TypeSpecificInfo for collections, etc
+    // `None` when nothing more than the kind is known about the type
     pub specific_info: TypeSpecificInfo,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash, JsonSchema)]
 pub struct FieldTypeRecord {
     pub name: String,
     pub type_id: TypeId,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash, JsonSchema)]
+pub struct VariantTypeRecord {
+    pub name: String,
+    // empty for variants without a payload; positional payloads use the index as a name
+    pub fields: Vec<FieldTypeRecord>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash, JsonSchema)]
 #[serde(tag = "kind")]
 pub enum TypeSpecificInfo {
     None,
-    Struct { fields: Vec<FieldTypeRecord> },
-    Pointer { dereference_type_id: TypeId },
+    Struct {
+        fields: Vec<FieldTypeRecord>,
+    },
+    Pointer {
+        dereference_type_id: TypeId,
+    },
+    // `Seq`, `Array`, `Slice`, `Set` and the other homogeneous collections
+    Collection {
+        element_type_id: TypeId,
+    },
+    Map {
+        key_type_id: TypeId,
+        value_type_id: TypeId,
+    },
+    // `Enum` and `Variant`: all variants, including the ones never seen in a value
+    Enum {
+        variants: Vec<VariantTypeRecord>,
+    },
+    Tuple {
+        element_type_ids: Vec<TypeId>,
+    },
+    Function {
+        parameters: Vec<FieldTypeRecord>,
+        return_type_id: TypeId,
+    },
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct RecordEvent {
     pub kind: EventLogKind,
     pub metadata: String,
     pub content: String,
 }
 
-#[derive(Debug, Default, Copy, Clone, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Default, Copy, Clone, Serialize, Deserialize, PartialEq, Eq, Hash, JsonSchema)]
 #[serde(transparent)]
 pub struct TypeId(pub usize);
 
-impl Into<usize> for TypeId {
-    fn into(self) -> usize {
-        self.0
+impl From<TypeId> for usize {
+    fn from(value: TypeId) -> Self {
+        value.0
     }
 }
 
@@ -349,15 +444,38 @@ impl Into<usize> for TypeId {
 // serialize ValueRecord in a compatible way?
 /// Representation of a runtime value captured in a trace.
 #[serde_as]
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, JsonSchema)]
 #[serde(tag = "kind")]
 pub enum ValueRecord {
     Int {
         i: i64,
         type_id: TypeId,
     },
+    UInt {
+        u: u64,
+        type_id: TypeId,
+    },
+    // serde can't buffer 128-bit integers in internally tagged enums, so they are decimal
+    // strings in JSON and 16 big-endian bytes in CBOR (capnp stores the two halves)
+    Int128 {
+        #[serde(with = "int128::signed")]
+        #[schemars(with = "String")]
+        i: i128,
+        type_id: TypeId,
+    },
+    UInt128 {
+        #[serde(with = "int128::unsigned")]
+        #[schemars(with = "String")]
+        u: u128,
+        type_id: TypeId,
+    },
+    Char {
+        c: char,
+        type_id: TypeId,
+    },
     Float {
         #[serde_as(as = "DisplayFromStr")]
+        #[schemars(with = "String")]
         f: f64,
         type_id: TypeId,
     },
@@ -411,14 +529,38 @@ pub enum ValueRecord {
     },
     BigInt {
         #[serde(with = "base64")]
+        #[schemars(with = "String")]
         b: Vec<u8>, // Base64 encoded bytes of a big-endian unsigned integer
         negative: bool,
         type_id: TypeId,
     },
+    Bytes {
+        #[serde(with = "bytes")]
+        #[schemars(with = "String")]
+        data: Vec<u8>, // base64 encoded in JSON
+        // set if `data` was truncated: the length of the whole buffer
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        original_length: Option<u64>,
+        type_id: TypeId,
+    },
+    // placeholder for a value over the writer's limits, see `ValueLimits` in the writer
+    Elided {
+        // length of the string (in characters) or collection, if it had one
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        original_length: Option<u64>,
+        // the beginning of the value, missing if it was nested too deep to record anything
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        preview: Option<Box<ValueRecord>>,
+        type_id: TypeId,
+    },
+    Map {
+        entries: Vec<(ValueRecord, ValueRecord)>, // (key, value) pairs, in iteration order
+        type_id: TypeId,                          // usually a Type with MAP kind and TypeSpecificInfo::Map
+    },
 }
 
 /// Categories of types recorded in the trace.
-#[derive(Debug, Default, Copy, Clone, FromPrimitive, Serialize_repr, Deserialize_repr, PartialEq, JsonSchema)]
+#[derive(Debug, Default, Copy, Clone, FromPrimitive, Serialize_repr, Deserialize_repr, PartialEq, Eq, Hash, JsonSchema_repr)]
 #[repr(u8)]
 pub enum TypeKind {
     #[default]
@@ -474,10 +616,12 @@ pub enum TypeKind {
     NonExpanded,
     Any,
     Slice,
+
+    Map,
 }
 
 /// Kinds of I/O or log events that can appear in a trace.
-#[derive(Debug, Default, Copy, Clone, FromPrimitive, Serialize_repr, Deserialize_repr, PartialEq, JsonSchema)]
+#[derive(Debug, Default, Copy, Clone, FromPrimitive, Serialize_repr, Deserialize_repr, PartialEq, JsonSchema_repr)]
 #[repr(u8)]
 pub enum EventLogKind {
     #[default]
@@ -1,3 +1,5 @@
+use std::io::{self, Write};
+
 use clap::Args;
 use serde_json::Value;
 use trace_formatter::{
@@ -5,20 +7,31 @@ use trace_formatter::{
     read_write_json::{save_to_file, serialize_file},
 };
 
+use crate::trace_io::{STDIO, read_input};
+
 #[derive(Debug, Clone, Args)]
 pub(crate) struct FmtTraceCommand {
-    /// Trace file which we want to format
+    /// Trace file which we want to format, or `-` for stdin
     source_file: String,
 
-    /// Path where the formatted trace will be saved
+    /// Path where the formatted trace will be saved, or `-` for stdout
     target_file: String,
 }
 
 pub(crate) fn run(args: FmtTraceCommand) {
-    let ser_json: Value = serialize_file(args.source_file);
+    let ser_json: Value = if args.source_file == STDIO {
+        serde_json::from_slice(&read_input(STDIO)).expect("Failed to parse the json given on stdin")
+    } else {
+        serialize_file(args.source_file)
+    };
 
     let prettified_json: String = prettify_value(ser_json, "", false);
     let final_pretty_json: String = correct_path(&prettified_json);
 
-    save_to_file(args.target_file, final_pretty_json);
+    if args.target_file == STDIO {
+        // a closed pipe just ends the output
+        let _ = writeln!(io::stdout(), "{}", final_pretty_json.trim_end_matches('\n'));
+    } else {
+        save_to_file(args.target_file, final_pretty_json);
+    }
 }
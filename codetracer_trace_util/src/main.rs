@@ -1,15 +1,44 @@
-use std::path::Path;
+use std::fs;
 
 use crate::fmt_trace_cmd::FmtTraceCommand;
+use crate::print_cmd::PrintCommand;
+use crate::record_cmd::{RecordCommand, ServeCommand};
+use crate::schema_cmd::SchemaCommand;
+use crate::trace_io::OutputFormat;
+use crate::train_dict_cmd::TrainDictCommand;
+use crate::upgrade_cmd::UpgradeCommand;
 use clap::{Args, Parser, Subcommand};
-use codetracer_trace_reader::create_trace_reader;
-use codetracer_trace_writer::{create_trace_writer, trace_writer::TraceWriter};
+use codetracer_trace_writer::TraceWriterOptions;
 mod fmt_trace_cmd;
+mod print_cmd;
+mod record_cmd;
+mod schema_cmd;
+mod trace_io;
+mod train_dict_cmd;
+mod upgrade_cmd;
 
 #[derive(Debug, Clone, Args)]
 struct ConvertCommand {
+    /// Trace events file, or `-` for stdin; the format of stdin is detected from its contents
     input_file: String,
+    /// Path where the converted trace events will be saved, or `-` for stdout
     output_file: String,
+
+    /// Format of the output; by default given by the extension of the output file, or JSON for stdout
+    #[arg(short = 'f', long, value_enum)]
+    output_format: Option<OutputFormat>,
+
+    /// zstd compression level of a binary output file
+    #[arg(short = 'l', long, default_value_t = codetracer_trace_writer::DEFAULT_COMPRESSION_LEVEL, allow_negative_numbers = true)]
+    compression_level: i32,
+
+    /// Number of background compression threads for a binary output file
+    #[arg(short, long, default_value_t = 0)]
+    workers: u32,
+
+    /// Pretrained zstd dictionary (see `train-dict`) to compress a binary output file with
+    #[arg(short, long)]
+    dictionary: Option<String>,
 }
 
 #[non_exhaustive]
@@ -19,6 +48,21 @@ enum RuntimeTracingCliCommand {
     Convert(ConvertCommand),
     /// Format a trace which is in JSON file format
     FormatTrace(FmtTraceCommand),
+    /// Print the events of a trace, one JSON object per line
+    Print(PrintCommand),
+    /// Print the JSON Schema of a trace file
+    Schema(SchemaCommand),
+    /// Rewrite a trace recorded with an older format version using current events
+    Upgrade(UpgradeCommand),
+    /// Train a zstd dictionary for binary traces on sample traces
+    TrainDict(TrainDictCommand),
+    /// Receive one live trace streamed by a `SocketTraceWriter` and save it
+    Record(RecordCommand),
+    /// Receive live traces streamed by `SocketTraceWriter`s until interrupted, saving each one
+    ///
+    /// Each trace is saved into its own `trace-<n>` subdirectory of the output directory; the
+    /// numbers of the subdirectories which already exist, e.g. from an earlier run, are skipped.
+    Serve(ServeCommand),
 }
 
 #[derive(Parser, Debug)]
@@ -53,17 +97,35 @@ fn main() {
 
     match args.command {
         RuntimeTracingCliCommand::Convert(convert_command) => {
-            let input_file_format = determine_input_file_format_from_name(&convert_command.input_file).unwrap();
-            let output_file_format = determine_output_file_format_from_name(&convert_command.output_file).unwrap();
-            let mut trace_reader = create_trace_reader(input_file_format);
-            let mut trace_writer = create_trace_writer("", &[], output_file_format);
-            let mut trace_events = trace_reader.load_trace_events(Path::new(&convert_command.input_file)).unwrap();
-            trace_writer.begin_writing_trace_events(Path::new(&convert_command.output_file)).unwrap();
-            TraceWriter::append_events(trace_writer.as_mut(), &mut trace_events);
-            trace_writer.finish_writing_trace_events().unwrap();
+            let options = TraceWriterOptions {
+                compression_level: convert_command.compression_level,
+                workers: convert_command.workers,
+                dictionary: convert_command.dictionary.map(|dictionary| fs::read(dictionary).unwrap()),
+                ..Default::default()
+            };
+            let trace_events = trace_io::load_trace_events(&convert_command.input_file, false);
+            trace_io::store_trace_events(&convert_command.output_file, convert_command.output_format, options, trace_events);
         }
         RuntimeTracingCliCommand::FormatTrace(fmt_trace_cmd) => {
             fmt_trace_cmd::run(fmt_trace_cmd);
         }
+        RuntimeTracingCliCommand::Print(print_cmd) => {
+            print_cmd::run(print_cmd);
+        }
+        RuntimeTracingCliCommand::Schema(schema_cmd) => {
+            schema_cmd::run(schema_cmd);
+        }
+        RuntimeTracingCliCommand::Upgrade(upgrade_cmd) => {
+            upgrade_cmd::run(upgrade_cmd);
+        }
+        RuntimeTracingCliCommand::TrainDict(train_dict_cmd) => {
+            train_dict_cmd::run(train_dict_cmd);
+        }
+        RuntimeTracingCliCommand::Record(record_cmd) => {
+            record_cmd::run_record(record_cmd);
+        }
+        RuntimeTracingCliCommand::Serve(serve_cmd) => {
+            record_cmd::run_serve(serve_cmd);
+        }
     }
 }
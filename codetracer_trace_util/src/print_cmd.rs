@@ -0,0 +1,24 @@
+use std::io::{self, BufWriter, Write};
+
+use clap::Args;
+
+use crate::trace_io;
+
+#[derive(Debug, Clone, Args)]
+pub(crate) struct PrintCommand {
+    /// Trace events file, or `-` for stdin; the format is detected from the contents
+    input_file: String,
+}
+
+pub(crate) fn run(args: PrintCommand) {
+    let trace_events = trace_io::load_trace_events(&args.input_file, false);
+    let mut stdout = BufWriter::new(io::stdout().lock());
+    for event in &trace_events {
+        let line = serde_json::to_string(event).unwrap();
+        // a closed pipe, e.g. `print trace.bin | head`, just ends the output
+        if writeln!(stdout, "{line}").is_err() {
+            return;
+        }
+    }
+    let _ = stdout.flush();
+}
@@ -0,0 +1,172 @@
+use std::{
+    error::Error,
+    fs,
+    io::{self, Read},
+    net::TcpListener,
+    path::{Path, PathBuf},
+    process, thread,
+    time::Duration,
+};
+
+use clap::Args;
+use codetracer_trace_reader::TraceStreamReader;
+use codetracer_trace_writer::{TraceStreamAddress, create_trace_writer, trace_writer::TraceWriter};
+
+use crate::trace_io::OutputFormat;
+
+#[derive(Debug, Clone, Args)]
+pub(crate) struct ReceiveArgs {
+    /// Address to listen on: `host:port` for TCP or `unix:<path>` for a Unix domain socket
+    address: String,
+
+    /// Directory where the received trace will be saved
+    output_dir: String,
+
+    /// Format of the saved trace events
+    #[arg(short = 'f', long, value_enum, default_value_t = OutputFormat::Binary)]
+    output_format: OutputFormat,
+}
+
+#[derive(Debug, Clone, Args)]
+pub(crate) struct RecordCommand {
+    #[command(flatten)]
+    receive: ReceiveArgs,
+}
+
+#[derive(Debug, Clone, Args)]
+pub(crate) struct ServeCommand {
+    #[command(flatten)]
+    receive: ReceiveArgs,
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixListener),
+}
+
+impl Listener {
+    fn bind(address: &str) -> Result<Self, Box<dyn Error>> {
+        match TraceStreamAddress::parse(address)? {
+            TraceStreamAddress::Tcp(address) => Ok(Listener::Tcp(TcpListener::bind(address)?)),
+            #[cfg(unix)]
+            TraceStreamAddress::Unix(path) => match std::os::unix::net::UnixListener::bind(&path) {
+                Ok(listener) => Ok(Listener::Unix(listener)),
+                // e.g. left behind by a receiver which didn't shut down cleanly; connecting to check
+                // whether it's still in use would take the connection of a receiver which is
+                Err(err) if err.kind() == io::ErrorKind::AddrInUse => {
+                    Err(format!("{} already exists; remove it if no other receiver is listening on it", path.display()).into())
+                }
+                Err(err) => Err(err.into()),
+            },
+        }
+    }
+
+    fn accept(&self) -> Result<Box<dyn Read + Send>, Box<dyn Error>> {
+        match self {
+            Listener::Tcp(listener) => Ok(Box::new(listener.accept()?.0)),
+            #[cfg(unix)]
+            Listener::Unix(listener) => Ok(Box::new(listener.accept()?.0)),
+        }
+    }
+}
+
+/// Saves the trace streamed into `input` as `trace.json` or `trace.bin`, `trace_metadata.json` and
+/// `trace_paths.json` in `output_dir`. If the stream ends early, what was received is still saved.
+fn receive_trace(input: impl Read, output_dir: &Path, format: OutputFormat) -> Result<(), Box<dyn Error>> {
+    let mut reader = TraceStreamReader::new(input)?;
+    fs::create_dir_all(output_dir)?;
+
+    let events_file = match format {
+        OutputFormat::Json => "trace.json",
+        OutputFormat::Binary | OutputFormat::BinaryV0 => "trace.bin",
+    };
+    let mut trace_writer = create_trace_writer("", &[], format.into());
+    trace_writer.begin_writing_trace_events(&output_dir.join(events_file))?;
+    TraceWriter::begin_writing_trace_paths(trace_writer.as_mut(), &output_dir.join("trace_paths.json"))?;
+
+    let result = loop {
+        match reader.next_batch() {
+            Ok(Some(mut events)) => TraceWriter::append_events(trace_writer.as_mut(), &mut events),
+            Ok(None) => break Ok(()),
+            Err(err) => break Err(err),
+        }
+    };
+
+    trace_writer.finish_writing_trace_events()?;
+    TraceWriter::finish_writing_trace_paths(trace_writer.as_mut())?;
+    // the metadata of the traced program, not of this writer
+    fs::write(output_dir.join("trace_metadata.json"), serde_json::to_string(reader.metadata())?)?;
+    result
+}
+
+/// Creates the next `trace-<n>` subdirectory of `output_dir`, skipping the ones which already
+/// exist, e.g. from an earlier run
+fn create_trace_dir(output_dir: &Path, next_index: &mut u64) -> io::Result<PathBuf> {
+    fs::create_dir_all(output_dir)?;
+    loop {
+        let trace_dir = output_dir.join(format!("trace-{next_index}"));
+        *next_index += 1;
+        match fs::create_dir(&trace_dir) {
+            Ok(()) => return Ok(trace_dir),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+fn bind(address: &str) -> Listener {
+    Listener::bind(address).unwrap_or_else(|err| {
+        eprintln!("Unable to listen on {address}: {err}");
+        process::exit(1);
+    })
+}
+
+pub(crate) fn run_record(args: RecordCommand) {
+    let args = args.receive;
+    let listener = bind(&args.address);
+    let result = listener
+        .accept()
+        .and_then(|input| receive_trace(input, Path::new(&args.output_dir), args.output_format));
+    if let Err(err) = result {
+        eprintln!("Unable to receive the trace: {err}");
+        process::exit(1);
+    }
+}
+
+pub(crate) fn run_serve(args: ServeCommand) {
+    let args = args.receive;
+    let listener = bind(&args.address);
+    // grows while accepting keeps failing, e.g. when the process is out of file descriptors
+    let mut backoff = Duration::ZERO;
+    let mut next_index = 0;
+    loop {
+        let input = match listener.accept() {
+            Ok(input) => {
+                backoff = Duration::ZERO;
+                input
+            }
+            Err(err) => {
+                eprintln!("Unable to accept a connection: {err}");
+                backoff = (backoff * 2).clamp(Duration::from_millis(10), Duration::from_secs(1));
+                thread::sleep(backoff);
+                continue;
+            }
+        };
+        let output_dir = match create_trace_dir(Path::new(&args.output_dir), &mut next_index) {
+            Ok(output_dir) => output_dir,
+            Err(err) => {
+                eprintln!("Unable to create a directory for the trace: {err}");
+                continue;
+            }
+        };
+        let format = args.output_format;
+        thread::spawn(move || {
+            eprintln!("Receiving a trace into {}", output_dir.display());
+            match receive_trace(input, &output_dir, format) {
+                Ok(()) => eprintln!("Saved the trace in {}", output_dir.display()),
+                Err(err) => eprintln!("Unable to receive the trace for {}: {err}", output_dir.display()),
+            }
+        });
+    }
+}
@@ -0,0 +1,39 @@
+use std::fs;
+
+use clap::{Args, ValueEnum};
+use codetracer_trace_types::{trace_events_schema, trace_metadata_schema, trace_paths_schema};
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub(crate) enum SchemaTarget {
+    /// `trace.json`: the event stream
+    Events,
+    /// `trace_metadata.json`
+    Metadata,
+    /// `trace_paths.json`
+    Paths,
+}
+
+#[derive(Debug, Clone, Args)]
+pub(crate) struct SchemaCommand {
+    /// Trace file whose schema should be emitted
+    #[arg(value_enum, default_value_t = SchemaTarget::Events)]
+    target: SchemaTarget,
+
+    /// Path where the schema will be saved; printed to stdout if omitted
+    #[arg(short, long)]
+    output_file: Option<String>,
+}
+
+pub(crate) fn run(args: SchemaCommand) {
+    let schema = match args.target {
+        SchemaTarget::Events => trace_events_schema(),
+        SchemaTarget::Metadata => trace_metadata_schema(),
+        SchemaTarget::Paths => trace_paths_schema(),
+    };
+    let json = serde_json::to_string_pretty(&schema).expect("schema is serializable");
+
+    match args.output_file {
+        Some(output_file) => fs::write(&output_file, json + "\n").unwrap_or_else(|_| panic!("Unable to write to destination file: {output_file}")),
+        None => println!("{json}"),
+    }
+}
@@ -0,0 +1,114 @@
+use std::{
+    fs,
+    io::{self, BufWriter, Read, Write},
+    path::Path,
+    process,
+};
+
+use clap::ValueEnum;
+use codetracer_trace_reader::{create_trace_reader, detect_trace_format, upgrade_events};
+use codetracer_trace_types::TraceLowLevelEvent;
+use codetracer_trace_writer::{TraceWriterOptions, create_trace_writer_with_options, trace_writer::TraceWriter};
+
+use crate::{determine_input_file_format_from_name, determine_output_file_format_from_name};
+
+/// The file name which stands for stdin or stdout
+pub(crate) const STDIO: &str = "-";
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub(crate) enum OutputFormat {
+    Json,
+    /// Streaming CBOR+zstd binary format
+    Binary,
+    /// Cap'n Proto binary format
+    BinaryV0,
+}
+
+impl From<OutputFormat> for codetracer_trace_writer::TraceEventsFileFormat {
+    fn from(format: OutputFormat) -> Self {
+        match format {
+            OutputFormat::Json => codetracer_trace_writer::TraceEventsFileFormat::Json,
+            OutputFormat::Binary => codetracer_trace_writer::TraceEventsFileFormat::Binary,
+            OutputFormat::BinaryV0 => codetracer_trace_writer::TraceEventsFileFormat::BinaryV0,
+        }
+    }
+}
+
+/// Stdout for the events of a trace. It's buffered, as stdout itself flushes at every newline
+/// byte, which binary traces are full of. A closed pipe, e.g. `convert trace.json - -f binary |
+/// head -c 10`, just ends the program, like the output of `print`, instead of failing the writer.
+struct TraceStdout(BufWriter<io::Stdout>);
+
+fn exit_on_broken_pipe<T>(result: io::Result<T>) -> io::Result<T> {
+    if let Err(err) = &result
+        && err.kind() == io::ErrorKind::BrokenPipe
+    {
+        process::exit(0);
+    }
+    result
+}
+
+impl Write for TraceStdout {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        exit_on_broken_pipe(self.0.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        exit_on_broken_pipe(self.0.flush())
+    }
+}
+
+/// Reads the whole file, or stdin for `-`
+pub(crate) fn read_input(input_file: &str) -> Vec<u8> {
+    if input_file == STDIO {
+        let mut bytes = vec![];
+        io::stdin().read_to_end(&mut bytes).expect("Unable to read stdin");
+        bytes
+    } else {
+        fs::read(input_file).unwrap_or_else(|_| panic!("Unable to read file: {input_file}"))
+    }
+}
+
+/// Loads the events of `input_file`, or of stdin for `-`. The format is given by the extension,
+/// or detected from the contents for stdin and unknown extensions.
+pub(crate) fn load_trace_events(input_file: &str, upgrade: bool) -> Vec<TraceLowLevelEvent> {
+    if input_file != STDIO
+        && let Some(format) = determine_input_file_format_from_name(input_file)
+    {
+        let mut trace_reader = create_trace_reader(format);
+        let path = Path::new(input_file);
+        let events = if upgrade {
+            trace_reader.load_upgraded_trace_events(path)
+        } else {
+            trace_reader.load_trace_events(path)
+        };
+        return events.unwrap();
+    }
+
+    let bytes = read_input(input_file);
+    let format = detect_trace_format(&bytes).unwrap_or_else(|| panic!("Unable to detect the format of trace: {input_file}"));
+    let events = create_trace_reader(format).load_trace_events_from_slice(&bytes).unwrap();
+    if upgrade { upgrade_events(events) } else { events }
+}
+
+/// Writes `events` into `output_file`, or to stdout for `-`. Without an explicit `format`, it's
+/// given by the extension; stdout gets JSON.
+pub(crate) fn store_trace_events(output_file: &str, format: Option<OutputFormat>, options: TraceWriterOptions, mut events: Vec<TraceLowLevelEvent>) {
+    let format = match format {
+        Some(format) => format.into(),
+        None if output_file == STDIO => codetracer_trace_writer::TraceEventsFileFormat::Json,
+        None => determine_output_file_format_from_name(output_file)
+            .unwrap_or_else(|| panic!("Unable to determine the format of {output_file}; use --output-format")),
+    };
+
+    let mut trace_writer = create_trace_writer_with_options("", &[], format, options);
+    if output_file == STDIO {
+        trace_writer
+            .begin_writing_trace_events_to(Box::new(TraceStdout(BufWriter::new(io::stdout()))))
+            .unwrap();
+    } else {
+        trace_writer.begin_writing_trace_events(Path::new(output_file)).unwrap();
+    }
+    TraceWriter::append_events(trace_writer.as_mut(), &mut events);
+    trace_writer.finish_writing_trace_events().unwrap();
+}
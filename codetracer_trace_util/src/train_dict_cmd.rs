@@ -0,0 +1,37 @@
+use std::{fs, path::Path};
+
+use clap::Args;
+use codetracer_trace_reader::create_trace_reader;
+use codetracer_trace_writer::train_dictionary;
+
+use crate::determine_input_file_format_from_name;
+
+#[derive(Debug, Clone, Args)]
+pub(crate) struct TrainDictCommand {
+    /// Sample traces, ideally recorded by the same recorder as the traces to be compressed
+    #[arg(required = true)]
+    input_files: Vec<String>,
+
+    /// Path where the dictionary will be saved
+    #[arg(short, long)]
+    output_file: String,
+
+    /// Maximum size of the dictionary in bytes
+    #[arg(long, default_value_t = 112_640)]
+    max_size: usize,
+}
+
+pub(crate) fn run(args: TrainDictCommand) {
+    let samples: Vec<_> = args
+        .input_files
+        .iter()
+        .map(|input_file| {
+            let input_file_format = determine_input_file_format_from_name(input_file).unwrap();
+            let mut trace_reader = create_trace_reader(input_file_format);
+            trace_reader.load_trace_events(Path::new(input_file)).unwrap()
+        })
+        .collect();
+
+    let dictionary = train_dictionary(&samples, args.max_size).unwrap();
+    fs::write(&args.output_file, dictionary).unwrap_or_else(|_| panic!("Unable to write to dictionary file: {}", args.output_file));
+}
@@ -0,0 +1,36 @@
+use std::fs;
+
+use clap::Args;
+use codetracer_trace_types::{TRACE_FORMAT_VERSION, TraceMetadata};
+
+use crate::trace_io::{self, OutputFormat};
+
+#[derive(Debug, Clone, Args)]
+pub(crate) struct UpgradeCommand {
+    /// Trace events file recorded with an older format version, or `-` for stdin
+    input_file: String,
+
+    /// Path where the upgraded trace events will be saved, or `-` for stdout
+    output_file: String,
+
+    /// Format of the output; by default given by the extension of the output file, or JSON for stdout
+    #[arg(short = 'f', long, value_enum)]
+    output_format: Option<OutputFormat>,
+
+    /// `trace_metadata.json` of the trace; its `format_version` is bumped in place
+    #[arg(short, long)]
+    metadata_file: Option<String>,
+}
+
+pub(crate) fn run(args: UpgradeCommand) {
+    let trace_events = trace_io::load_trace_events(&args.input_file, true);
+    trace_io::store_trace_events(&args.output_file, args.output_format, Default::default(), trace_events);
+
+    if let Some(metadata_file) = args.metadata_file {
+        let json = fs::read_to_string(&metadata_file).unwrap_or_else(|_| panic!("Unable to read metadata file: {metadata_file}"));
+        let mut metadata: TraceMetadata = serde_json::from_str(&json).unwrap();
+        metadata.format_version = TRACE_FORMAT_VERSION;
+        let json = serde_json::to_string(&metadata).unwrap();
+        fs::write(&metadata_file, json).unwrap_or_else(|_| panic!("Unable to write to metadata file: {metadata_file}"));
+    }
+}
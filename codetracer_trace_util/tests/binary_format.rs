@@ -1,41 +1,298 @@
-use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{env, fs, process};
 
-use codetracer_trace_reader::create_trace_reader;
-use codetracer_trace_writer::create_trace_writer;
+use codetracer_trace_reader::{
+    MappedTrace, create_mapped_trace_reader, create_parallel_trace_reader, create_trace_reader, create_trace_reader_with_dictionary,
+    detect_trace_format, for_each_event_parallel, read_trace_parallel,
+};
+use codetracer_trace_types::{FullValueRecord, Line, PathId, StepRecord, TraceLowLevelEvent, TypeId, ValueRecord, VariableId};
 use codetracer_trace_writer::trace_writer::TraceWriter;
+use codetracer_trace_writer::{
+    BackgroundEncoding, Backpressure, TraceBuffer, TraceWriterOptions, create_trace_writer_with_options, train_dictionary,
+};
 
-fn test_binary_roundtrip(ver: codetracer_trace_writer::TraceEventsFileFormat, binfile: &str) {
-    let json_path = Path::new("tests/data/trace.json");
-
+fn load_json_trace() -> Vec<TraceLowLevelEvent> {
     let mut json_reader = create_trace_reader(codetracer_trace_reader::TraceEventsFileFormat::Json);
-    let original = json_reader.load_trace_events(json_path).unwrap();
+    json_reader.load_trace_events(Path::new("tests/data/trace.json")).unwrap()
+}
 
-    let bin_path_str = format!("tests/data/{}", binfile);
-    let bin_path = Path::new(&bin_path_str);
+/// Writes `events` into memory and returns the trace
+fn write_trace(format: codetracer_trace_writer::TraceEventsFileFormat, options: TraceWriterOptions, events: &[TraceLowLevelEvent]) -> Vec<u8> {
+    let buffer = TraceBuffer::new();
+    let mut bin_writer = create_trace_writer_with_options("", &[], format, options);
+    bin_writer.begin_writing_trace_events_to(Box::new(buffer.clone())).unwrap();
+    TraceWriter::append_events(bin_writer.as_mut(), &mut events.to_vec());
+    bin_writer.finish_writing_trace_events().unwrap();
+    buffer.contents()
+}
 
-    let mut bin_writer = create_trace_writer("", &[], ver);
-    bin_writer.begin_writing_trace_events(bin_path).unwrap();
+fn test_binary_roundtrip(ver: codetracer_trace_writer::TraceEventsFileFormat) {
+    let original = load_json_trace();
+
+    let paths = TraceBuffer::new();
+    let trace = TraceBuffer::new();
+    let mut bin_writer = create_trace_writer_with_options("", &[], ver, TraceWriterOptions::default());
+    TraceWriter::begin_writing_trace_paths_to(bin_writer.as_mut(), Box::new(paths.clone())).unwrap();
+    bin_writer.begin_writing_trace_events_to(Box::new(trace.clone())).unwrap();
     TraceWriter::append_events(bin_writer.as_mut(), &mut original.clone());
     bin_writer.finish_writing_trace_events().unwrap();
+    TraceWriter::finish_writing_trace_paths(bin_writer.as_mut()).unwrap();
 
     let mut bin_reader = create_trace_reader(codetracer_trace_reader::TraceEventsFileFormat::Binary);
-    let tracer2_events = bin_reader.load_trace_events(bin_path).unwrap();
-
-    fs::remove_file(bin_path).unwrap();
+    let tracer2_events = bin_reader.load_trace_events_from_slice(&trace.contents()).unwrap();
+    let streamed_events = bin_reader.load_trace_events_from(&mut &trace.contents()[..]).unwrap();
 
     let orig_json = serde_json::to_string(&original).unwrap();
-    let new_json = serde_json::to_string(&tracer2_events).unwrap();
+    assert_eq!(orig_json, serde_json::to_string(&tracer2_events).unwrap());
+    assert_eq!(orig_json, serde_json::to_string(&streamed_events).unwrap());
 
-    assert_eq!(orig_json, new_json);
+    let path_list: Vec<PathBuf> = serde_json::from_slice(&paths.contents()).unwrap();
+    let path_events = original.iter().filter(|event| matches!(event, TraceLowLevelEvent::Path(_)));
+    assert_eq!(path_list.len(), path_events.count());
 }
 
 #[test]
 fn test_binary_roundtrip_v0() {
-    test_binary_roundtrip(codetracer_trace_writer::TraceEventsFileFormat::BinaryV0, "trace.v0.bin");
+    test_binary_roundtrip(codetracer_trace_writer::TraceEventsFileFormat::BinaryV0);
+}
+
+#[test]
+fn test_binary_v0_multiple_batches() {
+    let original: Vec<TraceLowLevelEvent> = std::iter::once(TraceLowLevelEvent::Path("foo.rs".into()))
+        .chain((0..10_000).map(|i| {
+            TraceLowLevelEvent::Step(StepRecord {
+                path_id: PathId(0),
+                line: Line(i),
+            })
+        }))
+        .collect();
+
+    let trace = write_trace(
+        codetracer_trace_writer::TraceEventsFileFormat::BinaryV0,
+        TraceWriterOptions::default(),
+        &original,
+    );
+
+    let mut bin_reader = create_trace_reader(codetracer_trace_reader::TraceEventsFileFormat::Binary);
+    let events = bin_reader.load_trace_events_from_slice(&trace).unwrap();
+
+    assert_eq!(serde_json::to_string(&original).unwrap(), serde_json::to_string(&events).unwrap());
 }
 
 #[test]
 fn test_binary_roundtrip_v1() {
-    test_binary_roundtrip(codetracer_trace_writer::TraceEventsFileFormat::Binary, "trace.v1.bin");
+    test_binary_roundtrip(codetracer_trace_writer::TraceEventsFileFormat::Binary);
+}
+
+#[test]
+fn test_detect_trace_format() {
+    use codetracer_trace_reader::TraceEventsFileFormat;
+    use codetracer_trace_writer::TraceEventsFileFormat as WriterFormat;
+
+    let original = load_json_trace();
+    let json = fs::read("tests/data/trace.json").unwrap();
+    assert!(matches!(detect_trace_format(&json), Some(TraceEventsFileFormat::Json)));
+    assert!(matches!(detect_trace_format(b"  \n[]"), Some(TraceEventsFileFormat::Json)));
+    for format in [WriterFormat::Binary, WriterFormat::BinaryV0] {
+        let trace = write_trace(format, TraceWriterOptions::default(), &original);
+        assert!(matches!(detect_trace_format(&trace), Some(TraceEventsFileFormat::Binary)));
+    }
+    assert!(detect_trace_format(b"{}").is_none());
+    assert!(detect_trace_format(b"").is_none());
+}
+
+#[test]
+fn test_mapped_trace() {
+    let original = load_json_trace();
+
+    for (format, binfile) in [
+        (codetracer_trace_writer::TraceEventsFileFormat::BinaryV0, "trace.v0.mapped.bin"),
+        (codetracer_trace_writer::TraceEventsFileFormat::Binary, "trace.v1.mapped.bin"),
+    ] {
+        // only a file can be mapped
+        let bin_path = env::temp_dir().join(format!("{}.{binfile}", process::id()));
+        let bin_path = bin_path.as_path();
+        fs::write(bin_path, write_trace(format, TraceWriterOptions::default(), &original)).unwrap();
+
+        let mut bin_reader = create_mapped_trace_reader(codetracer_trace_reader::TraceEventsFileFormat::Binary, None);
+        let events = bin_reader.load_trace_events(bin_path).unwrap();
+        assert_eq!(serde_json::to_string(&original).unwrap(), serde_json::to_string(&events).unwrap());
+
+        let mapped = MappedTrace::open(bin_path).unwrap();
+        if matches!(format, codetracer_trace_writer::TraceEventsFileFormat::BinaryV0) {
+            let view = mapped.view().unwrap();
+            assert_eq!(view.event_count().unwrap(), original.len());
+            let viewed: Vec<TraceLowLevelEvent> = view.events().map(|event| event.unwrap().to_event().unwrap()).collect();
+            assert_eq!(serde_json::to_string(&original).unwrap(), serde_json::to_string(&viewed).unwrap());
+        } else {
+            assert!(mapped.view().is_err());
+        }
+        drop(mapped);
+        fs::remove_file(bin_path).unwrap();
+    }
+}
+
+fn test_binary_roundtrip_with_options(options: TraceWriterOptions) {
+    let original = load_json_trace();
+
+    let dictionary = options.dictionary.clone();
+    let trace = write_trace(codetracer_trace_writer::TraceEventsFileFormat::Binary, options, &original);
+
+    let mut bin_reader = create_trace_reader_with_dictionary(codetracer_trace_reader::TraceEventsFileFormat::Binary, dictionary.clone());
+    let tracer2_events = bin_reader.load_trace_events_from_slice(&trace).unwrap();
+    let parallel_events = read_trace_parallel(&trace, dictionary.as_deref()).unwrap();
+
+    if dictionary.is_some() {
+        let mut plain_reader = create_trace_reader(codetracer_trace_reader::TraceEventsFileFormat::Binary);
+        assert!(plain_reader.load_trace_events_from_slice(&trace).is_err());
+    }
+
+    assert_eq!(serde_json::to_string(&original).unwrap(), serde_json::to_string(&tracer2_events).unwrap());
+    assert_eq!(
+        serde_json::to_string(&original).unwrap(),
+        serde_json::to_string(&parallel_events).unwrap()
+    );
+}
+
+#[test]
+fn test_parallel_decoding() {
+    let large_value = ValueRecord::String {
+        text: "x".repeat(100),
+        type_id: TypeId(0),
+    };
+    let mut original = vec![TraceLowLevelEvent::Path("foo.rs".into())];
+    for i in 0..10_000 {
+        original.push(TraceLowLevelEvent::Step(StepRecord {
+            path_id: PathId(0),
+            line: Line(i % 50),
+        }));
+        original.push(TraceLowLevelEvent::Value(FullValueRecord {
+            variable_id: VariableId(0),
+            value: large_value.clone(),
+        }));
+    }
+
+    let options = TraceWriterOptions {
+        frame_size: 1024,
+        ..Default::default()
+    };
+    let trace = write_trace(codetracer_trace_writer::TraceEventsFileFormat::Binary, options, &original);
+
+    let mut bin_reader = create_parallel_trace_reader(codetracer_trace_reader::TraceEventsFileFormat::Binary, None);
+    let events = bin_reader.load_trace_events_from_slice(&trace).unwrap();
+    assert_eq!(serde_json::to_string(&original).unwrap(), serde_json::to_string(&events).unwrap());
+
+    let steps = AtomicUsize::new(0);
+    let values = AtomicUsize::new(0);
+    for_each_event_parallel(&trace, None, |event| match event {
+        TraceLowLevelEvent::Step(_) => {
+            steps.fetch_add(1, Ordering::Relaxed);
+        }
+        // most of them are back-references
+        TraceLowLevelEvent::Value(FullValueRecord { value, .. }) if *value == large_value => {
+            values.fetch_add(1, Ordering::Relaxed);
+        }
+        _ => {}
+    })
+    .unwrap();
+    assert_eq!(steps.into_inner(), 10_000);
+    assert_eq!(values.into_inner(), 10_000);
+
+    // an error stops the decoding instead of waiting for the broken frame
+    let mut corrupted = trace.clone();
+    let middle = corrupted.len() / 2;
+    corrupted[middle..middle + 64].fill(0xff);
+    assert!(read_trace_parallel(&corrupted, None).is_err());
+    assert!(for_each_event_parallel(&corrupted, None, |_| {}).is_err());
+}
+
+#[test]
+fn test_binary_roundtrip_compression_options() {
+    let options = TraceWriterOptions {
+        compression_level: 19,
+        frame_size: 1024,
+        workers: 2,
+        ..Default::default()
+    };
+    test_binary_roundtrip_with_options(options);
+}
+
+#[test]
+fn test_binary_roundtrip_dictionary() {
+    let dictionary = train_dictionary(&vec![load_json_trace(); 64], 4096).unwrap();
+
+    let options = TraceWriterOptions {
+        dictionary: Some(dictionary),
+        ..Default::default()
+    };
+    test_binary_roundtrip_with_options(options);
+}
+
+#[test]
+fn test_binary_roundtrip_background() {
+    for backpressure in [Backpressure::Block, Backpressure::Grow] {
+        let options = TraceWriterOptions {
+            background: Some(BackgroundEncoding {
+                queue_capacity: 4,
+                backpressure,
+            }),
+            ..Default::default()
+        };
+        test_binary_roundtrip_with_options(options);
+    }
+}
+
+#[test]
+fn test_background_drop_with_marker() {
+    let mut original = vec![
+        TraceLowLevelEvent::Path("foo.rs".into()),
+        TraceLowLevelEvent::VariableName("a".to_string()),
+    ];
+    for i in 0..20_000 {
+        original.push(TraceLowLevelEvent::Step(StepRecord {
+            path_id: PathId(0),
+            line: Line(i % 100),
+        }));
+        original.push(TraceLowLevelEvent::Value(FullValueRecord {
+            variable_id: VariableId(0),
+            value: ValueRecord::Int { i, type_id: TypeId(0) },
+        }));
+        if i % 1000 == 0 {
+            original.push(TraceLowLevelEvent::VariableName(format!("v{i}")));
+        }
+    }
+
+    let options = TraceWriterOptions {
+        background: Some(BackgroundEncoding {
+            queue_capacity: 1,
+            backpressure: Backpressure::DropWithMarker,
+        }),
+        ..Default::default()
+    };
+    let trace = write_trace(codetracer_trace_writer::TraceEventsFileFormat::Binary, options, &original);
+
+    let mut bin_reader = create_trace_reader(codetracer_trace_reader::TraceEventsFileFormat::Binary);
+    let events = bin_reader.load_trace_events_from_slice(&trace).unwrap();
+
+    let is_droppable = |event: &TraceLowLevelEvent| matches!(event, TraceLowLevelEvent::Step(_) | TraceLowLevelEvent::Value(_));
+    let dropped: u64 = events
+        .iter()
+        .map(|event| match event {
+            TraceLowLevelEvent::DroppedEvents(count) => *count,
+            _ => 0,
+        })
+        .sum();
+    let kept = events.iter().filter(|event| is_droppable(event)).count() as u64;
+    assert_eq!(kept + dropped, original.iter().filter(|event| is_droppable(event)).count() as u64);
+
+    // interning events are never dropped, and stay in order
+    let undroppable = |events: &[TraceLowLevelEvent]| {
+        events
+            .iter()
+            .filter(|event| !is_droppable(event) && !matches!(event, TraceLowLevelEvent::DroppedEvents(_)))
+            .map(|event| serde_json::to_string(event).unwrap())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(undroppable(&events), undroppable(&original));
 }
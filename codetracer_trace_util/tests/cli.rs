@@ -0,0 +1,63 @@
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::str;
+
+use codetracer_trace_reader::{TraceEventsFileFormat, create_trace_reader};
+
+const CLI: &str = env!("CARGO_BIN_EXE_codetracer_trace_util");
+
+/// Runs the CLI with `input` on stdin and returns its stdout
+fn run_piped(args: &[&str], input: &[u8]) -> Vec<u8> {
+    let mut child = Command::new(CLI).args(args).stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().unwrap();
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "{args:?} failed");
+    output.stdout
+}
+
+#[test]
+fn test_convert_and_print_through_pipes() {
+    let json = std::fs::read("tests/data/trace.json").unwrap();
+    // compared after a roundtrip through the types, which normalizes e.g. the spelling of floats
+    let events = create_trace_reader(TraceEventsFileFormat::Json)
+        .load_trace_events(Path::new("tests/data/trace.json"))
+        .unwrap();
+    let events: Vec<serde_json::Value> = events.iter().map(|event| serde_json::to_value(event).unwrap()).collect();
+
+    let binary = run_piped(&["convert", "-", "-", "--output-format", "binary"], &json);
+    assert!(binary.starts_with(&[0xC0, 0xDE, 0x72, 0xAC, 0xE2]));
+
+    // the format of stdin is detected from its contents
+    let printed = run_piped(&["print", "-"], &binary);
+    let printed: Vec<serde_json::Value> = String::from_utf8(printed)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(printed, events);
+
+    let converted = run_piped(&["convert", "-", "-"], &binary);
+    let converted: Vec<serde_json::Value> = serde_json::from_slice(&converted).unwrap();
+    assert_eq!(converted, events);
+}
+
+#[test]
+fn test_convert_into_a_closed_pipe() {
+    let json = std::fs::read("tests/data/trace.json").unwrap();
+    for format in ["json", "binary", "binary-v0"] {
+        let mut child = Command::new(CLI)
+            .args(["convert", "-", "-", "--output-format", format])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        // the reader goes away before the trace is written, like `head` does
+        drop(child.stdout.take());
+        child.stdin.take().unwrap().write_all(&json).unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success(), "{format}: {}", str::from_utf8(&output.stderr).unwrap());
+        assert!(output.stderr.is_empty());
+    }
+}
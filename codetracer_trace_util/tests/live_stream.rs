@@ -0,0 +1,176 @@
+use std::net::TcpListener;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use std::{env, fs, process, thread};
+
+use codetracer_trace_reader::{TraceEventsFileFormat, TraceStreamReader, create_trace_reader};
+use codetracer_trace_types::{Line, NONE_VALUE, TraceLowLevelEvent, TraceMetadata};
+use codetracer_trace_writer::{SocketTraceWriter, TraceBuffer, TraceStreamAddress, create_trace_writer, trace_writer::TraceWriter};
+
+fn record_program(writer: &mut dyn TraceWriter) {
+    let path = Path::new("/test/path.small");
+    TraceWriter::start(writer, path, Line(1));
+    let function_id = TraceWriter::ensure_function_id(writer, "function", path, Line(3));
+    for line in 1..200 {
+        TraceWriter::register_step(writer, path, Line(line));
+        let arg = TraceWriter::arg(writer, "a", NONE_VALUE);
+        TraceWriter::register_call(writer, function_id, vec![arg]);
+        TraceWriter::register_return(writer, NONE_VALUE);
+    }
+}
+
+/// Streams a small trace to `address` and returns its events
+fn record(address: &TraceStreamAddress) -> Vec<TraceLowLevelEvent> {
+    let mut writer = SocketTraceWriter::new("program.small", &["arg".to_string()]);
+    // a few events per batch, so there are many of them
+    writer.batch_size = 64;
+    TraceWriter::set_language(&mut writer, "small", "1.0");
+    writer.begin_writing_trace_events_to(address.connect().unwrap()).unwrap();
+    record_program(&mut writer);
+    TraceWriter::set_exit_code(&mut writer, 3);
+    writer.finish_writing_trace_events().unwrap();
+
+    let buffer = TraceBuffer::new();
+    let mut json_writer = create_trace_writer("program.small", &[], codetracer_trace_writer::TraceEventsFileFormat::Json);
+    json_writer.begin_writing_trace_events_to(Box::new(buffer.clone())).unwrap();
+    record_program(json_writer.as_mut());
+    json_writer.finish_writing_trace_events().unwrap();
+    serde_json::from_slice(&buffer.contents()).unwrap()
+}
+
+#[test]
+fn test_stream_over_tcp() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = TraceStreamAddress::Tcp(listener.local_addr().unwrap().to_string());
+
+    let receiver = thread::spawn(move || {
+        let mut reader = TraceStreamReader::new(listener.accept().unwrap().0).unwrap();
+        let initial_metadata = reader.metadata().clone();
+        let mut events = vec![];
+        let mut batches = 0;
+        while let Some(batch) = reader.next_batch().unwrap() {
+            events.extend(batch);
+            batches += 1;
+        }
+        assert!(reader.is_finished());
+        assert!(batches > 10);
+        (initial_metadata, reader.metadata().clone(), events)
+    });
+    let expected = record(&address);
+    let (initial_metadata, metadata, events): (TraceMetadata, TraceMetadata, _) = receiver.join().unwrap();
+
+    assert_eq!(initial_metadata.program, "program.small");
+    assert_eq!(initial_metadata.language.unwrap().name, "small");
+    assert_eq!(initial_metadata.exit_code, None);
+    assert_eq!(metadata.exit_code, Some(3));
+    assert_eq!(serde_json::to_string(&events).unwrap(), serde_json::to_string(&expected).unwrap());
+}
+
+#[test]
+fn test_stream_ended_early() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = TraceStreamAddress::Tcp(listener.local_addr().unwrap().to_string());
+
+    let receiver = thread::spawn(move || {
+        let mut reader = TraceStreamReader::new(listener.accept().unwrap().0).unwrap();
+        let mut events = 0;
+        let result = loop {
+            match reader.next_batch() {
+                Ok(Some(batch)) => events += batch.len(),
+                Ok(None) => break Ok(()),
+                Err(err) => break Err(err.to_string()),
+            }
+        };
+        (events, result)
+    });
+
+    let mut writer = SocketTraceWriter::new("program.small", &[]);
+    writer.batch_size = 1;
+    writer.begin_writing_trace_events_to(address.connect().unwrap()).unwrap();
+    TraceWriter::start(&mut writer, Path::new("/test/path.small"), Line(1));
+    // the program crashes: the writer is never finished
+    drop(writer);
+
+    let (events, result) = receiver.join().unwrap();
+    assert!(events > 0);
+    assert!(result.is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_record_command() {
+    let dir = env::temp_dir().join(format!("test_record_command_{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let socket = dir.join("trace.sock");
+    let output_dir = dir.join("trace");
+
+    let mut receiver = process::Command::new(env!("CARGO_BIN_EXE_codetracer_trace_util"))
+        .args(["record", &format!("unix:{}", socket.display()), output_dir.to_str().unwrap()])
+        .spawn()
+        .unwrap();
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while !socket.exists() {
+        if let Some(status) = receiver.try_wait().unwrap() {
+            panic!("the receiver exited before listening: {status}");
+        }
+        if Instant::now() > deadline {
+            receiver.kill().unwrap();
+            panic!("the receiver didn't listen on {} in time", socket.display());
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    let expected = record(&TraceStreamAddress::Unix(socket.clone()));
+    assert!(receiver.wait().unwrap().success());
+
+    let mut bin_reader = create_trace_reader(TraceEventsFileFormat::Binary);
+    let events = bin_reader.load_trace_events(&output_dir.join("trace.bin")).unwrap();
+    assert_eq!(serde_json::to_string(&events).unwrap(), serde_json::to_string(&expected).unwrap());
+    let metadata: TraceMetadata = serde_json::from_slice(&fs::read(output_dir.join("trace_metadata.json")).unwrap()).unwrap();
+    assert_eq!(metadata.exit_code, Some(3));
+    let paths: Vec<String> = serde_json::from_slice(&fs::read(output_dir.join("trace_paths.json")).unwrap()).unwrap();
+    assert_eq!(paths, ["/test/path.small"]);
+
+    // the socket file is left behind, as if the receiver had crashed
+    let output = process::Command::new(env!("CARGO_BIN_EXE_codetracer_trace_util"))
+        .args(["record", &format!("unix:{}", socket.display()), output_dir.to_str().unwrap()])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr).unwrap().contains("already exists"));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_serve_command() {
+    let dir = env::temp_dir().join(format!("test_serve_command_{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let socket = dir.join("trace.sock");
+    let output_dir = dir.join("traces");
+    // a trace received by an earlier run
+    fs::create_dir_all(output_dir.join("trace-0")).unwrap();
+    fs::write(output_dir.join("trace-0").join("trace.bin"), "earlier").unwrap();
+
+    let mut receiver = process::Command::new(env!("CARGO_BIN_EXE_codetracer_trace_util"))
+        .args(["serve", &format!("unix:{}", socket.display()), output_dir.to_str().unwrap()])
+        .spawn()
+        .unwrap();
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while !socket.exists() && Instant::now() < deadline && receiver.try_wait().unwrap().is_none() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    record(&TraceStreamAddress::Unix(socket.clone()));
+    let metadata_path = output_dir.join("trace-1").join("trace_metadata.json");
+    while !metadata_path.exists() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    receiver.kill().unwrap();
+    receiver.wait().unwrap();
+
+    assert!(metadata_path.exists());
+    assert_eq!(fs::read_to_string(output_dir.join("trace-0").join("trace.bin")).unwrap(), "earlier");
+
+    fs::remove_dir_all(&dir).unwrap();
+}
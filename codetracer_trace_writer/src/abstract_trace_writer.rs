@@ -1,34 +1,82 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     env,
     error::Error,
-    fs,
+    io::Write,
     path::{Path, PathBuf},
+    sync::OnceLock,
 };
 
+use crate::metadata;
+use crate::trace_output::TraceOutput;
+use crate::value_limits::ValueLimits;
 use codetracer_trace_types::{
     AssignCellRecord, AssignCompoundItemRecord, AssignmentRecord, BindVariableRecord, CallRecord, CellValueRecord, CompoundValueRecord, EventLogKind,
-    FullValueRecord, FunctionId, FunctionRecord, Line, NONE_TYPE_ID, PassBy, PathId, Place, RValue, RecordEvent, ReturnRecord, StepRecord,
-    TOP_LEVEL_FUNCTION_ID, ThreadId, TraceLowLevelEvent, TraceMetadata, TypeId, TypeKind, TypeRecord, TypeSpecificInfo, ValueRecord,
-    VariableCellRecord, VariableId,
+    FullValueRecord, FunctionId, FunctionRecord, GitInfo, LanguageInfo, Line, NONE_TYPE_ID, PassBy, PathId, Place, RValue, RecordEvent, RecorderInfo,
+    ReturnRecord, StepRecord, TOP_LEVEL_FUNCTION_ID, TRACE_FORMAT_VERSION, ThreadId, TraceLowLevelEvent, TraceMetadata, TypeId, TypeKind, TypeRecord,
+    TypeSpecificInfo, ValueRecord, VariableCellRecord, VariableId,
 };
 
+/// When the writer emits `Timestamp` events
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampMode {
+    /// No automatic timestamps; `register_timestamp` can still be called explicitly
+    #[default]
+    Disabled,
+    /// Before every step
+    EveryStep,
+    /// Before the first step after at least `interval_ns` nanoseconds passed since the last timestamp
+    Periodic { interval_ns: u64 },
+}
+
+/// What identifies a function when interning it with `ensure_function_id`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum FunctionKey {
+    /// Functions with the same name defined in different places are different functions
+    Location { name: String, path_id: PathId, line: Line },
+    /// A key chosen by the recorder, e.g. a qualified name or a code object id
+    Custom(String),
+}
+
 pub struct AbstractTraceWriterData {
     // trace metadata:
     pub workdir: PathBuf,
     pub program: String,
     pub args: Vec<String>,
+    pub start_time: Option<u64>,
+    pub environment: BTreeMap<String, String>,
+    pub language: Option<LanguageInfo>,
+    pub recorder: Option<RecorderInfo>,
+    pub exit_code: Option<i32>,
+    pub extensions: BTreeMap<String, serde_json::Value>,
     // internal tracer state:
     pub path_list: Vec<PathBuf>,
     pub function_list: Vec<(String, PathId, Line)>,
 
     pub paths: HashMap<PathBuf, PathId>,
-    pub functions: HashMap<String, FunctionId>,
+    pub functions: HashMap<FunctionKey, FunctionId>,
     pub variables: HashMap<String, VariableId>,
-    pub types: HashMap<String, TypeId>,
-
-    pub trace_metadata_path: Option<PathBuf>,
-    pub trace_paths_path: Option<PathBuf>,
+    // the ids are positions in the stream of `VariableName` events, which can repeat a name
+    // (e.g. in appended traces), so they aren't the size of `variables`
+    pub variable_count: usize,
+    // keyed by the whole record: types with the same name, but a different kind or structure
+    // (e.g. instantiations of a generic) get different ids
+    pub types: HashMap<TypeRecord, TypeId>,
+    // like `variable_count`: an appended stream can repeat a type record
+    pub type_count: usize,
+
+    pub trace_metadata_output: Option<TraceOutput>,
+    pub trace_paths_output: Option<TraceOutput>,
+
+    pub value_limits: ValueLimits,
+
+    pub timestamp_mode: TimestampMode,
+    pub last_timestamp: Option<u64>,
+    // started together with `start_time`, so timestamps can be mapped to wall-clock time
+    clock: metadata::MonotonicClock,
+    // the hostname and the git state of `workdir`, probed the first time the metadata is needed:
+    // running `git` can take seconds in a large work tree
+    host_info: OnceLock<(Option<String>, Option<GitInfo>)>,
 }
 
 impl AbstractTraceWriterData {
@@ -37,16 +85,31 @@ impl AbstractTraceWriterData {
             workdir: env::current_dir().expect("can access the current dir"),
             program: program.to_string(),
             args: args.to_vec(),
+            start_time: metadata::now_unix_ms(),
+            environment: BTreeMap::new(),
+            language: None,
+            recorder: None,
+            exit_code: None,
+            extensions: BTreeMap::new(),
 
             path_list: vec![],
             function_list: vec![],
             paths: HashMap::new(),
             functions: HashMap::new(),
             variables: HashMap::new(),
+            variable_count: 0,
             types: HashMap::new(),
+            type_count: 0,
+
+            trace_metadata_output: None,
+            trace_paths_output: None,
 
-            trace_metadata_path: None,
-            trace_paths_path: None,
+            value_limits: ValueLimits::default(),
+
+            timestamp_mode: TimestampMode::Disabled,
+            last_timestamp: None,
+            clock: metadata::MonotonicClock::start(),
+            host_info: OnceLock::new(),
         }
     }
 }
@@ -59,15 +122,95 @@ pub trait AbstractTraceWriter {
     fn append_events(&mut self, events: &mut Vec<TraceLowLevelEvent>);
 
     fn begin_writing_trace_metadata(&mut self, path: &Path) -> Result<(), Box<dyn Error>> {
-        self.get_mut_data().trace_metadata_path = Some(path.to_path_buf());
+        self.get_mut_data().trace_metadata_output = Some(TraceOutput::File(path.to_path_buf()));
+        Ok(())
+    }
+
+    fn begin_writing_trace_metadata_to(&mut self, output: Box<dyn Write + Send>) -> Result<(), Box<dyn Error>> {
+        self.get_mut_data().trace_metadata_output = Some(TraceOutput::Writer(output));
         Ok(())
     }
 
     fn begin_writing_trace_paths(&mut self, path: &Path) -> Result<(), Box<dyn Error>> {
-        self.get_mut_data().trace_paths_path = Some(path.to_path_buf());
+        self.get_mut_data().trace_paths_output = Some(TraceOutput::File(path.to_path_buf()));
         Ok(())
     }
 
+    fn begin_writing_trace_paths_to(&mut self, output: Box<dyn Write + Send>) -> Result<(), Box<dyn Error>> {
+        self.get_mut_data().trace_paths_output = Some(TraceOutput::Writer(output));
+        Ok(())
+    }
+
+    fn set_language(&mut self, name: &str, version: &str) {
+        self.get_mut_data().language = Some(LanguageInfo {
+            name: name.to_string(),
+            version: version.to_string(),
+        });
+    }
+
+    /// Usually called with `env!("CARGO_PKG_NAME")` and `env!("CARGO_PKG_VERSION")` of the recorder
+    fn set_recorder(&mut self, name: &str, version: &str) {
+        self.get_mut_data().recorder = Some(RecorderInfo {
+            name: name.to_string(),
+            version: version.to_string(),
+        });
+    }
+
+    fn set_exit_code(&mut self, exit_code: i32) {
+        self.get_mut_data().exit_code = Some(exit_code);
+    }
+
+    /// Stores the current values of the given environment variables in the metadata;
+    /// unset variables are skipped
+    fn record_environment_variables(&mut self, names: &[&str]) {
+        for name in names {
+            if let Ok(value) = env::var(name) {
+                self.get_mut_data().environment.insert(name.to_string(), value);
+            }
+        }
+    }
+
+    fn set_metadata_extension(&mut self, key: &str, value: serde_json::Value) {
+        self.get_mut_data().extensions.insert(key.to_string(), value);
+    }
+
+    fn set_timestamp_mode(&mut self, mode: TimestampMode) {
+        self.get_mut_data().timestamp_mode = mode;
+    }
+
+    /// Emits a `Timestamp` event with the current time; does nothing where no monotonic clock is available
+    fn register_timestamp(&mut self) {
+        if let Some(nanoseconds) = self.get_data().clock.elapsed_ns() {
+            self.get_mut_data().last_timestamp = Some(nanoseconds);
+            self.add_event(TraceLowLevelEvent::Timestamp(nanoseconds));
+        }
+    }
+
+    /// Limits applied to all values passed to the writer from now on
+    fn set_value_limits(&mut self, limits: ValueLimits) {
+        self.get_mut_data().value_limits = limits;
+    }
+
+    fn limit_value(&self, value: ValueRecord) -> ValueRecord {
+        self.get_data().value_limits.apply(value)
+    }
+
+    /// A `Bytes` value, truncated to `max_bytes_length` of the value limits if it is set
+    fn bytes_value(&self, data: &[u8], type_id: TypeId) -> ValueRecord {
+        match self.get_data().value_limits.max_bytes_length {
+            Some(max_bytes_length) if data.len() > max_bytes_length => ValueRecord::Bytes {
+                data: data[..max_bytes_length].to_vec(),
+                original_length: Some(data.len() as u64),
+                type_id,
+            },
+            _ => ValueRecord::Bytes {
+                data: data.to_vec(),
+                original_length: None,
+                type_id,
+            },
+        }
+    }
+
     fn start(&mut self, path: &std::path::Path, line: Line) {
         let function_id = self.ensure_function_id("<toplevel>", path, line);
         self.register_call(function_id, vec![]);
@@ -85,20 +228,33 @@ pub trait AbstractTraceWriter {
     fn ensure_path_id(&mut self, path: &std::path::Path) -> PathId {
         if !self.get_data().paths.contains_key(path) {
             let mut_data = self.get_mut_data();
-            mut_data.paths.insert(path.to_path_buf(), PathId(mut_data.paths.len()));
+            mut_data.paths.insert(path.to_path_buf(), PathId(mut_data.path_list.len()));
             self.register_path(path);
         }
         *self.get_data().paths.get(path).unwrap()
     }
 
+    /// Interns a function by its name and location
     fn ensure_function_id(&mut self, function_name: &str, path: &std::path::Path, line: Line) -> FunctionId {
-        if !self.get_data().functions.contains_key(function_name) {
-            // same function names for different path line? TODO
-            let mut_data = self.get_mut_data();
-            mut_data.functions.insert(function_name.to_string(), FunctionId(mut_data.functions.len()));
-            self.register_function(function_name, path, line);
+        let path_id = self.ensure_path_id(path);
+        let key = FunctionKey::Location {
+            name: function_name.to_string(),
+            path_id,
+            line,
+        };
+        self.ensure_function_id_with_key(key, function_name, path, line)
+    }
+
+    /// Interns a function by a recorder-supplied key; `function_name`, `path` and `line` are
+    /// only used the first time the key is seen
+    fn ensure_function_id_with_key(&mut self, key: FunctionKey, function_name: &str, path: &std::path::Path, line: Line) -> FunctionId {
+        if let Some(function_id) = self.get_data().functions.get(&key) {
+            return *function_id;
         }
-        *self.get_data().functions.get(function_name).unwrap()
+        let function_id = FunctionId(self.get_data().function_list.len());
+        self.get_mut_data().functions.insert(key, function_id);
+        self.register_function(function_name, path, line);
+        function_id
     }
 
     fn ensure_type_id(&mut self, kind: TypeKind, lang_type: &str) -> TypeId {
@@ -107,23 +263,61 @@ pub trait AbstractTraceWriter {
     }
 
     fn ensure_raw_type_id(&mut self, typ: TypeRecord) -> TypeId {
-        if !self.get_data().types.contains_key(&typ.lang_type) {
-            let mut_data = self.get_mut_data();
-            mut_data.types.insert(typ.lang_type.clone(), TypeId(mut_data.types.len()));
-            self.register_raw_type(typ.clone());
+        if let Some(type_id) = self.get_data().types.get(&typ) {
+            return *type_id;
         }
-        *self.get_data().types.get(&typ.lang_type).unwrap()
+        let mut_data = self.get_mut_data();
+        let type_id = TypeId(mut_data.type_count);
+        mut_data.types.insert(typ.clone(), type_id);
+        mut_data.type_count += 1;
+        self.register_raw_type(typ);
+        type_id
     }
 
     fn ensure_variable_id(&mut self, variable_name: &str) -> VariableId {
         if !self.get_data().variables.contains_key(variable_name) {
             let mut_data = self.get_mut_data();
-            mut_data.variables.insert(variable_name.to_string(), VariableId(mut_data.variables.len()));
+            mut_data.variables.insert(variable_name.to_string(), VariableId(mut_data.variable_count));
+            mut_data.variable_count += 1;
             self.register_variable_name(variable_name);
         }
         *self.get_data().variables.get(variable_name).unwrap()
     }
 
+    /// Updates the interning tables for an event produced elsewhere (e.g. read from another trace),
+    /// so that `ensure_*_id` calls after `append_events` agree with the ids in the appended events
+    fn update_interning_tables(&mut self, event: &TraceLowLevelEvent) {
+        let data = self.get_mut_data();
+        match event {
+            TraceLowLevelEvent::Path(path) => {
+                let path_id = PathId(data.path_list.len());
+                data.paths.entry(path.clone()).or_insert(path_id);
+                data.path_list.push(path.clone());
+            }
+            TraceLowLevelEvent::Function(function) => {
+                let function_id = FunctionId(data.function_list.len());
+                let key = FunctionKey::Location {
+                    name: function.name.clone(),
+                    path_id: function.path_id,
+                    line: function.line,
+                };
+                data.functions.entry(key).or_insert(function_id);
+                data.function_list.push((function.name.clone(), function.path_id, function.line));
+            }
+            TraceLowLevelEvent::VariableName(name) | TraceLowLevelEvent::Variable(name) => {
+                let variable_id = VariableId(data.variable_count);
+                data.variables.entry(name.clone()).or_insert(variable_id);
+                data.variable_count += 1;
+            }
+            TraceLowLevelEvent::Type(typ) => {
+                let type_id = TypeId(data.type_count);
+                data.types.entry(typ.clone()).or_insert(type_id);
+                data.type_count += 1;
+            }
+            _ => {}
+        }
+    }
+
     fn register_path(&mut self, path: &std::path::Path) {
         self.get_mut_data().path_list.push(path.to_path_buf());
         self.add_event(TraceLowLevelEvent::Path(path.to_path_buf()));
@@ -141,6 +335,20 @@ pub trait AbstractTraceWriter {
 
     fn register_step(&mut self, path: &std::path::Path, line: Line) {
         let path_id = self.ensure_path_id(path);
+        match self.get_data().timestamp_mode {
+            TimestampMode::Disabled => {}
+            TimestampMode::EveryStep => self.register_timestamp(),
+            TimestampMode::Periodic { interval_ns } => {
+                let data = self.get_data();
+                let due = match (data.last_timestamp, data.clock.elapsed_ns()) {
+                    (Some(last), Some(now)) => now.saturating_sub(last) >= interval_ns,
+                    _ => true,
+                };
+                if due {
+                    self.register_timestamp();
+                }
+            }
+        }
         self.add_event(TraceLowLevelEvent::Step(StepRecord { path_id, line }));
     }
 
@@ -164,10 +372,12 @@ pub trait AbstractTraceWriter {
 
     fn arg(&mut self, name: &str, value: ValueRecord) -> FullValueRecord {
         let variable_id = self.ensure_variable_id(name);
+        let value = self.limit_value(value);
         FullValueRecord { variable_id, value }
     }
 
     fn register_return(&mut self, return_value: ValueRecord) {
+        let return_value = self.limit_value(return_value);
         self.add_event(TraceLowLevelEvent::Return(ReturnRecord { return_value }));
     }
 
@@ -210,14 +420,17 @@ pub trait AbstractTraceWriter {
     }
 
     fn register_full_value(&mut self, variable_id: VariableId, value: ValueRecord) {
+        let value = self.limit_value(value);
         self.add_event(TraceLowLevelEvent::Value(FullValueRecord { variable_id, value }));
     }
 
     fn register_compound_value(&mut self, place: Place, value: ValueRecord) {
+        let value = self.limit_value(value);
         self.add_event(TraceLowLevelEvent::CompoundValue(CompoundValueRecord { place, value }));
     }
 
     fn register_cell_value(&mut self, place: Place, value: ValueRecord) {
+        let value = self.limit_value(value);
         self.add_event(TraceLowLevelEvent::CellValue(CellValueRecord { place, value }));
     }
 
@@ -230,6 +443,7 @@ pub trait AbstractTraceWriter {
     }
 
     fn assign_cell(&mut self, place: Place, new_value: ValueRecord) {
+        let new_value = self.limit_value(new_value);
         self.add_event(TraceLowLevelEvent::AssignCell(AssignCellRecord { place, new_value }));
     }
 
@@ -240,7 +454,8 @@ pub trait AbstractTraceWriter {
 
     fn drop_variable(&mut self, variable_name: &str) {
         let variable_id = self.ensure_variable_id(variable_name);
-        self.add_event(TraceLowLevelEvent::DropVariable(variable_id));
+        // `DropVariable` is deprecated, a single-element `DropVariables` means the same
+        self.add_event(TraceLowLevelEvent::DropVariables(vec![variable_id]));
     }
 
     // history event helpers
@@ -297,15 +512,34 @@ pub trait AbstractTraceWriter {
         self.add_event(TraceLowLevelEvent::DropLastStep);
     }
 
+    /// The metadata of the trace as it is now; `end_time` is the current time, while the hostname
+    /// and the git state are only probed by the first call
+    fn trace_metadata(&self) -> TraceMetadata {
+        let data = self.get_data();
+        let (hostname, git) = data.host_info.get_or_init(|| (metadata::hostname(), metadata::git_info(&data.workdir)));
+        TraceMetadata {
+            program: data.program.clone(),
+            args: data.args.clone(),
+            workdir: data.workdir.clone(),
+            format_version: TRACE_FORMAT_VERSION,
+            start_time: data.start_time,
+            end_time: metadata::now_unix_ms(),
+            hostname: hostname.clone(),
+            environment: data.environment.clone(),
+            language: data.language.clone(),
+            recorder: data.recorder.clone(),
+            git: git.clone(),
+            exit_code: data.exit_code,
+            extensions: data.extensions.clone(),
+        }
+    }
+
     fn finish_writing_trace_metadata(&mut self) -> Result<(), Box<dyn Error>> {
-        if let Some(path) = &self.get_data().trace_metadata_path {
-            let trace_metadata = TraceMetadata {
-                program: self.get_data().program.clone(),
-                args: self.get_data().args.clone(),
-                workdir: self.get_data().workdir.clone(),
-            };
-            let json = serde_json::to_string(&trace_metadata)?;
-            fs::write(path, json)?;
+        if self.get_data().trace_metadata_output.is_some() {
+            let json = serde_json::to_string(&self.trace_metadata())?;
+            if let Some(output) = &mut self.get_mut_data().trace_metadata_output {
+                output.write_all(json.as_bytes())?;
+            }
             Ok(())
         } else {
             panic!("finish_writing_trace_metadata() called without previous call to begin_writing_trace_metadata()");
@@ -313,9 +547,11 @@ pub trait AbstractTraceWriter {
     }
 
     fn finish_writing_trace_paths(&mut self) -> Result<(), Box<dyn Error>> {
-        if let Some(path) = &self.get_data().trace_paths_path {
+        if self.get_data().trace_paths_output.is_some() {
             let json = serde_json::to_string(&self.get_data().path_list)?;
-            fs::write(path, json)?;
+            if let Some(output) = &mut self.get_mut_data().trace_paths_output {
+                output.write_all(json.as_bytes())?;
+            }
             Ok(())
         } else {
             panic!("finish_writing_trace_paths() called without previous call to begin_writing_trace_paths()");
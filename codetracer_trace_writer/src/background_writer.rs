@@ -0,0 +1,212 @@
+use std::{
+    error::Error,
+    io::Write,
+    sync::mpsc::{self, Receiver, Sender, SyncSender, TrySendError},
+    thread::{self, JoinHandle},
+};
+
+use codetracer_trace_format_cbor_zstd::wire::WireEncoder;
+use codetracer_trace_types::TraceLowLevelEvent;
+
+use crate::{
+    BackgroundEncoding, Backpressure, TraceWriterOptions,
+    abstract_trace_writer::{AbstractTraceWriter, AbstractTraceWriterData},
+    cbor_zstd_writer::{FrameEncoder, create_encoder},
+    trace_writer::TraceWriter,
+};
+
+type EncoderResult = Result<(), Box<dyn Error + Send + Sync>>;
+
+enum QueueSender {
+    Bounded(SyncSender<TraceLowLevelEvent>),
+    Unbounded(Sender<TraceLowLevelEvent>),
+}
+
+impl QueueSender {
+    /// Waits for room in a bounded queue. A send only fails if the encoder thread stopped, and
+    /// `finish_writing_trace_events` reports why, so the event is discarded.
+    fn send(&self, event: TraceLowLevelEvent) {
+        let _ = match self {
+            QueueSender::Bounded(sender) => sender.send(event),
+            QueueSender::Unbounded(sender) => sender.send(event),
+        };
+    }
+
+    /// Returns the event back if the queue is full
+    fn try_send(&self, event: TraceLowLevelEvent) -> Result<(), TraceLowLevelEvent> {
+        match self {
+            QueueSender::Bounded(sender) => match sender.try_send(event) {
+                Err(TrySendError::Full(event)) => Err(event),
+                _ => Ok(()),
+            },
+            QueueSender::Unbounded(sender) => {
+                let _ = sender.send(event);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Whether later events can't refer to `event`, so it can be dropped under backpressure
+fn is_droppable(event: &TraceLowLevelEvent) -> bool {
+    matches!(
+        event,
+        TraceLowLevelEvent::Step(_)
+            | TraceLowLevelEvent::Value(_)
+            | TraceLowLevelEvent::Event(_)
+            | TraceLowLevelEvent::Asm(_)
+            | TraceLowLevelEvent::Timestamp(_)
+    )
+}
+
+fn encode_events(receiver: Receiver<TraceLowLevelEvent>, mut encoder: FrameEncoder<'static>) -> EncoderResult {
+    let mut wire_encoder = WireEncoder::new();
+    let mut buf = Vec::new();
+    for event in receiver {
+        buf.clear();
+        wire_encoder.encode(&event, &mut buf);
+        encoder.write_item(&buf)?;
+    }
+    encoder.finish()?;
+    Ok(())
+}
+
+/// Like `CborZstdTraceWriter`, but the recording thread only queues the events: they are encoded
+/// and compressed on a dedicated thread
+pub struct BackgroundCborZstdTraceWriter {
+    base: AbstractTraceWriterData,
+
+    options: TraceWriterOptions,
+    background: BackgroundEncoding,
+    sender: Option<QueueSender>,
+    encoder_thread: Option<JoinHandle<EncoderResult>>,
+    // events dropped since the last `DroppedEvents` marker
+    dropped_events: u64,
+    last_step_dropped: bool,
+}
+
+impl BackgroundCborZstdTraceWriter {
+    /// Create a new tracer instance for the given program and arguments.
+    pub fn new(program: &str, args: &[String], options: TraceWriterOptions, background: BackgroundEncoding) -> Self {
+        BackgroundCborZstdTraceWriter {
+            base: AbstractTraceWriterData::new(program, args),
+
+            options,
+            background,
+            sender: None,
+            encoder_thread: None,
+            dropped_events: 0,
+            last_step_dropped: false,
+        }
+    }
+
+    fn drop_event(&mut self, event: &TraceLowLevelEvent) {
+        self.dropped_events += 1;
+        if matches!(event, TraceLowLevelEvent::Step(_)) {
+            self.last_step_dropped = true;
+        }
+    }
+
+    fn queue_event(&mut self, event: TraceLowLevelEvent) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        if self.background.backpressure != Backpressure::DropWithMarker {
+            sender.send(event);
+            return;
+        }
+
+        match &event {
+            TraceLowLevelEvent::Step(_) => self.last_step_dropped = false,
+            // it would remove the step before the dropped one
+            TraceLowLevelEvent::DropLastStep if self.last_step_dropped => {
+                self.dropped_events += 1;
+                return;
+            }
+            _ => {}
+        }
+
+        if is_droppable(&event) {
+            if self.dropped_events > 0 {
+                if sender.try_send(TraceLowLevelEvent::DroppedEvents(self.dropped_events)).is_err() {
+                    self.drop_event(&event);
+                    return;
+                }
+                self.dropped_events = 0;
+            }
+            if let Err(event) = sender.try_send(event) {
+                self.drop_event(&event);
+            }
+        } else {
+            if self.dropped_events > 0 {
+                sender.send(TraceLowLevelEvent::DroppedEvents(self.dropped_events));
+                self.dropped_events = 0;
+            }
+            sender.send(event);
+        }
+    }
+}
+
+impl AbstractTraceWriter for BackgroundCborZstdTraceWriter {
+    fn get_data(&self) -> &AbstractTraceWriterData {
+        &self.base
+    }
+
+    fn get_mut_data(&mut self) -> &mut AbstractTraceWriterData {
+        &mut self.base
+    }
+
+    fn add_event(&mut self, event: TraceLowLevelEvent) {
+        self.queue_event(event);
+    }
+
+    fn append_events(&mut self, events: &mut Vec<TraceLowLevelEvent>) {
+        for e in events.drain(..) {
+            self.update_interning_tables(&e);
+            self.queue_event(e);
+        }
+    }
+}
+
+impl TraceWriter for BackgroundCborZstdTraceWriter {
+    fn begin_writing_trace_events_to(&mut self, output: Box<dyn Write + Send>) -> Result<(), Box<dyn Error>> {
+        let encoder = create_encoder(output, &self.options)?;
+
+        let (sender, receiver) = match self.background.backpressure {
+            Backpressure::Grow => {
+                let (sender, receiver) = mpsc::channel();
+                (QueueSender::Unbounded(sender), receiver)
+            }
+            Backpressure::Block | Backpressure::DropWithMarker => {
+                let (sender, receiver) = mpsc::sync_channel(self.background.queue_capacity);
+                (QueueSender::Bounded(sender), receiver)
+            }
+        };
+        self.sender = Some(sender);
+        self.encoder_thread = Some(
+            thread::Builder::new()
+                .name("trace encoder".to_string())
+                .spawn(move || encode_events(receiver, encoder))?,
+        );
+
+        Ok(())
+    }
+
+    fn finish_writing_trace_events(&mut self) -> Result<(), Box<dyn Error>> {
+        let Some(encoder_thread) = self.encoder_thread.take() else {
+            panic!("finish_writing_trace_events() called without previous call to begin_writing_trace_events()");
+        };
+
+        if let Some(sender) = self.sender.take()
+            && self.dropped_events > 0
+        {
+            sender.send(TraceLowLevelEvent::DroppedEvents(self.dropped_events));
+            self.dropped_events = 0;
+        }
+        // the sender is dropped by now, so the encoder thread finishes once it drains the queue
+        match encoder_thread.join() {
+            Ok(result) => result.map_err(|err| -> Box<dyn Error> { err }),
+            Err(_) => Err("The trace encoder thread panicked".into()),
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use std::{
+    error::Error,
+    io::{BufWriter, Write},
+};
+
+use codetracer_trace_format_capnp::capnptrace::{DEFAULT_BATCH_SIZE, SEGMENTED_HEADER, write_event_batch};
+use codetracer_trace_types::TraceLowLevelEvent;
+
+use crate::{
+    abstract_trace_writer::{AbstractTraceWriter, AbstractTraceWriterData},
+    trace_writer::TraceWriter,
+};
+
+/// Streams a `BinaryV0` trace as a segmented Cap'n Proto file: events are collected into
+/// batches, and every full batch is written out as a separate message.
+pub struct CapnpTraceWriter {
+    base: AbstractTraceWriterData,
+
+    trace_events_file: Option<BufWriter<Box<dyn Write + Send>>>,
+    batch: Vec<TraceLowLevelEvent>,
+}
+
+impl CapnpTraceWriter {
+    /// Create a new tracer instance for the given program and arguments.
+    pub fn new(program: &str, args: &[String]) -> Self {
+        CapnpTraceWriter {
+            base: AbstractTraceWriterData::new(program, args),
+
+            trace_events_file: None,
+            batch: Vec::with_capacity(DEFAULT_BATCH_SIZE),
+        }
+    }
+
+    fn write_batch(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(file) = &mut self.trace_events_file
+            && !self.batch.is_empty()
+        {
+            write_event_batch(&self.batch, file)?;
+        }
+        self.batch.clear();
+        Ok(())
+    }
+}
+
+impl AbstractTraceWriter for CapnpTraceWriter {
+    fn get_data(&self) -> &AbstractTraceWriterData {
+        &self.base
+    }
+
+    fn get_mut_data(&mut self) -> &mut AbstractTraceWriterData {
+        &mut self.base
+    }
+
+    fn add_event(&mut self, event: TraceLowLevelEvent) {
+        self.batch.push(event);
+        if self.batch.len() >= DEFAULT_BATCH_SIZE {
+            self.write_batch().unwrap();
+        }
+    }
+
+    fn append_events(&mut self, events: &mut Vec<TraceLowLevelEvent>) {
+        for e in events.drain(..) {
+            self.update_interning_tables(&e);
+            <Self as AbstractTraceWriter>::add_event(self, e);
+        }
+    }
+}
+
+impl TraceWriter for CapnpTraceWriter {
+    fn begin_writing_trace_events_to(&mut self, output: Box<dyn Write + Send>) -> Result<(), Box<dyn Error>> {
+        let mut file_output = BufWriter::new(output);
+        file_output.write_all(SEGMENTED_HEADER)?;
+        self.trace_events_file = Some(file_output);
+
+        Ok(())
+    }
+
+    fn finish_writing_trace_events(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.trace_events_file.is_none() {
+            panic!("finish_writing_trace_events() called without previous call to begin_writing_trace_events()");
+        }
+        self.write_batch()?;
+        if let Some(mut file) = self.trace_events_file.take() {
+            file.flush()?;
+        }
+
+        Ok(())
+    }
+}
@@ -1,9 +1,11 @@
-use std::{fs::File, io::Write, path::PathBuf};
+use std::io::Write;
 
-use codetracer_trace_format_cbor_zstd::HEADERV1;
-use zeekstd::Encoder;
+use codetracer_trace_format_cbor_zstd::{HEADERV1, wire::WireEncoder};
+use zeekstd::{EncodeOptions, Encoder, FrameSizePolicy, SEEKABLE_MAX_FRAME_SIZE};
+use zstd_safe::{CCtx, CParameter};
 
 use crate::{
+    TraceWriterOptions,
     abstract_trace_writer::{AbstractTraceWriter, AbstractTraceWriterData},
     trace_writer::TraceWriter,
 };
@@ -12,22 +14,86 @@ use codetracer_trace_types::TraceLowLevelEvent;
 pub struct CborZstdTraceWriter<'a> {
     base: AbstractTraceWriterData,
 
-    trace_events_path: Option<PathBuf>,
-    trace_events_file_zstd_encoder: Option<Encoder<'a, File>>,
+    trace_events_file_zstd_encoder: Option<FrameEncoder<'a>>,
+    wire_encoder: WireEncoder,
+    options: TraceWriterOptions,
 }
 
 impl CborZstdTraceWriter<'_> {
     /// Create a new tracer instance for the given program and arguments.
-    pub fn new(program: &str, args: &[String]) -> Self {
+    pub fn new(program: &str, args: &[String], options: TraceWriterOptions) -> Self {
         CborZstdTraceWriter {
             base: AbstractTraceWriterData::new(program, args),
 
-            trace_events_path: None,
             trace_events_file_zstd_encoder: None,
+            wire_encoder: WireEncoder::new(),
+            options,
         }
     }
 }
 
+/// Compresses the item stream into seekable zstd frames which only end between items, so every
+/// frame can be decoded on its own
+pub(crate) struct FrameEncoder<'a> {
+    encoder: Encoder<'a, Box<dyn Write + Send>>,
+    frame_size: usize,
+    // uncompressed bytes in the current frame
+    frame_bytes: usize,
+}
+
+impl FrameEncoder<'_> {
+    pub(crate) fn write_item(&mut self, item: &[u8]) -> zeekstd::Result<()> {
+        // zeekstd would split the item at its own frame size limit
+        if self.frame_bytes > 0 && self.frame_bytes + item.len() > SEEKABLE_MAX_FRAME_SIZE {
+            self.end_frame()?;
+        }
+        self.encoder.write_all(item)?;
+        self.frame_bytes += item.len();
+        if self.frame_bytes >= self.frame_size {
+            self.end_frame()?;
+        }
+        Ok(())
+    }
+
+    fn end_frame(&mut self) -> zeekstd::Result<()> {
+        self.encoder.end_frame()?;
+        self.frame_bytes = 0;
+        Ok(())
+    }
+
+    pub(crate) fn finish(self) -> zeekstd::Result<()> {
+        self.encoder.finish()?;
+        Ok(())
+    }
+}
+
+/// Writes the header to `output` and returns a zstd encoder for the event stream after it
+pub(crate) fn create_encoder<'a>(
+    mut output: Box<dyn Write + Send>,
+    options: &TraceWriterOptions,
+) -> Result<FrameEncoder<'a>, Box<dyn std::error::Error>> {
+    let zstd_error = |code| zstd_safe::get_error_name(code).to_string();
+
+    let mut cctx = CCtx::try_create().ok_or("Unable to allocate a zstd compression context")?;
+    if options.workers > 0 {
+        cctx.set_parameter(CParameter::NbWorkers(options.workers)).map_err(zstd_error)?;
+    }
+    if let Some(dictionary) = &options.dictionary {
+        cctx.load_dictionary(dictionary).map_err(zstd_error)?;
+    }
+    // frames are ended by `FrameEncoder`, at item boundaries
+    let encode_options = EncodeOptions::with_cctx(cctx)
+        .compression_level(options.compression_level)
+        .frame_size_policy(FrameSizePolicy::Uncompressed(SEEKABLE_MAX_FRAME_SIZE as u32));
+
+    output.write_all(HEADERV1)?;
+    Ok(FrameEncoder {
+        encoder: Encoder::with_opts(output, encode_options)?,
+        frame_size: options.frame_size as usize,
+        frame_bytes: 0,
+    })
+}
+
 impl AbstractTraceWriter for CborZstdTraceWriter<'_> {
     fn get_data(&self) -> &AbstractTraceWriterData {
         &self.base
@@ -38,27 +104,24 @@ impl AbstractTraceWriter for CborZstdTraceWriter<'_> {
     }
 
     fn add_event(&mut self, event: TraceLowLevelEvent) {
-        let buf: Vec<u8> = Vec::new();
-        let q = cbor4ii::serde::to_vec(buf, &event).unwrap();
+        let mut q: Vec<u8> = Vec::new();
+        self.wire_encoder.encode(&event, &mut q);
         if let Some(enc) = &mut self.trace_events_file_zstd_encoder {
-            enc.write_all(&q).unwrap();
+            enc.write_item(&q).unwrap();
         }
     }
 
     fn append_events(&mut self, events: &mut Vec<TraceLowLevelEvent>) {
         for e in events {
+            self.update_interning_tables(e);
             AbstractTraceWriter::add_event(self, e.clone());
         }
     }
 }
 
 impl TraceWriter for CborZstdTraceWriter<'_> {
-    fn begin_writing_trace_events(&mut self, path: &std::path::Path) -> Result<(), Box<dyn std::error::Error>> {
-        let pb = path.to_path_buf();
-        self.trace_events_path = Some(pb.clone());
-        let mut file_output = std::fs::File::create(pb)?;
-        file_output.write_all(HEADERV1)?;
-        self.trace_events_file_zstd_encoder = Some(Encoder::new(file_output)?);
+    fn begin_writing_trace_events_to(&mut self, output: Box<dyn Write + Send>) -> Result<(), Box<dyn std::error::Error>> {
+        self.trace_events_file_zstd_encoder = Some(create_encoder(output, &self.options)?);
 
         Ok(())
     }
@@ -1,35 +1,36 @@
-use std::{
-    fs::File,
-    io::{Cursor, Write},
-    path::PathBuf,
-};
+use std::io::{Cursor, Write};
 
 use ruzstd::encoding::{CompressionLevel, compress};
 
 use crate::{
+    TraceWriterOptions,
     abstract_trace_writer::{AbstractTraceWriter, AbstractTraceWriterData},
     trace_writer::TraceWriter,
 };
 use codetracer_trace_types::TraceLowLevelEvent;
-use codetracer_trace_format_cbor_zstd::HEADERV1;
+use codetracer_trace_format_cbor_zstd::{HEADERV1, wire::WireEncoder};
 
 pub struct CborZstdTraceWriter {
     base: AbstractTraceWriterData,
 
-    trace_events_path: Option<PathBuf>,
-    trace_events_file: Option<File>,
+    trace_events_file: Option<Box<dyn Write + Send>>,
     uncompressed_buf: Vec<u8>,
+    wire_encoder: WireEncoder,
+    options: TraceWriterOptions,
 }
 
 impl CborZstdTraceWriter {
     /// Create a new tracer instance for the given program and arguments.
-    pub fn new(program: &str, args: &[String]) -> Self {
+    ///
+    /// ruzstd supports neither dictionaries nor worker threads, so only the compression level is honored
+    pub fn new(program: &str, args: &[String], options: TraceWriterOptions) -> Self {
         CborZstdTraceWriter {
             base: AbstractTraceWriterData::new(program, args),
 
-            trace_events_path: None,
             trace_events_file: None,
             uncompressed_buf: vec![],
+            wire_encoder: WireEncoder::new(),
+            options,
         }
     }
 }
@@ -44,26 +45,21 @@ impl AbstractTraceWriter for CborZstdTraceWriter {
     }
 
     fn add_event(&mut self, event: TraceLowLevelEvent) {
-        let buf: Vec<u8> = Vec::new();
-        let q = cbor4ii::serde::to_vec(buf, &event).expect("CBOR encode failed");
-        self.uncompressed_buf.extend_from_slice(&q);
+        self.wire_encoder.encode(&event, &mut self.uncompressed_buf);
     }
 
     fn append_events(&mut self, events: &mut Vec<TraceLowLevelEvent>) {
         for e in events.drain(..) {
+            self.update_interning_tables(&e);
             <Self as AbstractTraceWriter>::add_event(self, e);
         }
     }
 }
 
 impl TraceWriter for CborZstdTraceWriter {
-    fn begin_writing_trace_events(&mut self, path: &std::path::Path) -> Result<(), Box<dyn std::error::Error>> {
-        let pb = path.to_path_buf();
-        self.trace_events_path = Some(pb.clone());
-
-        let mut file_output = std::fs::File::create(pb)?;
-        file_output.write_all(HEADERV1)?;
-        self.trace_events_file = Some(file_output);
+    fn begin_writing_trace_events_to(&mut self, mut output: Box<dyn Write + Send>) -> Result<(), Box<dyn std::error::Error>> {
+        output.write_all(HEADERV1)?;
+        self.trace_events_file = Some(output);
 
         Ok(())
     }
@@ -71,7 +67,12 @@ impl TraceWriter for CborZstdTraceWriter {
     fn finish_writing_trace_events(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         if let Some(mut file) = self.trace_events_file.take() {
             let mut cursor = Cursor::new(&self.uncompressed_buf);
-            compress(&mut cursor, &mut file, CompressionLevel::Fastest);
+            let level = if self.options.compression_level <= 1 {
+                CompressionLevel::Fastest
+            } else {
+                CompressionLevel::Default
+            };
+            compress(&mut cursor, &mut file, level);
 
             file.flush()?;
 
@@ -0,0 +1,402 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    error::Error,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use codetracer_trace_types::{
+    CallRecord, FunctionRecord, StepRecord, TOP_LEVEL_FUNCTION_ID, ThreadId, TraceLowLevelEvent, TraceMetadata, ValueRecord,
+};
+
+use crate::{
+    TraceEventsFileFormat, TraceWriterOptions,
+    abstract_trace_writer::{AbstractTraceWriter, AbstractTraceWriterData},
+    metadata, new_trace_writer,
+    trace_writer::TraceWriter,
+};
+
+/// How much of the end of the trace a flight recorder keeps
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlightRecorderLimit {
+    /// The last this many events
+    Events(usize),
+    /// As many of the last events as fit into this many bytes of CBOR encoding, as estimated from
+    /// their strings and values; the most recent event is always kept
+    Bytes(usize),
+}
+
+// roughly the CBOR encoding of an event or a value without its strings and nested values: the
+// variant and field names and a few small integers
+const EVENT_OVERHEAD: usize = 24;
+const VALUE_OVERHEAD: usize = 24;
+
+/// The size of an event in CBOR, estimated without encoding it, as it's done while recording
+fn estimated_size(event: &TraceLowLevelEvent) -> usize {
+    let payload = match event {
+        TraceLowLevelEvent::Value(value) => estimated_value_size(&value.value),
+        TraceLowLevelEvent::Call(call) => call.args.iter().map(|arg| estimated_value_size(&arg.value)).sum(),
+        TraceLowLevelEvent::Return(record) => estimated_value_size(&record.return_value),
+        TraceLowLevelEvent::Event(record) => record.metadata.len() + record.content.len(),
+        TraceLowLevelEvent::Asm(lines) => lines.iter().map(|line| line.len() + 1).sum(),
+        TraceLowLevelEvent::DropVariables(variable_ids) => variable_ids.len() * 2,
+        TraceLowLevelEvent::CompoundValue(record) => estimated_value_size(&record.value),
+        TraceLowLevelEvent::CellValue(record) => estimated_value_size(&record.value),
+        TraceLowLevelEvent::AssignCell(record) => estimated_value_size(&record.new_value),
+        _ => 0,
+    };
+    EVENT_OVERHEAD + payload
+}
+
+fn estimated_value_size(value: &ValueRecord) -> usize {
+    let payload = match value {
+        ValueRecord::String { text, .. } | ValueRecord::Raw { r: text, .. } | ValueRecord::Error { msg: text, .. } => text.len(),
+        ValueRecord::Sequence { elements, .. } | ValueRecord::Tuple { elements, .. } | ValueRecord::Struct { field_values: elements, .. } => {
+            elements.iter().map(estimated_value_size).sum()
+        }
+        ValueRecord::Variant { discriminator, contents, .. } => discriminator.len() + estimated_value_size(contents),
+        ValueRecord::Reference { dereferenced, .. } => estimated_value_size(dereferenced),
+        ValueRecord::Bytes { data, .. } => data.len(),
+        ValueRecord::BigInt { b, .. } => b.len(),
+        ValueRecord::Int128 { .. } | ValueRecord::UInt128 { .. } => 16,
+        ValueRecord::Map { entries, .. } => entries
+            .iter()
+            .map(|(key, value)| estimated_value_size(key) + estimated_value_size(value))
+            .sum(),
+        ValueRecord::Elided { preview, .. } => preview.as_deref().map_or(0, estimated_value_size),
+        _ => 0,
+    };
+    VALUE_OVERHEAD + payload
+}
+
+/// Whether an event interns an id, which later events can refer to
+fn is_interning(event: &TraceLowLevelEvent) -> bool {
+    matches!(
+        event,
+        TraceLowLevelEvent::Path(_)
+            | TraceLowLevelEvent::Function(_)
+            | TraceLowLevelEvent::Type(_)
+            | TraceLowLevelEvent::VariableName(_)
+            | TraceLowLevelEvent::Variable(_)
+    )
+}
+
+struct RingBuffer {
+    limit: FlightRecorderLimit,
+    // never discarded: every event may refer to them
+    interning: Vec<TraceLowLevelEvent>,
+    functions: Vec<FunctionRecord>,
+    // the most recent events, with their estimated sizes when limited by bytes
+    ring: VecDeque<(TraceLowLevelEvent, usize)>,
+    ring_bytes: usize,
+    discarded_events: u64,
+
+    // the state at the first event of the ring, replayed by the synthetic prefix
+    call_stacks: BTreeMap<Option<ThreadId>, Vec<CallRecord>>,
+    threads: BTreeSet<ThreadId>,
+    current_thread: Option<ThreadId>,
+
+    // set by the writer as soon as it's created
+    metadata: Option<TraceMetadata>,
+}
+
+impl RingBuffer {
+    fn push(&mut self, event: TraceLowLevelEvent) {
+        if is_interning(&event) {
+            if let TraceLowLevelEvent::Function(function) = &event {
+                self.functions.push(function.clone());
+            }
+            self.interning.push(event);
+            return;
+        }
+
+        let size = match self.limit {
+            FlightRecorderLimit::Events(_) => 0,
+            FlightRecorderLimit::Bytes(_) => estimated_size(&event),
+        };
+        self.ring.push_back((event, size));
+        self.ring_bytes += size;
+
+        while self.over_limit() {
+            self.discard_oldest();
+        }
+        // it would remove a step of the prefix
+        while matches!(self.ring.front(), Some((TraceLowLevelEvent::DropLastStep, _))) {
+            self.discard_oldest();
+        }
+    }
+
+    fn over_limit(&self) -> bool {
+        match self.limit {
+            FlightRecorderLimit::Events(max_events) => self.ring.len() > max_events,
+            FlightRecorderLimit::Bytes(max_bytes) => self.ring_bytes > max_bytes && self.ring.len() > 1,
+        }
+    }
+
+    fn discard_oldest(&mut self) {
+        let Some((event, size)) = self.ring.pop_front() else {
+            return;
+        };
+        self.ring_bytes -= size;
+        self.discarded_events += 1;
+
+        match event {
+            TraceLowLevelEvent::Call(call) => self.call_stacks.entry(self.current_thread).or_default().push(call),
+            TraceLowLevelEvent::Return(_) => {
+                self.call_stacks.entry(self.current_thread).or_default().pop();
+            }
+            TraceLowLevelEvent::ThreadStart(thread_id) => {
+                self.threads.insert(thread_id);
+            }
+            TraceLowLevelEvent::ThreadExit(thread_id) => {
+                self.threads.remove(&thread_id);
+                self.call_stacks.remove(&Some(thread_id));
+            }
+            TraceLowLevelEvent::ThreadSwitch(thread_id) => self.current_thread = Some(thread_id),
+            _ => {}
+        }
+    }
+
+    /// The interning events and the calls active at the start of the ring, followed by the ring
+    fn snapshot(&self) -> Vec<TraceLowLevelEvent> {
+        let mut events = self.interning.clone();
+        events.extend(self.threads.iter().map(|thread_id| TraceLowLevelEvent::ThreadStart(*thread_id)));
+        for (thread_id, calls) in &self.call_stacks {
+            if calls.is_empty() {
+                continue;
+            }
+            if let Some(thread_id) = thread_id {
+                events.push(TraceLowLevelEvent::ThreadSwitch(*thread_id));
+            }
+            for call in calls {
+                // like `register_call`, every call but the top level one starts with a step to the function
+                if call.function_id != TOP_LEVEL_FUNCTION_ID
+                    && let Some(function) = self.functions.get(call.function_id.0)
+                {
+                    events.push(TraceLowLevelEvent::Step(StepRecord {
+                        path_id: function.path_id,
+                        line: function.line,
+                    }));
+                }
+                events.push(TraceLowLevelEvent::Call(call.clone()));
+            }
+        }
+        if let Some(thread_id) = self.current_thread {
+            events.push(TraceLowLevelEvent::ThreadSwitch(thread_id));
+        }
+        if self.discarded_events > 0 {
+            events.push(TraceLowLevelEvent::DroppedEvents(self.discarded_events));
+        }
+        events.extend(self.ring.iter().map(|(event, _)| event.clone()));
+        events
+    }
+
+    fn paths(&self) -> Vec<&PathBuf> {
+        let paths = self.interning.iter().filter_map(|event| match event {
+            TraceLowLevelEvent::Path(path) => Some(path),
+            _ => None,
+        });
+        paths.collect()
+    }
+}
+
+/// A handle to the events kept by a `FlightRecorderTraceWriter`, which can dump them from any
+/// thread, e.g. on a signal or from a panic hook
+#[derive(Clone)]
+pub struct FlightRecorder {
+    state: Arc<Mutex<RingBuffer>>,
+}
+
+impl FlightRecorder {
+    fn lock(&self) -> MutexGuard<'_, RingBuffer> {
+        // a panic while recording doesn't make the events kept so far unusable
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// A self-contained trace of the most recent events: the interning events and the calls
+    /// active before them are replayed first, then a `DroppedEvents` event tells how many events
+    /// were discarded
+    pub fn snapshot(&self) -> Vec<TraceLowLevelEvent> {
+        self.lock().snapshot()
+    }
+
+    /// Saves the snapshot as a trace directory: `trace.json` or `trace.bin`, `trace_metadata.json`
+    /// and `trace_paths.json`
+    pub fn dump(&self, dir: &Path, format: TraceEventsFileFormat) -> Result<(), Box<dyn Error>> {
+        dump_state(&self.lock(), dir, format)
+    }
+
+    /// Dumps the trace into `dir` when the program panics, before the previous panic hook runs
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn dump_on_panic(&self, dir: PathBuf, format: TraceEventsFileFormat) {
+        let recorder = self.clone();
+        let previous_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            match recorder.try_dump(&dir, format) {
+                Ok(()) => eprintln!("flight recorder: saved the last events of the trace in {}", dir.display()),
+                Err(err) => eprintln!("flight recorder: unable to save the trace: {err}"),
+            }
+            previous_hook(info);
+        }));
+    }
+
+    /// Like `dump`, but gives up if the events stay locked, e.g. by the panicking thread itself
+    #[cfg(not(target_arch = "wasm32"))]
+    fn try_dump(&self, dir: &Path, format: TraceEventsFileFormat) -> Result<(), Box<dyn Error>> {
+        for _ in 0..100 {
+            match self.state.try_lock() {
+                Ok(state) => return dump_state(&state, dir, format),
+                Err(std::sync::TryLockError::Poisoned(err)) => return dump_state(&err.into_inner(), dir, format),
+                Err(std::sync::TryLockError::WouldBlock) => std::thread::sleep(std::time::Duration::from_millis(10)),
+            }
+        }
+        Err("the trace is locked by a thread which is recording".into())
+    }
+}
+
+fn events_file_name(format: TraceEventsFileFormat) -> &'static str {
+    match format {
+        TraceEventsFileFormat::Json => "trace.json",
+        TraceEventsFileFormat::BinaryV0 | TraceEventsFileFormat::Binary => "trace.bin",
+    }
+}
+
+fn write_events(mut events: Vec<TraceLowLevelEvent>, format: TraceEventsFileFormat, output: Box<dyn Write + Send>) -> Result<(), Box<dyn Error>> {
+    let mut trace_writer = new_trace_writer("", &[], format, TraceWriterOptions::default());
+    trace_writer.begin_writing_trace_events_to(output)?;
+    TraceWriter::append_events(trace_writer.as_mut(), &mut events);
+    trace_writer.finish_writing_trace_events()
+}
+
+fn dump_state(state: &RingBuffer, dir: &Path, format: TraceEventsFileFormat) -> Result<(), Box<dyn Error>> {
+    fs::create_dir_all(dir)?;
+    let events_file = fs::File::create(dir.join(events_file_name(format)))?;
+    write_events(state.snapshot(), format, Box::new(events_file))?;
+
+    if let Some(trace_metadata) = &state.metadata {
+        let trace_metadata = TraceMetadata {
+            end_time: metadata::now_unix_ms(),
+            ..trace_metadata.clone()
+        };
+        fs::write(dir.join("trace_metadata.json"), serde_json::to_string(&trace_metadata)?)?;
+    }
+    fs::write(dir.join("trace_paths.json"), serde_json::to_string(&state.paths())?)?;
+    Ok(())
+}
+
+/// Keeps only the end of the trace in memory, for long-running programs which can't be recorded
+/// completely. The kept events are dumped as a self-contained trace on demand, through a
+/// [`FlightRecorder`], or when writing the events finishes.
+///
+/// Interning events are never discarded, so their number grows with the size of the traced
+/// program, but not with the length of its run.
+pub struct FlightRecorderTraceWriter {
+    base: AbstractTraceWriterData,
+
+    format: TraceEventsFileFormat,
+    recorder: FlightRecorder,
+    trace_events_output: Option<Box<dyn Write + Send>>,
+}
+
+impl FlightRecorderTraceWriter {
+    /// Create a new tracer instance for the given program and arguments; `format` is the format of
+    /// the events written by `finish_writing_trace_events`
+    pub fn new(program: &str, args: &[String], format: TraceEventsFileFormat, limit: FlightRecorderLimit) -> Self {
+        let mut result = FlightRecorderTraceWriter {
+            base: AbstractTraceWriterData::new(program, args),
+            format,
+            recorder: FlightRecorder {
+                state: Arc::new(Mutex::new(RingBuffer {
+                    limit,
+                    interning: vec![],
+                    functions: vec![],
+                    ring: VecDeque::new(),
+                    ring_bytes: 0,
+                    discarded_events: 0,
+                    call_stacks: BTreeMap::new(),
+                    threads: BTreeSet::new(),
+                    current_thread: None,
+                    metadata: None,
+                })),
+            },
+            trace_events_output: None,
+        };
+        result.update_metadata();
+        result
+    }
+
+    /// A handle for dumping the kept events
+    pub fn recorder(&self) -> FlightRecorder {
+        self.recorder.clone()
+    }
+
+    // the recorder can't reach the writer, so it gets a copy of the metadata whenever it changes
+    fn update_metadata(&mut self) {
+        let metadata = self.trace_metadata();
+        self.recorder.lock().metadata = Some(metadata);
+    }
+}
+
+impl AbstractTraceWriter for FlightRecorderTraceWriter {
+    fn get_data(&self) -> &AbstractTraceWriterData {
+        &self.base
+    }
+
+    fn get_mut_data(&mut self) -> &mut AbstractTraceWriterData {
+        &mut self.base
+    }
+
+    fn add_event(&mut self, event: TraceLowLevelEvent) {
+        self.recorder.lock().push(event);
+    }
+
+    fn append_events(&mut self, events: &mut Vec<TraceLowLevelEvent>) {
+        for e in events.drain(..) {
+            self.update_interning_tables(&e);
+            AbstractTraceWriter::add_event(self, e);
+        }
+    }
+}
+
+impl TraceWriter for FlightRecorderTraceWriter {
+    fn begin_writing_trace_events_to(&mut self, output: Box<dyn Write + Send>) -> Result<(), Box<dyn Error>> {
+        self.trace_events_output = Some(output);
+        Ok(())
+    }
+
+    fn set_language(&mut self, name: &str, version: &str) {
+        AbstractTraceWriter::set_language(self, name, version);
+        self.update_metadata();
+    }
+
+    fn set_recorder(&mut self, name: &str, version: &str) {
+        AbstractTraceWriter::set_recorder(self, name, version);
+        self.update_metadata();
+    }
+
+    fn set_exit_code(&mut self, exit_code: i32) {
+        AbstractTraceWriter::set_exit_code(self, exit_code);
+        self.update_metadata();
+    }
+
+    fn record_environment_variables(&mut self, names: &[&str]) {
+        AbstractTraceWriter::record_environment_variables(self, names);
+        self.update_metadata();
+    }
+
+    fn set_metadata_extension(&mut self, key: &str, value: serde_json::Value) {
+        AbstractTraceWriter::set_metadata_extension(self, key, value);
+        self.update_metadata();
+    }
+
+    /// Writes the snapshot of the kept events
+    fn finish_writing_trace_events(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(output) = self.trace_events_output.take() {
+            write_events(self.recorder.snapshot(), self.format, output)
+        } else {
+            panic!("finish_writing_trace_events() called without previous call to begin_writing_trace_events()");
+        }
+    }
+}
@@ -1,6 +1,30 @@
 mod abstract_trace_writer;
+#[cfg(not(target_arch = "wasm32"))]
+mod background_writer;
+mod capnp_writer;
+mod flight_recorder;
+mod metadata;
 mod non_streaming_trace_writer;
+mod shared_trace_writer;
+#[cfg(not(target_arch = "wasm32"))]
+mod socket_writer;
+mod tee_trace_writer;
+mod trace_output;
 pub mod trace_writer;
+mod value_limits;
+mod writer_options;
+
+pub use abstract_trace_writer::{FunctionKey, TimestampMode};
+pub use flight_recorder::{FlightRecorder, FlightRecorderLimit, FlightRecorderTraceWriter};
+pub use shared_trace_writer::{SharedTraceWriter, TraceWriterHandle};
+#[cfg(not(target_arch = "wasm32"))]
+pub use socket_writer::{SocketTraceWriter, TraceStreamAddress};
+pub use tee_trace_writer::TeeTraceWriter;
+pub use trace_output::{TraceBuffer, TraceOutput};
+pub use value_limits::ValueLimits;
+#[cfg(not(target_arch = "wasm32"))]
+pub use writer_options::train_dictionary;
+pub use writer_options::{BackgroundEncoding, Backpressure, DEFAULT_COMPRESSION_LEVEL, DEFAULT_FRAME_SIZE, TraceWriterOptions};
 
 #[cfg(target_arch = "wasm32")]
 #[path = "./cbor_zstd_writer_wasm.rs"]
@@ -16,14 +40,51 @@ pub enum TraceEventsFileFormat {
     Binary,
 }
 
-pub fn create_trace_writer(program: &str, args: &[String], format: TraceEventsFileFormat) -> Box<dyn trace_writer::TraceWriter> {
+pub fn create_trace_writer(program: &str, args: &[String], format: TraceEventsFileFormat) -> Box<dyn trace_writer::TraceWriter + Send> {
+    create_trace_writer_with_options(program, args, format, TraceWriterOptions::default())
+}
+
+pub fn create_trace_writer_with_options(
+    program: &str,
+    args: &[String],
+    format: TraceEventsFileFormat,
+    options: TraceWriterOptions,
+) -> Box<dyn trace_writer::TraceWriter + Send> {
+    new_trace_writer(program, args, format, options)
+}
+
+/// A writer for recorders which record multiple threads at once; see `SharedTraceWriter`
+pub fn create_shared_trace_writer(program: &str, args: &[String], format: TraceEventsFileFormat, options: TraceWriterOptions) -> SharedTraceWriter {
+    SharedTraceWriter::new(new_trace_writer(program, args, format, options))
+}
+
+/// A writer which streams the trace to a receiver while it's being recorded; pass it the
+/// connection from `TraceStreamAddress::connect` with `begin_writing_trace_events_to`
+#[cfg(not(target_arch = "wasm32"))]
+pub fn create_socket_trace_writer(program: &str, args: &[String]) -> Box<dyn trace_writer::TraceWriter + Send> {
+    Box::new(SocketTraceWriter::new(program, args))
+}
+
+fn new_trace_writer(
+    program: &str,
+    args: &[String],
+    format: TraceEventsFileFormat,
+    options: TraceWriterOptions,
+) -> Box<dyn trace_writer::TraceWriter + Send> {
     match format {
-        TraceEventsFileFormat::Json | TraceEventsFileFormat::BinaryV0 => {
+        TraceEventsFileFormat::BinaryV0 => Box::new(capnp_writer::CapnpTraceWriter::new(program, args)),
+        TraceEventsFileFormat::Json => {
             let mut result = Box::new(non_streaming_trace_writer::NonStreamingTraceWriter::new(program, args));
             result.set_format(format);
             result
         }
-        TraceEventsFileFormat::Binary => Box::new(crate::cbor_zstd_writer::CborZstdTraceWriter::new(program, args)),
+        TraceEventsFileFormat::Binary => {
+            #[cfg(not(target_arch = "wasm32"))]
+            if let Some(background) = options.background {
+                return Box::new(background_writer::BackgroundCborZstdTraceWriter::new(program, args, options, background));
+            }
+            Box::new(crate::cbor_zstd_writer::CborZstdTraceWriter::new(program, args, options))
+        }
     }
 }
 
@@ -31,11 +92,15 @@ pub fn create_trace_writer(program: &str, args: &[String], format: TraceEventsFi
 mod tests {
     use codetracer_trace_types::*;
     use std::path::Path;
-    use crate::{non_streaming_trace_writer::NonStreamingTraceWriter, trace_writer::TraceWriter};
+    use crate::{
+        FlightRecorderLimit, FlightRecorderTraceWriter, FunctionKey, SharedTraceWriter, TeeTraceWriter, TimestampMode, TraceBuffer,
+        TraceEventsFileFormat, TraceWriterOptions, ValueLimits, create_shared_trace_writer, new_trace_writer,
+        non_streaming_trace_writer::NonStreamingTraceWriter, trace_writer::TraceWriter,
+    };
 
     #[test]
     fn test_simple_trace() {
-        let mut tracer = NonStreamingTraceWriter::new("path.small", &vec![]);
+        let mut tracer = NonStreamingTraceWriter::new("path.small", &[]);
         let path = Path::new("/test/path.small");
         tracer.start(path, Line(1));
         tracer.register_step(path, Line(1));
@@ -45,10 +110,10 @@ mod tests {
         tracer.register_special_event(EventLogKind::Write, "test2");
         tracer.register_special_event(EventLogKind::Error, "testError");
 
-        let function_path_id = tracer.ensure_path_id(&path);
+        let function_path_id = tracer.ensure_path_id(path);
         let function_line = Line(3);
         // -> function_id 1 after top level;
-        let function_id = tracer.ensure_function_id("function", &path, function_line);
+        let function_id = tracer.ensure_function_id("function", path, function_line);
         assert!(function_id == FunctionId(1));
 
         let before_temp_step = tracer.events.len();
@@ -71,7 +136,7 @@ mod tests {
             assert_eq!(*path_id, function_path_id);
             assert_eq!(*line, function_line);
         } else {
-            assert!(false, "expected a auto-registered step event before the last call one");
+            panic!("expected a auto-registered step event before the last call one");
         }
         assert!(matches!(should_be_call, TraceLowLevelEvent::Call(CallRecord { .. })));
 
@@ -159,4 +224,440 @@ mod tests {
         // tracer.store_trace_paths(&PathBuf::from("trace_paths.json")).unwrap();
         // tracer.store_trace_events(&PathBuf::from("trace.json")).unwrap();
     }
+
+    #[test]
+    fn test_trace_metadata() {
+        let mut tracer = NonStreamingTraceWriter::new("program.small", &["arg".to_string()]);
+        tracer.set_language("small", "1.0");
+        tracer.set_recorder("small-recorder", "0.1.0");
+        // SAFETY: no other test reads or writes this variable
+        unsafe { std::env::set_var("CODETRACER_TEST_METADATA_VAR", "value") };
+        tracer.record_environment_variables(&["CODETRACER_TEST_METADATA_VAR", "CODETRACER_TEST_METADATA_UNSET"]);
+        tracer.set_metadata_extension("small", serde_json::json!({"optimized": false}));
+
+        let path = std::env::temp_dir().join(format!("test_trace_metadata_{}.json", std::process::id()));
+        TraceWriter::begin_writing_trace_metadata(&mut tracer, &path).unwrap();
+        TraceWriter::finish_writing_trace_metadata(&mut tracer).unwrap();
+        // finishing again rewrites the metadata with the final values
+        tracer.set_exit_code(3);
+        TraceWriter::finish_writing_trace_metadata(&mut tracer).unwrap();
+        let metadata: TraceMetadata = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(metadata.program, "program.small");
+        assert_eq!(metadata.format_version, TRACE_FORMAT_VERSION);
+        assert_eq!(metadata.language.unwrap().name, "small");
+        assert_eq!(metadata.recorder.unwrap().version, "0.1.0");
+        assert_eq!(metadata.exit_code, Some(3));
+        assert_eq!(metadata.environment.len(), 1);
+        assert_eq!(metadata.environment["CODETRACER_TEST_METADATA_VAR"], "value");
+        assert_eq!(metadata.extensions["small"]["optimized"], false);
+        assert!(metadata.start_time.unwrap() <= metadata.end_time.unwrap());
+    }
+
+    #[test]
+    fn test_timestamps() {
+        let mut tracer = NonStreamingTraceWriter::new("path.small", &[]);
+        let path = Path::new("/test/path.small");
+        tracer.start(path, Line(1));
+
+        tracer.register_step(path, Line(1));
+        assert!(!tracer.events.iter().any(|event| matches!(event, TraceLowLevelEvent::Timestamp(_))));
+
+        tracer.set_timestamp_mode(TimestampMode::EveryStep);
+        tracer.register_step(path, Line(2));
+        tracer.register_step(path, Line(3));
+        let timestamps: Vec<u64> = tracer
+            .events
+            .iter()
+            .filter_map(|event| match event {
+                TraceLowLevelEvent::Timestamp(nanoseconds) => Some(*nanoseconds),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(timestamps.len(), 2);
+        assert!(timestamps[0] <= timestamps[1]);
+        assert!(matches!(tracer.events[tracer.events.len() - 2], TraceLowLevelEvent::Timestamp(_)));
+
+        // an interval which can't pass during the test: the timestamps of the `EveryStep` steps are
+        // recent enough, so neither step gets one
+        tracer.set_timestamp_mode(TimestampMode::Periodic { interval_ns: u64::MAX });
+        let before = tracer.events.len();
+        tracer.register_step(path, Line(4));
+        tracer.register_step(path, Line(5));
+        assert_eq!(tracer.events.len(), before + 2);
+
+        // without an earlier timestamp, the first step gets one
+        let mut tracer = NonStreamingTraceWriter::new("path.small", &[]);
+        tracer.start(path, Line(1));
+        tracer.set_timestamp_mode(TimestampMode::Periodic { interval_ns: u64::MAX });
+        let before = tracer.events.len();
+        tracer.register_step(path, Line(2));
+        tracer.register_step(path, Line(3));
+        assert_eq!(tracer.events.len(), before + 3);
+        assert!(matches!(tracer.events[before], TraceLowLevelEvent::Timestamp(_)));
+    }
+
+    #[test]
+    fn test_functions_with_the_same_name() {
+        let mut tracer = NonStreamingTraceWriter::new("path.small", &[]);
+        let path_a = Path::new("/test/a.small");
+        let path_b = Path::new("/test/b.small");
+        tracer.start(path_a, Line(1));
+
+        let new_a = tracer.ensure_function_id("new", path_a, Line(3));
+        let new_b = tracer.ensure_function_id("new", path_b, Line(3));
+        assert_ne!(new_a, new_b);
+        assert_eq!(tracer.ensure_function_id("new", path_a, Line(3)), new_a);
+
+        let key = FunctionKey::Custom("b::Thing::new".to_string());
+        let custom = tracer.ensure_function_id_with_key(key.clone(), "new", path_b, Line(3));
+        assert_ne!(custom, new_b);
+        assert_eq!(tracer.ensure_function_id_with_key(key, "new", path_b, Line(10)), custom);
+    }
+
+    #[test]
+    fn test_append_events_updates_interning_tables() {
+        let mut source = NonStreamingTraceWriter::new("path.small", &[]);
+        let path = Path::new("/test/path.small");
+        source.start(path, Line(1));
+        let function_id = source.ensure_function_id("function", path, Line(3));
+
+        let mut tracer = NonStreamingTraceWriter::new("path.small", &[]);
+        let mut events = source.events.clone();
+        TraceWriter::append_events(&mut tracer, &mut events);
+
+        let events_len = tracer.events.len();
+        assert_eq!(tracer.ensure_function_id("function", path, Line(3)), function_id);
+        assert_eq!(tracer.ensure_path_id(path), PathId(0));
+        assert_eq!(tracer.events.len(), events_len);
+    }
+
+    #[test]
+    fn test_append_events_with_a_repeated_variable_name() {
+        let mut tracer = NonStreamingTraceWriter::new("path.small", &[]);
+        // two appended traces, which both intern `a`
+        let mut events = vec![
+            TraceLowLevelEvent::VariableName("a".to_string()),
+            TraceLowLevelEvent::VariableName("b".to_string()),
+            TraceLowLevelEvent::VariableName("a".to_string()),
+            TraceLowLevelEvent::VariableName("c".to_string()),
+        ];
+        TraceWriter::append_events(&mut tracer, &mut events);
+
+        assert_eq!(tracer.ensure_variable_id("a"), VariableId(0));
+        assert_eq!(tracer.ensure_variable_id("b"), VariableId(1));
+        assert_eq!(tracer.ensure_variable_id("c"), VariableId(3));
+        assert_eq!(tracer.ensure_variable_id("d"), VariableId(4));
+    }
+
+    #[test]
+    fn test_append_events_with_a_repeated_type() {
+        let mut tracer = NonStreamingTraceWriter::new("path.small", &[]);
+        let int_type = tracer.to_raw_type(TypeKind::Int, "Int");
+        let float_type = tracer.to_raw_type(TypeKind::Float, "Float");
+        let string_type = tracer.to_raw_type(TypeKind::String, "String");
+        let mut events = vec![
+            TraceLowLevelEvent::Type(int_type.clone()),
+            TraceLowLevelEvent::Type(float_type.clone()),
+            TraceLowLevelEvent::Type(int_type.clone()),
+            TraceLowLevelEvent::Type(string_type.clone()),
+        ];
+        TraceWriter::append_events(&mut tracer, &mut events);
+
+        assert_eq!(tracer.ensure_raw_type_id(int_type), TypeId(0));
+        assert_eq!(tracer.ensure_raw_type_id(float_type), TypeId(1));
+        assert_eq!(tracer.ensure_raw_type_id(string_type), TypeId(3));
+        assert_eq!(tracer.ensure_type_id(TypeKind::Bool, "Bool"), TypeId(4));
+    }
+
+    #[test]
+    fn test_types_with_the_same_name() {
+        let mut tracer = NonStreamingTraceWriter::new("path.small", &[]);
+        tracer.start(Path::new("/test/path.small"), Line(1));
+
+        let int_type_id = tracer.ensure_type_id(TypeKind::Int, "Int");
+        let float_type_id = tracer.ensure_type_id(TypeKind::Float, "Float");
+        let pair = |tracer: &mut NonStreamingTraceWriter, type_id: TypeId| {
+            tracer.ensure_raw_type_id(TypeRecord {
+                kind: TypeKind::Struct,
+                lang_type: "Pair<T>".to_string(),
+                specific_info: TypeSpecificInfo::Struct {
+                    fields: vec![
+                        FieldTypeRecord {
+                            name: "left".to_string(),
+                            type_id,
+                        },
+                        FieldTypeRecord {
+                            name: "right".to_string(),
+                            type_id,
+                        },
+                    ],
+                },
+            })
+        };
+
+        let int_pair_type_id = pair(&mut tracer, int_type_id);
+        let float_pair_type_id = pair(&mut tracer, float_type_id);
+        assert_ne!(int_pair_type_id, float_pair_type_id);
+        assert_eq!(pair(&mut tracer, int_type_id), int_pair_type_id);
+
+        assert_ne!(tracer.ensure_type_id(TypeKind::Seq, "Int"), int_type_id);
+    }
+
+    #[test]
+    fn test_bytes_value() {
+        let mut tracer = NonStreamingTraceWriter::new("path.small", &[]);
+        let type_id = tracer.ensure_type_id(TypeKind::Seq, "Bytes");
+        let data: Vec<u8> = (0..=255).collect();
+
+        let full = tracer.bytes_value(&data, type_id);
+        assert!(matches!(&full, ValueRecord::Bytes { data: d, original_length: None, .. } if d.len() == 256));
+
+        tracer.set_value_limits(ValueLimits {
+            max_bytes_length: Some(16),
+            ..Default::default()
+        });
+        let truncated = tracer.bytes_value(&data, type_id);
+        assert!(matches!(&truncated, ValueRecord::Bytes { data: d, original_length: Some(256), .. } if d[..] == data[..16]));
+    }
+
+    #[test]
+    fn test_shared_trace_writer() {
+        let shared = create_shared_trace_writer("path.small", &[], TraceEventsFileFormat::Json, TraceWriterOptions::default());
+        let trace_path = std::env::temp_dir().join(format!("test_shared_trace_writer_{}.json", std::process::id()));
+        shared.with_writer(|writer| writer.begin_writing_trace_events(&trace_path)).unwrap();
+
+        let handles = [shared.handle(), shared.handle()];
+        std::thread::scope(|scope| {
+            for handle in &handles {
+                scope.spawn(move || {
+                    let path = Path::new("/test/path.small");
+                    let function_id = handle.ensure_function_id("function", path, Line(3));
+                    // the lines tell which thread recorded a step
+                    let first_line = handle.thread_id().0 as i64 * 1000;
+                    for line in first_line..first_line + 100 {
+                        handle.register_step(path, Line(line));
+                    }
+                    handle.register_call(function_id, vec![handle.arg("a", NONE_VALUE)]);
+                });
+            }
+        });
+        drop(handles);
+        shared.with_writer(|writer| writer.finish_writing_trace_events()).unwrap();
+
+        let events: Vec<TraceLowLevelEvent> = serde_json::from_str(&std::fs::read_to_string(&trace_path).unwrap()).unwrap();
+        std::fs::remove_file(&trace_path).unwrap();
+
+        let count = |predicate: fn(&TraceLowLevelEvent) -> bool| events.iter().filter(|event| predicate(event)).count();
+        // the interning tables are shared: both threads get the same ids
+        assert_eq!(count(|event| matches!(event, TraceLowLevelEvent::Path(_))), 1);
+        assert_eq!(count(|event| matches!(event, TraceLowLevelEvent::Function(_))), 1);
+        assert_eq!(count(|event| matches!(event, TraceLowLevelEvent::VariableName(_))), 1);
+        assert_eq!(count(|event| matches!(event, TraceLowLevelEvent::ThreadStart(_))), 2);
+        assert_eq!(count(|event| matches!(event, TraceLowLevelEvent::ThreadExit(_))), 2);
+
+        // every step comes after a switch to the thread which recorded it
+        let mut current_thread = None;
+        let mut steps = 0;
+        for event in &events {
+            match event {
+                TraceLowLevelEvent::ThreadSwitch(thread_id) => current_thread = Some(thread_id.0 as i64),
+                TraceLowLevelEvent::Step(StepRecord { line, .. }) => {
+                    assert_eq!(Some(line.0 / 1000), current_thread);
+                    steps += 1;
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(steps, 2 * 100);
+    }
+
+    #[test]
+    fn test_tee_trace_writer() {
+        let buffers = [TraceBuffer::new(), TraceBuffer::new()];
+        let formats = [TraceEventsFileFormat::Json, TraceEventsFileFormat::Binary];
+        let sinks = formats
+            .into_iter()
+            .zip(&buffers)
+            .map(|(format, buffer)| {
+                let mut sink = new_trace_writer("path.small", &[], format, TraceWriterOptions::default());
+                sink.begin_writing_trace_events_to(Box::new(buffer.clone())).unwrap();
+                sink
+            })
+            .collect();
+        let mut tee = TeeTraceWriter::new("path.small", &[], sinks);
+        assert!(TraceWriter::begin_writing_trace_events_to(&mut tee, Box::new(TraceBuffer::new())).is_err());
+
+        TraceWriter::set_language(&mut tee, "small", "1.0");
+        let path = Path::new("/test/path.small");
+        TraceWriter::start(&mut tee, path, Line(1));
+        let function_id = TraceWriter::ensure_function_id(&mut tee, "function", path, Line(3));
+        TraceWriter::register_step(&mut tee, path, Line(2));
+        let arg = TraceWriter::arg(&mut tee, "a", NONE_VALUE);
+        TraceWriter::register_call(&mut tee, function_id, vec![arg]);
+        TraceWriter::append_events(&mut tee, &mut vec![TraceLowLevelEvent::VariableName("a".to_string())]);
+        assert_eq!(TraceWriter::ensure_variable_id(&mut tee, "b"), VariableId(2));
+
+        // a sink added later gets the interning events recorded before it
+        let late_buffer = TraceBuffer::new();
+        let mut late_sink = new_trace_writer("path.small", &[], TraceEventsFileFormat::Json, TraceWriterOptions::default());
+        late_sink.begin_writing_trace_events_to(Box::new(late_buffer.clone())).unwrap();
+        tee.add_sink(late_sink);
+        TraceWriter::register_step(&mut tee, path, Line(4));
+
+        // the sinks have the same interning tables as the tee: nothing new is interned
+        for sink in tee.sinks_mut() {
+            assert_eq!(TraceWriter::ensure_function_id(sink.as_mut(), "function", path, Line(3)), function_id);
+            assert_eq!(TraceWriter::ensure_variable_id(sink.as_mut(), "a"), VariableId(0));
+            assert_eq!(TraceWriter::ensure_variable_id(sink.as_mut(), "b"), VariableId(2));
+        }
+
+        let metadata = TraceBuffer::new();
+        let sink = &mut tee.sinks_mut()[0];
+        TraceWriter::begin_writing_trace_metadata_to(sink.as_mut(), Box::new(metadata.clone())).unwrap();
+        TraceWriter::finish_writing_trace_metadata(sink.as_mut()).unwrap();
+        let metadata: TraceMetadata = serde_json::from_slice(&metadata.contents()).unwrap();
+        assert_eq!(metadata.language.unwrap().name, "small");
+
+        TraceWriter::finish_writing_trace_events(&mut tee).unwrap();
+        let json_events: Vec<TraceLowLevelEvent> = serde_json::from_slice(&buffers[0].contents()).unwrap();
+        assert_eq!(json_events.iter().filter(|event| matches!(event, TraceLowLevelEvent::Path(_))).count(), 1);
+        assert!(matches!(json_events.last().unwrap(), TraceLowLevelEvent::Step(_)));
+        let late_events: Vec<TraceLowLevelEvent> = serde_json::from_slice(&late_buffer.contents()).unwrap();
+        assert!(matches!(late_events[0], TraceLowLevelEvent::Path(_)));
+        assert!(matches!(late_events[1], TraceLowLevelEvent::Function(_)));
+        let late_variables = late_events.iter().filter(|event| matches!(event, TraceLowLevelEvent::VariableName(_)));
+        assert_eq!(late_variables.count(), 3);
+        assert!(!late_events.iter().any(|event| matches!(event, TraceLowLevelEvent::Call(_))));
+        let binary = buffers[1].contents();
+        assert_eq!(&binary[..5], &[0xC0, 0xDE, 0x72, 0xAC, 0xE2]);
+    }
+
+    #[test]
+    fn test_flight_recorder() {
+        let mut tracer = FlightRecorderTraceWriter::new("path.small", &[], TraceEventsFileFormat::Json, FlightRecorderLimit::Events(10));
+        let recorder = tracer.recorder();
+        let path = Path::new("/test/path.small");
+        TraceWriter::start(&mut tracer, path, Line(1));
+        let outer = TraceWriter::ensure_function_id(&mut tracer, "outer", path, Line(3));
+        let inner = TraceWriter::ensure_function_id(&mut tracer, "inner", path, Line(10));
+        TraceWriter::register_call(&mut tracer, outer, vec![]);
+        TraceWriter::register_step(&mut tracer, path, Line(4));
+        TraceWriter::register_call(&mut tracer, inner, vec![]);
+        for line in 11..100 {
+            TraceWriter::register_step(&mut tracer, path, Line(line));
+        }
+        TraceWriter::register_return(&mut tracer, NONE_VALUE);
+        TraceWriter::register_step(&mut tracer, path, Line(5));
+
+        let events = recorder.snapshot();
+        let calls: Vec<FunctionId> = events
+            .iter()
+            .filter_map(|event| match event {
+                TraceLowLevelEvent::Call(call) => Some(call.function_id),
+                _ => None,
+            })
+            .collect();
+        // the calls active before the kept events are replayed
+        assert_eq!(calls, [TOP_LEVEL_FUNCTION_ID, outer, inner]);
+        let dropped = events
+            .iter()
+            .position(|event| matches!(event, TraceLowLevelEvent::DroppedEvents(_)))
+            .unwrap();
+        assert_eq!(events.len() - dropped - 1, 10);
+        assert!(matches!(
+            events.last().unwrap(),
+            TraceLowLevelEvent::Step(StepRecord { line: Line(5), .. })
+        ));
+        // the interning events come first, and nothing is interned twice
+        assert!(matches!(events[0], TraceLowLevelEvent::Path(_)));
+        assert_eq!(events.iter().filter(|event| matches!(event, TraceLowLevelEvent::Function(_))).count(), 3);
+
+        // the snapshot is a complete trace: a writer can intern from it
+        let mut replay = NonStreamingTraceWriter::new("path.small", &[]);
+        TraceWriter::append_events(&mut replay, &mut events.clone());
+        assert_eq!(replay.ensure_function_id("inner", path, Line(10)), inner);
+
+        TraceWriter::set_language(&mut tracer, "small", "1.0");
+        let dir = std::env::temp_dir().join(format!("test_flight_recorder_{}", std::process::id()));
+        recorder.dump(&dir, TraceEventsFileFormat::Json).unwrap();
+        let dumped: Vec<TraceLowLevelEvent> = serde_json::from_str(&std::fs::read_to_string(dir.join("trace.json")).unwrap()).unwrap();
+        let metadata: TraceMetadata = serde_json::from_str(&std::fs::read_to_string(dir.join("trace_metadata.json")).unwrap()).unwrap();
+        let paths: Vec<String> = serde_json::from_str(&std::fs::read_to_string(dir.join("trace_paths.json")).unwrap()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(dumped.len(), events.len());
+        assert_eq!(metadata.language.unwrap().name, "small");
+        assert_eq!(paths, ["/test/path.small"]);
+    }
+
+    #[test]
+    fn test_flight_recorder_byte_limit() {
+        let mut tracer = FlightRecorderTraceWriter::new("path.small", &[], TraceEventsFileFormat::Json, FlightRecorderLimit::Bytes(1000));
+        let path = Path::new("/test/path.small");
+        TraceWriter::start(&mut tracer, path, Line(1));
+        let type_id = TraceWriter::ensure_type_id(&mut tracer, TypeKind::Int, "Int");
+        for i in 0..1000 {
+            TraceWriter::register_step(&mut tracer, path, Line(i));
+            TraceWriter::register_variable_with_full_value(&mut tracer, "i", ValueRecord::Int { i, type_id });
+        }
+
+        let buffer = TraceBuffer::new();
+        TraceWriter::begin_writing_trace_events_to(&mut tracer, Box::new(buffer.clone())).unwrap();
+        TraceWriter::finish_writing_trace_events(&mut tracer).unwrap();
+        let events: Vec<TraceLowLevelEvent> = serde_json::from_slice(&buffer.contents()).unwrap();
+        let kept = events.iter().filter(|event| matches!(event, TraceLowLevelEvent::Step(_))).count();
+        assert!(kept > 10 && kept < 1000);
+        assert!(matches!(events.last().unwrap(), TraceLowLevelEvent::Value(_)));
+
+        // the contents of the values count as well: each of these alone takes up the whole limit
+        let string_type_id = TraceWriter::ensure_type_id(&mut tracer, TypeKind::String, "String");
+        let bytes_type_id = TraceWriter::ensure_type_id(&mut tracer, TypeKind::Seq, "Bytes");
+        let map_type_id = TraceWriter::ensure_type_id(&mut tracer, TypeKind::Map, "Map");
+        let large_values = [
+            ValueRecord::String {
+                text: "a".repeat(1000),
+                type_id: string_type_id,
+            },
+            ValueRecord::Bytes {
+                data: vec![0; 1000],
+                original_length: None,
+                type_id: bytes_type_id,
+            },
+            ValueRecord::Map {
+                entries: (0..20)
+                    .map(|i| (ValueRecord::Int { i, type_id }, ValueRecord::Int { i, type_id }))
+                    .collect(),
+                type_id: map_type_id,
+            },
+        ];
+        for value in large_values {
+            TraceWriter::register_step(&mut tracer, path, Line(1000));
+            TraceWriter::register_variable_with_full_value(&mut tracer, "v", value);
+            let events = tracer.recorder().snapshot();
+            assert!(!events.iter().any(|event| matches!(event, TraceLowLevelEvent::Step(_))));
+            assert!(matches!(events.last().unwrap(), TraceLowLevelEvent::Value(_)));
+        }
+    }
+
+    #[test]
+    fn test_flight_recorder_with_threads() {
+        let tracer = FlightRecorderTraceWriter::new("path.small", &[], TraceEventsFileFormat::Json, FlightRecorderLimit::Events(10));
+        let recorder = tracer.recorder();
+        let shared = SharedTraceWriter::new(Box::new(tracer));
+        let path = Path::new("/test/path.small");
+        let handles = [shared.handle(), shared.handle()];
+        let function_id = handles[0].ensure_function_id("function", path, Line(3));
+        handles[0].register_call(function_id, vec![]);
+        handles[1].register_step(path, Line(1));
+        handles[0].register_return(NONE_VALUE);
+        for line in 2..100 {
+            handles[1].register_step(path, Line(line));
+        }
+
+        // the call of the first thread is discarded together with its return: it's not replayed
+        let events = recorder.snapshot();
+        assert!(events.iter().any(|event| matches!(event, TraceLowLevelEvent::DroppedEvents(_))));
+        assert!(!events.iter().any(|event| matches!(event, TraceLowLevelEvent::Call(_))));
+    }
 }
@@ -0,0 +1,82 @@
+//! Environment probes used to fill in `TraceMetadata` automatically.
+//!
+//! None of these are available on `wasm32`, where they all return `None`.
+
+use std::path::Path;
+
+use codetracer_trace_types::GitInfo;
+
+/// Current wall-clock time in milliseconds since the unix epoch
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn now_unix_ms() -> Option<u64> {
+    let duration = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).ok()?;
+    u64::try_from(duration.as_millis()).ok()
+}
+
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn now_unix_ms() -> Option<u64> {
+    None
+}
+
+/// Monotonic clock started together with the trace, used for `Timestamp` events
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) struct MonotonicClock(std::time::Instant);
+
+#[cfg(not(target_arch = "wasm32"))]
+impl MonotonicClock {
+    pub(crate) fn start() -> Self {
+        MonotonicClock(std::time::Instant::now())
+    }
+
+    pub(crate) fn elapsed_ns(&self) -> Option<u64> {
+        u64::try_from(self.0.elapsed().as_nanos()).ok()
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+pub(crate) struct MonotonicClock;
+
+#[cfg(target_arch = "wasm32")]
+impl MonotonicClock {
+    pub(crate) fn start() -> Self {
+        MonotonicClock
+    }
+
+    pub(crate) fn elapsed_ns(&self) -> Option<u64> {
+        None
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn hostname() -> Option<String> {
+    gethostname::gethostname().into_string().ok().filter(|name| !name.is_empty())
+}
+
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn hostname() -> Option<String> {
+    None
+}
+
+/// Commit and dirty state of the git repository containing `workdir`,
+/// `None` if it's not in a repository or `git` is not installed
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn git_info(workdir: &Path) -> Option<GitInfo> {
+    use std::process::Command;
+
+    let git = |args: &[&str]| -> Option<String> {
+        let output = Command::new("git").arg("-C").arg(workdir).args(args).output().ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        String::from_utf8(output.stdout).ok()
+    };
+
+    let commit = git(&["rev-parse", "HEAD"])?.trim().to_string();
+    let dirty = !git(&["status", "--porcelain"])?.trim().is_empty();
+    Some(GitInfo { commit, dirty })
+}
+
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn git_info(_workdir: &Path) -> Option<GitInfo> {
+    None
+}
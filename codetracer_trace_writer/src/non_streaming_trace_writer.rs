@@ -1,7 +1,6 @@
 use std::{
     error::Error,
-    fs,
-    path::{Path, PathBuf},
+    io::{BufWriter, Write},
 };
 
 use crate::{
@@ -23,7 +22,7 @@ pub struct NonStreamingTraceWriter {
     pub events: Vec<TraceLowLevelEvent>,
 
     format: TraceEventsFileFormat,
-    trace_events_path: Option<PathBuf>,
+    trace_events_output: Option<Box<dyn Write + Send>>,
 }
 
 impl NonStreamingTraceWriter {
@@ -35,7 +34,7 @@ impl NonStreamingTraceWriter {
             events: vec![],
 
             format: TraceEventsFileFormat::Binary,
-            trace_events_path: None,
+            trace_events_output: None,
         }
     }
 
@@ -58,31 +57,34 @@ impl AbstractTraceWriter for NonStreamingTraceWriter {
     }
 
     fn append_events(&mut self, events: &mut Vec<TraceLowLevelEvent>) {
+        for event in events.iter() {
+            self.update_interning_tables(event);
+        }
         self.events.append(events)
     }
 }
 
 impl TraceWriter for NonStreamingTraceWriter {
-    fn begin_writing_trace_events(&mut self, path: &Path) -> Result<(), Box<dyn Error>> {
-        self.trace_events_path = Some(path.to_path_buf());
+    fn begin_writing_trace_events_to(&mut self, output: Box<dyn Write + Send>) -> Result<(), Box<dyn Error>> {
+        self.trace_events_output = Some(output);
         Ok(())
     }
 
     fn finish_writing_trace_events(&mut self) -> Result<(), Box<dyn Error>> {
-        if let Some(path) = &self.trace_events_path {
+        if let Some(output) = self.trace_events_output.take() {
+            let mut output = BufWriter::new(output);
             match self.format {
                 TraceEventsFileFormat::Json => {
-                    let json = serde_json::to_string(&self.events)?;
-                    fs::write(path, json)?;
+                    serde_json::to_writer(&mut output, &self.events)?;
                 }
                 TraceEventsFileFormat::BinaryV0 => {
-                    let mut file = fs::File::create(path)?;
-                    write_trace(&self.events, &mut file)?;
+                    write_trace(&self.events, &mut output)?;
                 }
                 TraceEventsFileFormat::Binary => {
                     unreachable!()
                 }
             }
+            output.flush()?;
             Ok(())
         } else {
             panic!("finish_writing_trace_events() called without previous call to begin_writing_trace_events()");
@@ -0,0 +1,263 @@
+use std::{
+    borrow::Borrow,
+    collections::HashMap,
+    hash::{BuildHasher, Hash, RandomState},
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex, MutexGuard, RwLock,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use codetracer_trace_types::{
+    EventLogKind, FullValueRecord, FunctionId, Line, PathId, ThreadId, TraceLowLevelEvent, TypeId, TypeKind, TypeRecord, TypeSpecificInfo,
+    ValueRecord, VariableId,
+};
+
+use crate::{abstract_trace_writer::FunctionKey, trace_writer::TraceWriter};
+
+const SHARDS: usize = 16;
+
+/// A cache of interned ids, split into independently locked shards so lookups from different
+/// threads rarely wait for each other
+struct ShardedMap<K, V> {
+    shards: [RwLock<HashMap<K, V>>; SHARDS],
+    hasher: RandomState,
+}
+
+impl<K: Hash + Eq, V: Copy> ShardedMap<K, V> {
+    fn new() -> Self {
+        ShardedMap {
+            shards: std::array::from_fn(|_| RwLock::new(HashMap::new())),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn shard<Q: Hash + ?Sized>(&self, key: &Q) -> &RwLock<HashMap<K, V>> {
+        &self.shards[self.hasher.hash_one(key) as usize % SHARDS]
+    }
+
+    fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.shard(key).read().unwrap().get(key).copied()
+    }
+
+    fn insert(&self, key: K, value: V) {
+        self.shard(&key).write().unwrap().insert(key, value);
+    }
+}
+
+struct WriterState {
+    writer: Box<dyn TraceWriter + Send>,
+    // the thread the last events were written by
+    current_thread: Option<ThreadId>,
+}
+
+struct Shared {
+    state: Mutex<WriterState>,
+    // read-only copies of the writer's interning tables: ids of already interned items can be
+    // looked up without locking the writer
+    paths: ShardedMap<PathBuf, PathId>,
+    functions: ShardedMap<FunctionKey, FunctionId>,
+    types: ShardedMap<TypeRecord, TypeId>,
+    variables: ShardedMap<String, VariableId>,
+    next_thread_id: AtomicU64,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, WriterState> {
+        self.state.lock().expect("trace writer lock poisoned")
+    }
+}
+
+/// A trace writer which can be used from multiple threads at once.
+///
+/// Every recording thread writes through its own [`TraceWriterHandle`]. The handles share the
+/// interning tables of the underlying writer, and a `ThreadSwitch` event is inserted whenever
+/// the events come from a different handle than the previous ones, including before the events
+/// of the first handle.
+#[derive(Clone)]
+pub struct SharedTraceWriter {
+    shared: Arc<Shared>,
+}
+
+impl SharedTraceWriter {
+    pub fn new(writer: Box<dyn TraceWriter + Send>) -> Self {
+        SharedTraceWriter {
+            shared: Arc::new(Shared {
+                state: Mutex::new(WriterState {
+                    writer,
+                    current_thread: None,
+                }),
+                paths: ShardedMap::new(),
+                functions: ShardedMap::new(),
+                types: ShardedMap::new(),
+                variables: ShardedMap::new(),
+                next_thread_id: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Creates a handle for a new thread with the next free thread id and records its `ThreadStart`
+    pub fn handle(&self) -> TraceWriterHandle {
+        let thread_id = ThreadId(self.shared.next_thread_id.fetch_add(1, Ordering::Relaxed));
+        self.handle_with_id(thread_id)
+    }
+
+    /// Like `handle`, for a thread id chosen by the recorder, e.g. the runtime's own thread id.
+    /// Mixing it with `handle` can give two threads the same id.
+    pub fn handle_with_id(&self, thread_id: ThreadId) -> TraceWriterHandle {
+        TraceWriter::thread_start(self.shared.lock().writer.as_mut(), thread_id);
+        TraceWriterHandle {
+            shared: self.shared.clone(),
+            thread_id,
+        }
+    }
+
+    /// Runs `f` with exclusive access to the underlying writer, e.g. to begin or finish writing the trace
+    pub fn with_writer<R>(&self, f: impl FnOnce(&mut dyn TraceWriter) -> R) -> R {
+        f(self.shared.lock().writer.as_mut())
+    }
+}
+
+/// Writes the events of a single thread into a [`SharedTraceWriter`]; records the `ThreadExit`
+/// of its thread when dropped
+pub struct TraceWriterHandle {
+    shared: Arc<Shared>,
+    thread_id: ThreadId,
+}
+
+impl TraceWriterHandle {
+    pub fn thread_id(&self) -> ThreadId {
+        self.thread_id
+    }
+
+    /// Runs `f` with exclusive access to the underlying writer, after switching it to the thread
+    /// of this handle; the events `f` adds belong to this thread
+    pub fn with_writer<R>(&self, f: impl FnOnce(&mut dyn TraceWriter) -> R) -> R {
+        let mut state = self.shared.lock();
+        if state.current_thread != Some(self.thread_id) {
+            // even the first thread to write gets a switch: the events before it would belong to
+            // no thread, e.g. for a flight recorder replaying the active calls of each thread
+            TraceWriter::thread_switch(state.writer.as_mut(), self.thread_id);
+            state.current_thread = Some(self.thread_id);
+        }
+        f(state.writer.as_mut())
+    }
+
+    // interning needs no thread switch: the interning events don't belong to any thread
+    fn intern<R>(&self, f: impl FnOnce(&mut dyn TraceWriter) -> R) -> R {
+        f(self.shared.lock().writer.as_mut())
+    }
+
+    pub fn ensure_path_id(&self, path: &Path) -> PathId {
+        if let Some(path_id) = self.shared.paths.get(path) {
+            return path_id;
+        }
+        let path_id = self.intern(|writer| TraceWriter::ensure_path_id(writer, path));
+        self.shared.paths.insert(path.to_path_buf(), path_id);
+        path_id
+    }
+
+    pub fn ensure_function_id(&self, function_name: &str, path: &Path, line: Line) -> FunctionId {
+        let key = FunctionKey::Location {
+            name: function_name.to_string(),
+            path_id: self.ensure_path_id(path),
+            line,
+        };
+        self.ensure_function_id_with_key(key, function_name, path, line)
+    }
+
+    pub fn ensure_function_id_with_key(&self, key: FunctionKey, function_name: &str, path: &Path, line: Line) -> FunctionId {
+        if let Some(function_id) = self.shared.functions.get(&key) {
+            return function_id;
+        }
+        let function_id = self.intern(|writer| TraceWriter::ensure_function_id_with_key(writer, key.clone(), function_name, path, line));
+        self.shared.functions.insert(key, function_id);
+        function_id
+    }
+
+    pub fn ensure_type_id(&self, kind: TypeKind, lang_type: &str) -> TypeId {
+        self.ensure_raw_type_id(TypeRecord {
+            kind,
+            lang_type: lang_type.to_string(),
+            specific_info: TypeSpecificInfo::None,
+        })
+    }
+
+    pub fn ensure_raw_type_id(&self, typ: TypeRecord) -> TypeId {
+        if let Some(type_id) = self.shared.types.get(&typ) {
+            return type_id;
+        }
+        let type_id = self.intern(|writer| TraceWriter::ensure_raw_type_id(writer, typ.clone()));
+        self.shared.types.insert(typ, type_id);
+        type_id
+    }
+
+    pub fn ensure_variable_id(&self, variable_name: &str) -> VariableId {
+        if let Some(variable_id) = self.shared.variables.get(variable_name) {
+            return variable_id;
+        }
+        let variable_id = self.intern(|writer| TraceWriter::ensure_variable_id(writer, variable_name));
+        self.shared.variables.insert(variable_name.to_string(), variable_id);
+        variable_id
+    }
+
+    pub fn register_step(&self, path: &Path, line: Line) {
+        self.with_writer(|writer| TraceWriter::register_step(writer, path, line));
+    }
+
+    pub fn register_call(&self, function_id: FunctionId, args: Vec<FullValueRecord>) {
+        self.with_writer(|writer| TraceWriter::register_call(writer, function_id, args));
+    }
+
+    pub fn arg(&self, name: &str, value: ValueRecord) -> FullValueRecord {
+        let variable_id = self.ensure_variable_id(name);
+        let value = self.intern(|writer| TraceWriter::limit_value(writer, value));
+        FullValueRecord { variable_id, value }
+    }
+
+    pub fn register_return(&self, return_value: ValueRecord) {
+        self.with_writer(|writer| TraceWriter::register_return(writer, return_value));
+    }
+
+    pub fn register_special_event(&self, kind: EventLogKind, content: &str) {
+        self.with_writer(|writer| TraceWriter::register_special_event(writer, kind, content));
+    }
+
+    pub fn register_asm(&self, instructions: &[String]) {
+        self.with_writer(|writer| TraceWriter::register_asm(writer, instructions));
+    }
+
+    pub fn register_variable_with_full_value(&self, name: &str, value: ValueRecord) {
+        let variable_id = self.ensure_variable_id(name);
+        self.register_full_value(variable_id, value);
+    }
+
+    pub fn register_full_value(&self, variable_id: VariableId, value: ValueRecord) {
+        self.with_writer(|writer| TraceWriter::register_full_value(writer, variable_id, value));
+    }
+
+    pub fn drop_variables(&self, variable_names: &[String]) {
+        self.with_writer(|writer| TraceWriter::drop_variables(writer, variable_names));
+    }
+
+    pub fn drop_last_step(&self) {
+        self.with_writer(|writer| TraceWriter::drop_last_step(writer));
+    }
+
+    pub fn add_event(&self, event: TraceLowLevelEvent) {
+        self.with_writer(|writer| TraceWriter::add_event(writer, event));
+    }
+}
+
+impl Drop for TraceWriterHandle {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.shared.state.lock() {
+            TraceWriter::thread_exit(state.writer.as_mut(), self.thread_id);
+        }
+    }
+}
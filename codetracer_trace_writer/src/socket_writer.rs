@@ -0,0 +1,170 @@
+use std::{
+    error::Error,
+    io::{self, BufWriter, Write},
+    net::TcpStream,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use codetracer_trace_format_cbor_zstd::{
+    live::{self, MessageKind, STREAM_HEADER},
+    wire::WireEncoder,
+};
+use codetracer_trace_types::TraceLowLevelEvent;
+
+use crate::{
+    abstract_trace_writer::{AbstractTraceWriter, AbstractTraceWriterData},
+    trace_writer::TraceWriter,
+};
+
+/// Where a live trace is sent to, or received from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceStreamAddress {
+    /// `host:port`
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl TraceStreamAddress {
+    /// `unix:<path>` for a Unix domain socket, anything else is a TCP `host:port`
+    pub fn parse(address: &str) -> Result<Self, Box<dyn Error>> {
+        match address.strip_prefix("unix:") {
+            #[cfg(unix)]
+            Some(path) => Ok(TraceStreamAddress::Unix(PathBuf::from(path))),
+            #[cfg(not(unix))]
+            Some(_) => Err("Unix domain sockets are not supported on this platform".into()),
+            None => Ok(TraceStreamAddress::Tcp(address.to_string())),
+        }
+    }
+
+    /// Connects to a receiver, e.g. `codetracer_trace_util record`, for `begin_writing_trace_events_to`
+    pub fn connect(&self) -> io::Result<Box<dyn Write + Send>> {
+        match self {
+            TraceStreamAddress::Tcp(address) => {
+                let stream = TcpStream::connect(address)?;
+                // the writer sends whole batches
+                stream.set_nodelay(true)?;
+                Ok(Box::new(stream))
+            }
+            #[cfg(unix)]
+            TraceStreamAddress::Unix(path) => Ok(Box::new(std::os::unix::net::UnixStream::connect(path)?)),
+        }
+    }
+}
+
+/// Streams the trace to a receiver while it's being recorded, using the protocol of
+/// `codetracer_trace_format_cbor_zstd::live`.
+///
+/// The metadata is sent when writing the events begins and again when it finishes, so recorders
+/// should set what they know, e.g. the language, before `begin_writing_trace_events_to`. If the
+/// receiver goes away, the remaining events are discarded and `finish_writing_trace_events`
+/// returns the error, instead of interrupting the traced program.
+pub struct SocketTraceWriter {
+    base: AbstractTraceWriterData,
+
+    /// A batch is sent when it reaches this many bytes
+    pub batch_size: usize,
+    /// A batch is sent with the first event after it's been waiting this long, so receivers see
+    /// slowly recorded traces too
+    pub max_batch_delay: Duration,
+
+    output: Option<BufWriter<Box<dyn Write + Send>>>,
+    wire_encoder: WireEncoder,
+    batch: Vec<u8>,
+    last_batch_time: Instant,
+    error: Option<io::Error>,
+}
+
+impl SocketTraceWriter {
+    /// Create a new tracer instance for the given program and arguments.
+    pub fn new(program: &str, args: &[String]) -> Self {
+        // receivers don't keep the events, so values are never encoded as back-references
+        let mut wire_encoder = WireEncoder::new();
+        wire_encoder.share_values = false;
+
+        SocketTraceWriter {
+            base: AbstractTraceWriterData::new(program, args),
+
+            batch_size: live::DEFAULT_BATCH_SIZE,
+            max_batch_delay: Duration::from_millis(100),
+
+            output: None,
+            wire_encoder,
+            batch: Vec::new(),
+            last_batch_time: Instant::now(),
+            error: None,
+        }
+    }
+
+    fn send_batch(&mut self) {
+        self.last_batch_time = Instant::now();
+        if self.batch.is_empty() {
+            return;
+        }
+        if let Some(output) = &mut self.output
+            && self.error.is_none()
+            && let Err(err) = live::write_message(output, MessageKind::Events, &self.batch).and_then(|_| output.flush())
+        {
+            self.error = Some(err);
+        }
+        self.batch.clear();
+    }
+}
+
+impl AbstractTraceWriter for SocketTraceWriter {
+    fn get_data(&self) -> &AbstractTraceWriterData {
+        &self.base
+    }
+
+    fn get_mut_data(&mut self) -> &mut AbstractTraceWriterData {
+        &mut self.base
+    }
+
+    fn add_event(&mut self, event: TraceLowLevelEvent) {
+        if self.output.is_none() {
+            return;
+        }
+        self.wire_encoder.encode(&event, &mut self.batch);
+        if self.batch.len() >= self.batch_size || self.last_batch_time.elapsed() >= self.max_batch_delay {
+            self.send_batch();
+        }
+    }
+
+    fn append_events(&mut self, events: &mut Vec<TraceLowLevelEvent>) {
+        for e in events {
+            self.update_interning_tables(e);
+            AbstractTraceWriter::add_event(self, e.clone());
+        }
+    }
+}
+
+impl TraceWriter for SocketTraceWriter {
+    fn begin_writing_trace_events_to(&mut self, output: Box<dyn Write + Send>) -> Result<(), Box<dyn Error>> {
+        let mut output = BufWriter::new(output);
+        output.write_all(STREAM_HEADER)?;
+        live::write_metadata_message(&mut output, &self.trace_metadata())?;
+        output.flush()?;
+
+        self.output = Some(output);
+        self.last_batch_time = Instant::now();
+        Ok(())
+    }
+
+    fn finish_writing_trace_events(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.output.is_none() {
+            panic!("finish_writing_trace_events() called without previous call to begin_writing_trace_events()");
+        }
+        self.send_batch();
+        let metadata = self.trace_metadata();
+        let mut output = self.output.take().expect("checked above");
+        if let Some(err) = self.error.take() {
+            return Err(err.into());
+        }
+
+        live::write_metadata_message(&mut output, &metadata)?;
+        live::write_message(&mut output, MessageKind::End, &[])?;
+        output.flush()?;
+        Ok(())
+    }
+}
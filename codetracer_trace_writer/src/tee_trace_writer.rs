@@ -0,0 +1,165 @@
+use std::{collections::HashMap, error::Error, io::Write, path::Path};
+
+use codetracer_trace_types::{FunctionRecord, TraceLowLevelEvent};
+
+use crate::{
+    abstract_trace_writer::{AbstractTraceWriter, AbstractTraceWriterData},
+    trace_writer::TraceWriter,
+};
+
+/// Forwards every event to several writers at once, e.g. a `.bin` file, a live socket and a
+/// flight recorder.
+///
+/// Ids are interned once, by the tee, and every sink gets the same events and updates its own
+/// interning tables from them, so the ids match across all sinks. Metadata set on the tee is
+/// passed on to the sinks as well. The events of each sink must be begun before it's added, and
+/// are finished together by `finish_writing_trace_events`; the metadata and the paths are written
+/// by the tee itself. `begin_writing_trace_events` and `begin_writing_trace_events_to` always
+/// fail on a tee, as it has no events output of its own.
+pub struct TeeTraceWriter {
+    base: AbstractTraceWriterData,
+
+    sinks: Vec<Box<dyn TraceWriter + Send>>,
+}
+
+impl TeeTraceWriter {
+    /// Create a new tracer instance for the given program and arguments.
+    pub fn new(program: &str, args: &[String], sinks: Vec<Box<dyn TraceWriter + Send>>) -> Self {
+        TeeTraceWriter {
+            base: AbstractTraceWriterData::new(program, args),
+
+            sinks,
+        }
+    }
+
+    /// Adds a sink, which gets the events recorded from now on. The paths, functions, types and
+    /// variable names interned before it are replayed into it first, so its ids match the other
+    /// sinks, but the other events recorded before it are missing from its trace.
+    pub fn add_sink(&mut self, mut sink: Box<dyn TraceWriter + Send>) {
+        for event in self.interning_events() {
+            forward(sink.as_mut(), event);
+        }
+        self.sinks.push(sink);
+    }
+
+    /// The interning events recorded so far, in an order which gives a new sink the same ids
+    fn interning_events(&self) -> Vec<TraceLowLevelEvent> {
+        let data = &self.base;
+        let paths = data.path_list.iter().map(|path| TraceLowLevelEvent::Path(path.clone()));
+        let functions = data.function_list.iter().map(|(name, path_id, line)| {
+            TraceLowLevelEvent::Function(FunctionRecord {
+                name: name.clone(),
+                path_id: *path_id,
+                line: *line,
+            })
+        });
+        let types = by_id(&data.types, data.type_count, |id| id.0).map(|typ| TraceLowLevelEvent::Type(typ.clone()));
+        let variables = by_id(&data.variables, data.variable_count, |id| id.0).map(|name| TraceLowLevelEvent::VariableName(name.clone()));
+        paths.chain(functions).chain(types).chain(variables).collect()
+    }
+
+    pub fn sinks_mut(&mut self) -> &mut [Box<dyn TraceWriter + Send>] {
+        &mut self.sinks
+    }
+
+    fn for_each_sink(&mut self, f: impl Fn(&mut dyn TraceWriter)) {
+        for sink in &mut self.sinks {
+            f(sink.as_mut());
+        }
+    }
+}
+
+/// The keys of an interning table in the order of their ids. The ids of keys which were interned
+/// again (e.g. by appended events) have no key of their own: they get the first key, as interning
+/// it again only advances the ids.
+fn by_id<K, Id: Copy>(table: &HashMap<K, Id>, count: usize, index: impl Fn(Id) -> usize) -> impl Iterator<Item = &K> {
+    let mut keys: Vec<Option<&K>> = vec![None; count];
+    for (key, id) in table {
+        keys[index(*id)] = Some(key);
+    }
+    let first = keys.first().copied().flatten();
+    keys.into_iter()
+        .map(move |key| key.or(first).expect("the first id always has its own key"))
+}
+
+fn forward(sink: &mut dyn TraceWriter, event: TraceLowLevelEvent) {
+    AbstractTraceWriter::update_interning_tables(sink, &event);
+    AbstractTraceWriter::add_event(sink, event);
+}
+
+impl AbstractTraceWriter for TeeTraceWriter {
+    fn get_data(&self) -> &AbstractTraceWriterData {
+        &self.base
+    }
+
+    fn get_mut_data(&mut self) -> &mut AbstractTraceWriterData {
+        &mut self.base
+    }
+
+    fn add_event(&mut self, event: TraceLowLevelEvent) {
+        if let Some((last, rest)) = self.sinks.split_last_mut() {
+            for sink in rest {
+                forward(sink.as_mut(), event.clone());
+            }
+            forward(last.as_mut(), event);
+        }
+    }
+
+    fn append_events(&mut self, events: &mut Vec<TraceLowLevelEvent>) {
+        for e in events.drain(..) {
+            self.update_interning_tables(&e);
+            AbstractTraceWriter::add_event(self, e);
+        }
+    }
+}
+
+const BEGIN_SINKS_ERROR: &str = "a TeeTraceWriter writes the events into its sinks: begin writing each of them before adding it";
+
+impl TraceWriter for TeeTraceWriter {
+    /// Always fails: the events are written into the sinks, which are begun before they're added
+    fn begin_writing_trace_events(&mut self, _path: &Path) -> Result<(), Box<dyn Error>> {
+        Err(BEGIN_SINKS_ERROR.into())
+    }
+
+    /// Always fails, like `begin_writing_trace_events`
+    fn begin_writing_trace_events_to(&mut self, _output: Box<dyn Write + Send>) -> Result<(), Box<dyn Error>> {
+        Err(BEGIN_SINKS_ERROR.into())
+    }
+
+    fn set_language(&mut self, name: &str, version: &str) {
+        AbstractTraceWriter::set_language(self, name, version);
+        self.for_each_sink(|sink| TraceWriter::set_language(sink, name, version));
+    }
+
+    fn set_recorder(&mut self, name: &str, version: &str) {
+        AbstractTraceWriter::set_recorder(self, name, version);
+        self.for_each_sink(|sink| TraceWriter::set_recorder(sink, name, version));
+    }
+
+    fn set_exit_code(&mut self, exit_code: i32) {
+        AbstractTraceWriter::set_exit_code(self, exit_code);
+        self.for_each_sink(|sink| TraceWriter::set_exit_code(sink, exit_code));
+    }
+
+    fn record_environment_variables(&mut self, names: &[&str]) {
+        AbstractTraceWriter::record_environment_variables(self, names);
+        self.for_each_sink(|sink| TraceWriter::record_environment_variables(sink, names));
+    }
+
+    fn set_metadata_extension(&mut self, key: &str, value: serde_json::Value) {
+        AbstractTraceWriter::set_metadata_extension(self, key, value.clone());
+        self.for_each_sink(|sink| TraceWriter::set_metadata_extension(sink, key, value.clone()));
+    }
+
+    /// Finishes every sink, even if an earlier one fails, and returns the first error
+    fn finish_writing_trace_events(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut result = Ok(());
+        for sink in &mut self.sinks {
+            let sink_result = sink.finish_writing_trace_events();
+            if result.is_ok() {
+                result = sink_result;
+            }
+        }
+        result
+    }
+}
@@ -0,0 +1,55 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+/// Where the metadata or the paths of a trace are written when they're finished
+pub enum TraceOutput {
+    /// Created only when the contents are written
+    File(PathBuf),
+    Writer(Box<dyn Write + Send>),
+}
+
+impl TraceOutput {
+    /// Replaces the contents of the file, or appends `contents` to the writer
+    pub(crate) fn write_all(&mut self, contents: &[u8]) -> io::Result<()> {
+        match self {
+            TraceOutput::File(path) => fs::write(path, contents),
+            TraceOutput::Writer(writer) => {
+                writer.write_all(contents)?;
+                writer.flush()
+            }
+        }
+    }
+}
+
+/// An in-memory `Write` sink for traces. Clones share the same buffer, so a clone passed to a
+/// writer can be read back through the original once the writer is finished.
+#[derive(Debug, Default, Clone)]
+pub struct TraceBuffer {
+    bytes: Arc<Mutex<Vec<u8>>>,
+}
+
+impl TraceBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A copy of everything written so far
+    pub fn contents(&self) -> Vec<u8> {
+        self.bytes.lock().expect("trace buffer lock poisoned").clone()
+    }
+}
+
+impl Write for TraceBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.bytes.lock().expect("trace buffer lock poisoned").extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
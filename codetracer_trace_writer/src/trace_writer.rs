@@ -1,19 +1,68 @@
-use std::{error::Error, path::Path};
+use std::{error::Error, fs::File, io::Write, path::Path};
 
-use crate::abstract_trace_writer::AbstractTraceWriter;
+use crate::abstract_trace_writer::{AbstractTraceWriter, FunctionKey, TimestampMode};
+use crate::value_limits::ValueLimits;
 use codetracer_trace_types::{
-    EventLogKind, FullValueRecord, FunctionId, Line, PassBy, PathId, Place, RValue, TraceLowLevelEvent, TypeId, TypeKind, TypeRecord, ValueRecord,
-    VariableId,
+    EventLogKind, FullValueRecord, FunctionId, Line, PassBy, PathId, Place, RValue, ThreadId, TraceLowLevelEvent, TypeId, TypeKind, TypeRecord,
+    ValueRecord, VariableId,
 };
 
 pub trait TraceWriter: AbstractTraceWriter {
     fn begin_writing_trace_metadata(&mut self, path: &Path) -> Result<(), Box<dyn Error>> {
         AbstractTraceWriter::begin_writing_trace_metadata(self, path)
     }
-    fn begin_writing_trace_events(&mut self, path: &Path) -> Result<(), Box<dyn Error>>;
+    /// Like `begin_writing_trace_metadata`, writing the metadata into `output` when it's finished
+    fn begin_writing_trace_metadata_to(&mut self, output: Box<dyn Write + Send>) -> Result<(), Box<dyn Error>> {
+        AbstractTraceWriter::begin_writing_trace_metadata_to(self, output)
+    }
+    fn begin_writing_trace_events(&mut self, path: &Path) -> Result<(), Box<dyn Error>> {
+        self.begin_writing_trace_events_to(Box::new(File::create(path)?))
+    }
+    /// Writes the events into `output` instead of a file, e.g. into a `TraceBuffer`, a socket or stdout.
+    /// Writers which only implement `begin_writing_trace_events` don't support it
+    fn begin_writing_trace_events_to(&mut self, _output: Box<dyn Write + Send>) -> Result<(), Box<dyn Error>> {
+        Err("this trace writer can only write the events into a file".into())
+    }
     fn begin_writing_trace_paths(&mut self, path: &Path) -> Result<(), Box<dyn Error>> {
         AbstractTraceWriter::begin_writing_trace_paths(self, path)
     }
+    /// Like `begin_writing_trace_paths`, writing the paths into `output` when they're finished
+    fn begin_writing_trace_paths_to(&mut self, output: Box<dyn Write + Send>) -> Result<(), Box<dyn Error>> {
+        AbstractTraceWriter::begin_writing_trace_paths_to(self, output)
+    }
+
+    fn set_language(&mut self, name: &str, version: &str) {
+        AbstractTraceWriter::set_language(self, name, version)
+    }
+    fn set_recorder(&mut self, name: &str, version: &str) {
+        AbstractTraceWriter::set_recorder(self, name, version)
+    }
+    fn set_exit_code(&mut self, exit_code: i32) {
+        AbstractTraceWriter::set_exit_code(self, exit_code)
+    }
+    fn record_environment_variables(&mut self, names: &[&str]) {
+        AbstractTraceWriter::record_environment_variables(self, names)
+    }
+    fn set_metadata_extension(&mut self, key: &str, value: serde_json::Value) {
+        AbstractTraceWriter::set_metadata_extension(self, key, value)
+    }
+
+    fn set_timestamp_mode(&mut self, mode: TimestampMode) {
+        AbstractTraceWriter::set_timestamp_mode(self, mode)
+    }
+    fn register_timestamp(&mut self) {
+        AbstractTraceWriter::register_timestamp(self)
+    }
+
+    fn set_value_limits(&mut self, limits: ValueLimits) {
+        AbstractTraceWriter::set_value_limits(self, limits)
+    }
+    fn limit_value(&self, value: ValueRecord) -> ValueRecord {
+        AbstractTraceWriter::limit_value(self, value)
+    }
+    fn bytes_value(&self, data: &[u8], type_id: TypeId) -> ValueRecord {
+        AbstractTraceWriter::bytes_value(self, data, type_id)
+    }
 
     fn start(&mut self, path: &Path, line: Line) {
         AbstractTraceWriter::start(self, path, line)
@@ -24,6 +73,9 @@ pub trait TraceWriter: AbstractTraceWriter {
     fn ensure_function_id(&mut self, function_name: &str, path: &Path, line: Line) -> FunctionId {
         AbstractTraceWriter::ensure_function_id(self, function_name, path, line)
     }
+    fn ensure_function_id_with_key(&mut self, key: FunctionKey, function_name: &str, path: &Path, line: Line) -> FunctionId {
+        AbstractTraceWriter::ensure_function_id_with_key(self, key, function_name, path, line)
+    }
     fn ensure_type_id(&mut self, kind: TypeKind, lang_type: &str) -> TypeId {
         AbstractTraceWriter::ensure_type_id(self, kind, lang_type)
     }
@@ -110,6 +162,15 @@ pub trait TraceWriter: AbstractTraceWriter {
     fn compound_rvalue(&mut self, variable_dependencies: &[String]) -> RValue {
         AbstractTraceWriter::compound_rvalue(self, variable_dependencies)
     }
+    fn thread_start(&mut self, thread_id: ThreadId) {
+        AbstractTraceWriter::thread_start(self, thread_id)
+    }
+    fn thread_exit(&mut self, thread_id: ThreadId) {
+        AbstractTraceWriter::thread_exit(self, thread_id)
+    }
+    fn thread_switch(&mut self, thread_id: ThreadId) {
+        AbstractTraceWriter::thread_switch(self, thread_id)
+    }
     fn drop_last_step(&mut self) {
         AbstractTraceWriter::drop_last_step(self)
     }
@@ -121,10 +182,13 @@ pub trait TraceWriter: AbstractTraceWriter {
         AbstractTraceWriter::append_events(self, events)
     }
 
+    /// Writes the metadata with its current values; calling it again rewrites the file, or writes
+    /// the metadata into the output given to `begin_writing_trace_metadata_to` once more
     fn finish_writing_trace_metadata(&mut self) -> Result<(), Box<dyn Error>> {
         AbstractTraceWriter::finish_writing_trace_metadata(self)
     }
     fn finish_writing_trace_events(&mut self) -> Result<(), Box<dyn Error>>;
+    /// Like `finish_writing_trace_metadata`, for the paths
     fn finish_writing_trace_paths(&mut self) -> Result<(), Box<dyn Error>> {
         AbstractTraceWriter::finish_writing_trace_paths(self)
     }
@@ -0,0 +1,230 @@
+use codetracer_trace_types::ValueRecord;
+
+/// Limits applied by the writer to every recorded value; `None` means unlimited
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ValueLimits {
+    /// Nesting depth of compound values; deeper ones are replaced by an `Elided` value without a preview
+    pub max_depth: Option<usize>,
+    /// Elements of a `Sequence` or entries of a `Map`
+    pub max_elements: Option<usize>,
+    /// Characters of a `String`
+    pub max_string_length: Option<usize>,
+    /// Bytes of a `Bytes` value; these are truncated in place and record their `original_length`
+    pub max_bytes_length: Option<usize>,
+}
+
+impl ValueLimits {
+    pub fn is_unlimited(&self) -> bool {
+        *self == ValueLimits::default()
+    }
+
+    /// Returns `value` with everything over the limits replaced by `Elided` values
+    pub fn apply(&self, value: ValueRecord) -> ValueRecord {
+        if self.is_unlimited() { value } else { self.apply_at_depth(value, 0) }
+    }
+
+    fn apply_at_depth(&self, value: ValueRecord, depth: usize) -> ValueRecord {
+        if self.max_depth.is_some_and(|max_depth| depth >= max_depth)
+            && let Some((original_length, type_id)) = compound_length_and_type(&value)
+        {
+            return ValueRecord::Elided {
+                original_length,
+                preview: None,
+                type_id,
+            };
+        }
+
+        match value {
+            ValueRecord::Sequence { elements, is_slice, type_id } => {
+                let original_length = elements.len();
+                let elements = self.apply_to_elements(elements, depth);
+                self.elide_if_truncated(
+                    original_length,
+                    elements.len(),
+                    ValueRecord::Sequence { elements, is_slice, type_id },
+                    type_id,
+                )
+            }
+            ValueRecord::Map { entries, type_id } => {
+                let original_length = entries.len();
+                let entries: Vec<_> = entries
+                    .into_iter()
+                    .take(self.max_elements.unwrap_or(usize::MAX))
+                    .map(|(key, value)| (self.apply_at_depth(key, depth + 1), self.apply_at_depth(value, depth + 1)))
+                    .collect();
+                self.elide_if_truncated(original_length, entries.len(), ValueRecord::Map { entries, type_id }, type_id)
+            }
+            // the number of fields is fixed by the type, so only nested values are limited
+            ValueRecord::Tuple { elements, type_id } => ValueRecord::Tuple {
+                elements: elements.into_iter().map(|element| self.apply_at_depth(element, depth + 1)).collect(),
+                type_id,
+            },
+            ValueRecord::Struct { field_values, type_id } => ValueRecord::Struct {
+                field_values: field_values.into_iter().map(|field| self.apply_at_depth(field, depth + 1)).collect(),
+                type_id,
+            },
+            ValueRecord::Variant {
+                discriminator,
+                contents,
+                type_id,
+            } => ValueRecord::Variant {
+                discriminator,
+                contents: Box::new(self.apply_at_depth(*contents, depth + 1)),
+                type_id,
+            },
+            ValueRecord::Reference {
+                dereferenced,
+                address,
+                mutable,
+                type_id,
+            } => ValueRecord::Reference {
+                dereferenced: Box::new(self.apply_at_depth(*dereferenced, depth + 1)),
+                address,
+                mutable,
+                type_id,
+            },
+            ValueRecord::String { text, type_id } => match self.max_string_length {
+                Some(max_string_length) if text.chars().count() > max_string_length => ValueRecord::Elided {
+                    original_length: Some(text.chars().count() as u64),
+                    preview: Some(Box::new(ValueRecord::String {
+                        text: text.chars().take(max_string_length).collect(),
+                        type_id,
+                    })),
+                    type_id,
+                },
+                _ => ValueRecord::String { text, type_id },
+            },
+            ValueRecord::Bytes {
+                data,
+                original_length,
+                type_id,
+            } => match self.max_bytes_length {
+                Some(max_bytes_length) if data.len() > max_bytes_length => ValueRecord::Bytes {
+                    original_length: Some(original_length.unwrap_or(data.len() as u64)),
+                    data: data[..max_bytes_length].to_vec(),
+                    type_id,
+                },
+                _ => ValueRecord::Bytes {
+                    data,
+                    original_length,
+                    type_id,
+                },
+            },
+            value => value,
+        }
+    }
+
+    fn apply_to_elements(&self, elements: Vec<ValueRecord>, depth: usize) -> Vec<ValueRecord> {
+        elements
+            .into_iter()
+            .take(self.max_elements.unwrap_or(usize::MAX))
+            .map(|element| self.apply_at_depth(element, depth + 1))
+            .collect()
+    }
+
+    fn elide_if_truncated(
+        &self,
+        original_length: usize,
+        length: usize,
+        preview: ValueRecord,
+        type_id: codetracer_trace_types::TypeId,
+    ) -> ValueRecord {
+        if length < original_length {
+            ValueRecord::Elided {
+                original_length: Some(original_length as u64),
+                preview: Some(Box::new(preview)),
+                type_id,
+            }
+        } else {
+            preview
+        }
+    }
+}
+
+/// The length (if it has one) and type of a value which has nested values
+fn compound_length_and_type(value: &ValueRecord) -> Option<(Option<u64>, codetracer_trace_types::TypeId)> {
+    match value {
+        ValueRecord::Sequence { elements, type_id, .. } | ValueRecord::Tuple { elements, type_id } => Some((Some(elements.len() as u64), *type_id)),
+        ValueRecord::Struct { field_values, type_id } => Some((Some(field_values.len() as u64), *type_id)),
+        ValueRecord::Map { entries, type_id } => Some((Some(entries.len() as u64), *type_id)),
+        ValueRecord::Variant { type_id, .. } | ValueRecord::Reference { type_id, .. } => Some((None, *type_id)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codetracer_trace_types::TypeId;
+
+    fn int(i: i64) -> ValueRecord {
+        ValueRecord::Int { i, type_id: TypeId(1) }
+    }
+
+    fn seq(elements: Vec<ValueRecord>) -> ValueRecord {
+        ValueRecord::Sequence {
+            elements,
+            is_slice: false,
+            type_id: TypeId(2),
+        }
+    }
+
+    #[test]
+    fn test_max_elements() {
+        let limits = ValueLimits {
+            max_elements: Some(2),
+            ..Default::default()
+        };
+        let value = limits.apply(seq((0..10).map(int).collect()));
+
+        match value {
+            ValueRecord::Elided {
+                original_length: Some(10),
+                preview: Some(preview),
+                type_id: TypeId(2),
+            } => assert_eq!(*preview, seq(vec![int(0), int(1)])),
+            _ => panic!("expected an elided sequence, got {value:?}"),
+        }
+        assert_eq!(limits.apply(seq(vec![int(0)])), seq(vec![int(0)]));
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let limits = ValueLimits {
+            max_depth: Some(1),
+            ..Default::default()
+        };
+        let value = limits.apply(seq(vec![seq(vec![int(0), int(1)]), int(2)]));
+
+        let expected = seq(vec![
+            ValueRecord::Elided {
+                original_length: Some(2),
+                preview: None,
+                type_id: TypeId(2),
+            },
+            int(2),
+        ]);
+        assert_eq!(value, expected);
+    }
+
+    #[test]
+    fn test_max_string_length() {
+        let limits = ValueLimits {
+            max_string_length: Some(3),
+            ..Default::default()
+        };
+        let value = limits.apply(ValueRecord::String {
+            text: "λλλλλ".to_string(),
+            type_id: TypeId(3),
+        });
+
+        match value {
+            ValueRecord::Elided {
+                original_length: Some(5),
+                preview: Some(preview),
+                ..
+            } => assert!(matches!(*preview, ValueRecord::String { ref text, .. } if text == "λλλ")),
+            _ => panic!("expected an elided string, got {value:?}"),
+        }
+    }
+}
@@ -0,0 +1,109 @@
+/// Compression settings of the streaming binary writer; ignored by the JSON and `BinaryV0` writers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceWriterOptions {
+    /// zstd compression level; negative levels trade ratio for speed, up to 22 for maximum compression
+    pub compression_level: i32,
+    /// Uncompressed size at which a new seekable zstd frame is started
+    pub frame_size: u32,
+    /// Background compression threads; 0 compresses on the writing thread
+    pub workers: u32,
+    /// Pretrained zstd dictionary (see `train_dictionary`); the same one is required to read the trace back
+    pub dictionary: Option<Vec<u8>>,
+    /// Encode and compress events on a dedicated thread instead of the recording one; not available on wasm
+    #[cfg(not(target_arch = "wasm32"))]
+    pub background: Option<BackgroundEncoding>,
+}
+
+/// What the recording thread does when the queue of a background encoder is full
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Backpressure {
+    /// Wait for the encoder to catch up
+    #[default]
+    Block,
+    /// Discard events which nothing later refers to and record how many were lost with a `DroppedEvents` event;
+    /// interning and call structure events are still queued, waiting if needed
+    DropWithMarker,
+    /// Never wait: the queue is unbounded and `queue_capacity` is ignored
+    Grow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BackgroundEncoding {
+    /// Number of events which can wait for the encoder
+    pub queue_capacity: usize,
+    pub backpressure: Backpressure,
+}
+
+impl Default for BackgroundEncoding {
+    fn default() -> Self {
+        BackgroundEncoding {
+            queue_capacity: 64 * 1024,
+            backpressure: Backpressure::default(),
+        }
+    }
+}
+
+/// zstd's own default level
+pub const DEFAULT_COMPRESSION_LEVEL: i32 = 3;
+
+/// Matches the default frame size policy of zeekstd
+pub const DEFAULT_FRAME_SIZE: u32 = 2 * 1024 * 1024;
+
+impl Default for TraceWriterOptions {
+    fn default() -> Self {
+        TraceWriterOptions {
+            compression_level: DEFAULT_COMPRESSION_LEVEL,
+            frame_size: DEFAULT_FRAME_SIZE,
+            workers: 0,
+            dictionary: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            background: None,
+        }
+    }
+}
+
+impl TraceWriterOptions {
+    /// The fastest compression, meant for live recording; combine it with `background` to also
+    /// move the encoding off the recording thread
+    pub fn fastest() -> Self {
+        TraceWriterOptions {
+            compression_level: 1,
+            ..Default::default()
+        }
+    }
+
+    /// The highest compression ratio, meant for archiving
+    pub fn smallest() -> Self {
+        TraceWriterOptions {
+            compression_level: 19,
+            frame_size: 8 * DEFAULT_FRAME_SIZE,
+            ..Default::default()
+        }
+    }
+}
+
+/// Trains a zstd dictionary of at most `max_size` bytes on the encoded event streams of `samples`
+#[cfg(not(target_arch = "wasm32"))]
+pub fn train_dictionary(samples: &[Vec<codetracer_trace_types::TraceLowLevelEvent>], max_size: usize) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    use codetracer_trace_format_cbor_zstd::wire::WireEncoder;
+
+    /// Size of the stream chunks used as individual training samples
+    const SAMPLE_SIZE: usize = 4 * 1024;
+
+    let mut samples_buffer = Vec::new();
+    let mut sample_sizes = Vec::new();
+    for events in samples {
+        let mut encoder = WireEncoder::new();
+        let mut stream = Vec::new();
+        for event in events {
+            encoder.encode(event, &mut stream);
+        }
+        sample_sizes.extend(stream.chunks(SAMPLE_SIZE).map(|chunk| chunk.len()));
+        samples_buffer.extend(stream);
+    }
+
+    let mut dictionary = Vec::with_capacity(max_size);
+    zstd_safe::train_from_buffer(&mut dictionary, &samples_buffer, &sample_sizes)
+        .map_err(|code| format!("Unable to train a dictionary: {}", zstd_safe::get_error_name(code)))?;
+    Ok(dictionary)
+}